@@ -0,0 +1,72 @@
+import os
+import logging
+
+import riscof.utils as utils
+from riscof.pluginTemplate import pluginTemplate
+
+logger = logging.getLogger()
+
+
+class rvemu(pluginTemplate):
+    __model__ = "rvemu"
+    __version__ = "0.1.0"
+
+    def __init__(self, *args, **kwargs):
+        sclass = super().__init__(*args, **kwargs)
+
+        config = kwargs.get('config')
+        if config is None:
+            print("Please enter input file paths in configuration.")
+            raise SystemExit(1)
+
+        # Built by `cargo build --release`
+        self.dut_exe = os.path.join(config.get('PATH', ''), "rvemu-for-book")
+        self.num_jobs = str(config.get('jobs', 1))
+        self.pluginpath = os.path.abspath(config['pluginpath'])
+        self.isa_spec = os.path.abspath(config['ispec'])
+        self.platform_spec = os.path.abspath(config['pspec'])
+        self.target_run = config.get('target_run', '1') != '0'
+
+        return sclass
+
+    def initialise(self, suite, work_dir, archtest_env):
+        self.work_dir = work_dir
+        self.suite_dir = suite
+        self.compile_cmd = 'riscv{1}-unknown-elf-gcc -march={0} \
+         -static -mcmodel=medany -fvisibility=hidden -nostdlib -nostartfiles -g\
+         -T ' + self.pluginpath + '/env/link.ld\
+         -I ' + self.pluginpath + '/env/\
+         -I ' + archtest_env + ' {2} -o {3} {4}'
+
+    def build(self, isa_yaml, platform_yaml):
+        ispec = utils.load_yaml(isa_yaml)['hart0']
+        self.xlen = '64' if 64 in ispec['supported_xlen'] else '32'
+        self.compile_cmd += ' -mabi=' + ('lp64 ' if self.xlen == '64' else 'ilp32 ')
+
+    def runTests(self, testList):
+        makefile = os.path.join(self.work_dir, "Makefile." + self.name[:-1])
+        if os.path.exists(makefile):
+            os.remove(makefile)
+        make = utils.makeUtil(makefilePath=makefile)
+        make.makeCommand = 'make -k -j' + self.num_jobs
+
+        for testname in testList:
+            testentry = testList[testname]
+            test = testentry['test_path']
+            test_dir = testentry['work_dir']
+            elf = 'my.elf'
+            sig_file = os.path.join(test_dir, self.name[:-1] + ".signature")
+            compile_macros = ' -D' + " -D".join(testentry['macros'])
+            cmd = self.compile_cmd.format(
+                testentry['isa'].lower(), self.xlen, test, elf, compile_macros)
+            if self.target_run:
+                simcmd = '{0} {1} --signature {2} --signature-granularity 4'.format(
+                    self.dut_exe, elf, sig_file)
+            else:
+                simcmd = 'echo "NO RUN"'
+            make.add_target('@cd {0}; {1}; {2};'.format(test_dir, cmd, simcmd))
+
+        make.execute_all(self.work_dir)
+
+        if not self.target_run:
+            raise SystemExit(0)
@@ -1,44 +1,385 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+
+use crate::config::{MachineConfig, RegionKind};
+use crate::devices::{htif::Htif, Detached, Device};
 use crate::dram::*;
 use crate::exception::*;
+use crate::mmu::AccessType;
 use crate::param::*;
 
+/// Size (in bytes) of a reservation set registered by `LR`
+const RESERVATION_SET_SIZE: u64 = 8;
+
+/// A device mapped at `base..base + size`
+struct MappedDevice {
+  name: String,
+  base: u64,
+  size: u64,
+  device: Box<dyn Device>,
+}
+
+impl MappedDevice {
+  fn contains(&self, addr: u64) -> bool {
+    addr.wrapping_sub(self.base) < self.size
+  }
+  fn end(&self) -> u64 {
+    self.base + (self.size - 1)
+  }
+}
+
+/// Error of mapping a device on `Bus`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BusError {
+  /// The address range is empty or exceeds the address space
+  InvalidRange { name: String, base: u64, size: u64 },
+  /// The address range overlaps with a mapped device
+  Overlap {
+    name: String,
+    base: u64,
+    size: u64,
+    mapped: String,
+  },
+  /// The image to be placed in memory is larger than the memory
+  TooLarge { name: String, size: u64, len: u64 },
+}
+
+impl fmt::Display for BusError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      BusError::InvalidRange { name, base, size } => write!(
+        f,
+        "InvalidRange: `{name}` can't be mapped at 0x{base:016x} with size 0x{size:x}"
+      ),
+      BusError::Overlap {
+        name,
+        base,
+        size,
+        mapped,
+      } => write!(
+        f,
+        "Overlap: `{name}` at 0x{base:016x} with size 0x{size:x} overlaps with `{mapped}`"
+      ),
+      BusError::TooLarge { name, size, len } => write!(
+        f,
+        "TooLarge: `{name}` with size 0x{size:x} can't hold an image of 0x{len:x} bytes"
+      ),
+    }
+  }
+}
+
+impl std::error::Error for BusError {}
+
+/// Check that `base..base + size` is a non-empty range within the address space
+fn check_range(name: &str, base: u64, size: u64) -> Result<(), BusError> {
+  if size == 0 || base.checked_add(size - 1).is_none() {
+    return Err(BusError::InvalidRange {
+      name: name.to_owned(),
+      base,
+      size,
+    });
+  }
+  Ok(())
+}
+
+/// How a load or store to an address not aligned to its size is handled
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum MisalignedPolicy {
+  /// Raise `LoadAccessMisaligned` or `StoreAMOAddrMisaligned`, as on most hardware
+  Trap,
+  /// Perform the access byte by byte, as Spike does
+  #[default]
+  Emulate,
+  /// Emulate the access if it lies within a page, otherwise trap
+  EmulateWithinPage,
+}
+
 pub struct Bus {
-  pub dram: Dram,
+  /// Mapped devices, sorted by base address
+  devices: Vec<MappedDevice>,
+  misaligned: MisalignedPolicy,
+  /// Reservation sets registered by `LR`, keyed by hart ID
+  reservations: HashMap<u64, u64>,
+  /// Exit code requested by the guest, which stops the hart after the current step
+  exit: Option<u64>,
+  htif: Option<Htif>,
+  /// Whether `tohost` of HTIF has been written since the last poll
+  htif_pending: bool,
 }
 
 impl Bus {
   pub fn new(code: Vec<u8>) -> Bus {
-    Self {
-      dram: Dram::new(code),
+    Self::with_config(&MachineConfig::default(), code).expect("the default memory map is valid")
+  }
+
+  /// Create a bus with DRAM (which begins with `code`) and the memory regions of `config`
+  pub fn with_config(config: &MachineConfig, code: Vec<u8>) -> Result<Bus, BusError> {
+    let mut bus = Self {
+      devices: vec![],
+      misaligned: config.misaligned,
+      reservations: HashMap::new(),
+      exit: None,
+      htif: None,
+      htif_pending: false,
+    };
+    check_range("dram", config.dram_base, config.dram_size)?;
+    let dram = Dram::with_size(config.dram_size, code)?;
+    bus.map("dram", config.dram_base, config.dram_size, Box::new(dram))?;
+    for region in config.regions.iter() {
+      // Memory is allocated only for a valid range
+      check_range(&region.name, region.base, region.size)?;
+      let device: Box<dyn Device> = match &region.kind {
+        RegionKind::Rom(data) => Box::new(Rom::new(data.clone())),
+        RegionKind::Sram => Box::new(Dram::zeroed(region.size)),
+      };
+      bus.map(&region.name, region.base, region.size, device)?;
     }
+    Ok(bus)
   }
 
-  pub fn fetch_inst(&self, addr: u64) -> Result<u8, Exception> {
-    match addr {
-      DRAM_BASE..=DRAM_END => Ok(self.dram.fetch_inst(addr)),
-      _ => Err(Exception::InstructionAccessFault(addr)),
+  /// Map a device at `base..base + size`, which must not overlap with any mapped device
+  pub fn map(
+    &mut self,
+    name: &str,
+    base: u64,
+    size: u64,
+    device: Box<dyn Device>,
+  ) -> Result<(), BusError> {
+    check_range(name, base, size)?;
+    let new = MappedDevice {
+      name: name.to_owned(),
+      base,
+      size,
+      device,
+    };
+    if let Some(mapped) = self
+      .devices
+      .iter()
+      .find(|mapped| mapped.base <= new.end() && new.base <= mapped.end())
+    {
+      return Err(BusError::Overlap {
+        name: new.name,
+        base,
+        size,
+        mapped: mapped.name.clone(),
+      });
     }
+    let index = self.devices.partition_point(|mapped| mapped.base < base);
+    self.devices.insert(index, new);
+    Ok(())
   }
 
-  pub fn load(&mut self, addr: u64, size: SizeType) -> Result<u64, Exception> {
-    let addr = addr + DRAM_BASE;
-    match addr {
-      DRAM_BASE..=DRAM_END => self.dram.load(addr, size),
-      _ => Err(Exception::LoadAccessFault(addr)),
+  /// Index of the device which `addr` is mapped to
+  fn index_of(&self, addr: u64) -> Option<usize> {
+    let index = self.devices.partition_point(|mapped| mapped.base <= addr);
+    index
+      .checked_sub(1)
+      .filter(|&i| self.devices[i].contains(addr))
+  }
+
+  /// Whether any device is mapped at `addr`, which doesn't access the device
+  pub fn is_mapped(&self, addr: u64) -> bool {
+    self.index_of(addr).is_some()
+  }
+
+  /// Find the device which `addr` is mapped to, and the offset inside it
+  fn find(&mut self, addr: u64) -> Option<(&mut dyn Device, u64)> {
+    let index = self.index_of(addr)?;
+    let mapped = &mut self.devices[index];
+    Some((mapped.device.as_mut(), addr - mapped.base))
+  }
+
+  pub fn misaligned_policy(&self) -> MisalignedPolicy {
+    self.misaligned
+  }
+  pub fn set_misaligned_policy(&mut self, policy: MisalignedPolicy) {
+    self.misaligned = policy;
+  }
+
+  /// Check an access of `size` bytes at `addr` against the misaligned policy
+  pub fn check_alignment(
+    &self,
+    addr: u64,
+    size: SizeType,
+    access: AccessType,
+  ) -> Result<(), Exception> {
+    let n_bytes = size.how_many_bytes() as u64;
+    if addr.is_multiple_of(n_bytes) {
+      return Ok(());
+    }
+    let crosses_page = addr % PAGE_SIZE + n_bytes > PAGE_SIZE;
+    match (self.misaligned, crosses_page) {
+      (MisalignedPolicy::Emulate, _) | (MisalignedPolicy::EmulateWithinPage, false) => Ok(()),
+      _ => Err(access.misaligned(addr)),
+    }
+  }
+
+  /// Bytes of host memory backing the guest memory of all devices
+  pub fn resident_bytes(&self) -> u64 {
+    self
+      .devices
+      .iter()
+      .map(|mapped| mapped.device.resident_bytes())
+      .sum()
+  }
+
+  /// Attach HTIF, which is polled on every tick after `tohost` is written
+  pub fn attach_htif(&mut self, htif: Htif) {
+    self.htif = Some(htif);
+  }
+
+  /// Advance all devices by a clock cycle, then let them perform DMA
+  pub fn tick(&mut self) {
+    for index in 0..self.devices.len() {
+      let mut device = mem::replace(&mut self.devices[index].device, Box::new(Detached));
+      device.tick();
+      device.dma(self);
+      self.devices[index].device = device;
+    }
+    if mem::take(&mut self.htif_pending) {
+      if let Some(mut htif) = self.htif.take() {
+        // A command the host can't access is dropped
+        let _ = htif.poll(self);
+        self.htif = Some(htif);
+      }
+    }
+  }
+
+  /// Request the hart to stop with the exit `code` of the guest
+  pub fn request_exit(&mut self, code: u64) {
+    self.exit = Some(code);
+  }
+
+  /// Take the exit code requested by the guest, if any
+  pub fn take_exit(&mut self) -> Option<u64> {
+    self.exit.take()
+  }
+
+  /// Register a reservation set (which contains `addr`) for the hart
+  pub fn reserve(&mut self, hart: u64, addr: u64) {
+    self
+      .reservations
+      .insert(hart, addr & !(RESERVATION_SET_SIZE - 1));
+  }
+
+  /// Check whether the hart still holds a reservation set which contains `addr`.
+  ///
+  /// The reservation of the hart is released no matter whether it's valid.
+  pub fn take_reservation(&mut self, hart: u64, addr: u64) -> bool {
+    self.reservations.remove(&hart) == Some(addr & !(RESERVATION_SET_SIZE - 1))
+  }
+
+  /// Release the reservation held by the hart, if any
+  pub fn release_reservation(&mut self, hart: u64) {
+    self.reservations.remove(&hart);
+  }
+
+  /// Invalidate all reservation sets overlapping with `addr..addr + n_bytes`.
+  ///
+  /// Called on every store, whether it's from a hart or a device.
+  fn invalidate_reservations(&mut self, addr: u64, n_bytes: u64) {
+    if self.reservations.is_empty() {
+      return;
     }
+    let first = addr & !(RESERVATION_SET_SIZE - 1);
+    let last = addr.wrapping_add(n_bytes - 1) & !(RESERVATION_SET_SIZE - 1);
+    self
+      .reservations
+      .retain(|_, &mut set| set != first && set != last);
+  }
+
+  pub fn fetch_inst(&mut self, addr: u64) -> Result<u8, Exception> {
+    let fault = Exception::InstructionAccessFault(addr);
+    let (device, offset) = self.find(addr).ok_or(fault)?;
+    device
+      .load(offset, SizeType::Byte)
+      .map(|byte| byte as u8)
+      .map_err(|_| fault)
+  }
+
+  /// Load from a physical address, the value is sign-extended
+  pub fn load(&mut self, addr: u64, size: SizeType) -> Result<u64, Exception> {
+    Ok(size.sign_extend(self.load_u(addr, size)?))
   }
   pub fn store(&mut self, addr: u64, size: SizeType, value: u64) -> Result<(), Exception> {
-    let addr = addr + DRAM_BASE;
-    match addr {
-      DRAM_BASE..=DRAM_END => self.dram.store(addr, size, value),
-      _ => Err(Exception::StoreAMOAccessFault(addr)),
+    self.check_alignment(addr, size, AccessType::Store)?;
+    let n_bytes = size.how_many_bytes() as u64;
+    self.invalidate_reservations(addr, n_bytes);
+    if let Some(htif) = &self.htif {
+      // Any store overlapping with `tohost`
+      self.htif_pending |=
+        htif.tohost().wrapping_sub(addr) < n_bytes || addr.wrapping_sub(htif.tohost()) < 8;
+    }
+    let fault = Exception::StoreAMOAccessFault(addr);
+    if addr.is_multiple_of(n_bytes) {
+      let (device, offset) = self.find(addr).ok_or(fault)?;
+      return device.store(offset, size, value).map_err(|_| fault);
     }
+    // An emulated access is split into bytes, which may lie in different devices,
+    // and nothing is written unless all of them are mapped
+    if (0..n_bytes).any(|i| self.index_of(addr.wrapping_add(i)).is_none()) {
+      return Err(fault);
+    }
+    for i in 0..n_bytes {
+      let (device, offset) = self.find(addr.wrapping_add(i)).ok_or(fault)?;
+      device
+        .store(offset, SizeType::Byte, value >> (8 * i))
+        .map_err(|_| fault)?;
+    }
+    Ok(())
   }
+  /// Load from a physical address, the value is zero-extended
   pub fn load_u(&mut self, addr: u64, size: SizeType) -> Result<u64, Exception> {
-    let addr = addr + DRAM_BASE;
-    match addr {
-      DRAM_BASE..=DRAM_END => self.dram.load_u(addr, size),
-      _ => Err(Exception::LoadAccessFault(addr)),
+    self.check_alignment(addr, size, AccessType::Load)?;
+    let n_bytes = size.how_many_bytes() as u64;
+    let fault = Exception::LoadAccessFault(addr);
+    if addr.is_multiple_of(n_bytes) {
+      let (device, offset) = self.find(addr).ok_or(fault)?;
+      return device.load(offset, size).map_err(|_| fault);
+    }
+    let mut value = 0;
+    for i in 0..n_bytes {
+      let (device, offset) = self.find(addr.wrapping_add(i)).ok_or(fault)?;
+      value |= device.load(offset, SizeType::Byte).map_err(|_| fault)? << (8 * i);
+    }
+    Ok(value)
+  }
+
+  /// Read `buf.len()` bytes from a physical address.
+  ///
+  /// Only aligned accesses are made, so the misaligned policy never applies.
+  pub fn read_bytes(&mut self, addr: u64, buf: &mut [u8]) -> Result<(), Exception> {
+    let mut done = 0;
+    while done < buf.len() {
+      let addr = addr.wrapping_add(done as u64);
+      if addr.is_multiple_of(8) && buf.len() - done >= 8 {
+        let value = self.load_u(addr, SizeType::DoubleWord)?;
+        buf[done..done + 8].copy_from_slice(&value.to_le_bytes());
+        done += 8;
+      } else {
+        buf[done] = self.load_u(addr, SizeType::Byte)? as u8;
+        done += 1;
+      }
+    }
+    Ok(())
+  }
+  /// Write all bytes of `data` to a physical address.
+  ///
+  /// Only aligned accesses are made, so the misaligned policy never applies.
+  pub fn write_bytes(&mut self, addr: u64, data: &[u8]) -> Result<(), Exception> {
+    let mut done = 0;
+    while done < data.len() {
+      let addr = addr.wrapping_add(done as u64);
+      if addr.is_multiple_of(8) && data.len() - done >= 8 {
+        let value = u64::from_le_bytes(data[done..done + 8].try_into().unwrap());
+        self.store(addr, SizeType::DoubleWord, value)?;
+        done += 8;
+      } else {
+        self.store(addr, SizeType::Byte, data[done] as u64)?;
+        done += 1;
+      }
     }
+    Ok(())
   }
 }
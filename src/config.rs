@@ -0,0 +1,104 @@
+//! # Machine Config
+//!
+//! Memory map of the emulated machine, from which `Bus`, `Dram` & `Cpu` are constructed.
+
+use crate::bus::MisalignedPolicy;
+use crate::param::*;
+
+/// Content of an additional memory region
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegionKind {
+  /// Read-only memory initialized with the bytes
+  Rom(Vec<u8>),
+  /// Zero-initialized read-write memory
+  Sram,
+}
+
+/// A memory region mapped at `base..base + size` besides DRAM
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryRegion {
+  pub name: String,
+  pub base: u64,
+  pub size: u64,
+  pub kind: RegionKind,
+}
+
+/// Memory map of the machine, which defaults to the constants in `param`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MachineConfig {
+  pub dram_base: u64,
+  pub dram_size: u64,
+  pub regions: Vec<MemoryRegion>,
+  pub uart_base: u64,
+  pub clint_base: u64,
+  /// Hart clock cycles per `mtime` increment, i.e. the rate of the timer
+  pub clint_cycles_per_tick: u64,
+  pub plic_base: u64,
+  pub virtio_base: u64,
+  /// How misaligned loads & stores are handled
+  pub misaligned: MisalignedPolicy,
+  /// Address of HTIF `tohost`, which overrides the `tohost` symbol of an ELF
+  pub tohost: Option<u64>,
+}
+
+impl Default for MachineConfig {
+  fn default() -> Self {
+    Self {
+      dram_base: DRAM_BASE,
+      dram_size: DRAM_SIZE,
+      regions: vec![],
+      uart_base: UART_BASE,
+      clint_base: CLINT_BASE,
+      clint_cycles_per_tick: CLINT_CYCLES_PER_TICK,
+      plic_base: PLIC_BASE,
+      virtio_base: VIRTIO_BASE,
+      misaligned: MisalignedPolicy::default(),
+      tohost: None,
+    }
+  }
+}
+
+impl MachineConfig {
+  /// The last byte of DRAM
+  pub fn dram_end(&self) -> u64 {
+    self.dram_base + (self.dram_size - 1)
+  }
+
+  pub fn with_dram(mut self, base: u64, size: u64) -> Self {
+    self.dram_base = base;
+    self.dram_size = size;
+    self
+  }
+
+  pub fn with_misaligned(mut self, policy: MisalignedPolicy) -> Self {
+    self.misaligned = policy;
+    self
+  }
+
+  /// Increment `mtime` once every `cycles` (at least 1) clock cycles of the hart
+  pub fn with_clint_cycles_per_tick(mut self, cycles: u64) -> Self {
+    self.clint_cycles_per_tick = cycles;
+    self
+  }
+
+  /// Add a ROM at `base`, which is exactly as large as `data`
+  pub fn with_rom(mut self, name: &str, base: u64, data: Vec<u8>) -> Self {
+    self.regions.push(MemoryRegion {
+      name: name.to_owned(),
+      base,
+      size: data.len() as u64,
+      kind: RegionKind::Rom(data),
+    });
+    self
+  }
+
+  pub fn with_sram(mut self, name: &str, base: u64, size: u64) -> Self {
+    self.regions.push(MemoryRegion {
+      name: name.to_owned(),
+      base,
+      size,
+      kind: RegionKind::Sram,
+    });
+    self
+  }
+}
@@ -1,8 +1,16 @@
+use std::collections::HashSet;
+use std::fmt;
+
 use crate::bus::*;
+use crate::config::MachineConfig;
 use crate::csr::*;
+use crate::devices::IrqLine;
 use crate::dram::SizeType;
 use crate::exception::*;
+use crate::fpu::{self, RoundingMode, RvFloat};
+use crate::mmu::{self, AccessType, Tlb};
 use crate::param::*;
+use crate::rvc;
 
 const ABI: [&str; 32] = [
   "zero", " ra ", " sp ", " gp ", " tp ", " t0 ", " t1 ", " t2 ", " s0 ", " s1 ", " a0 ", " a1 ",
@@ -10,46 +18,220 @@ const ABI: [&str; 32] = [
   " s8 ", " s9 ", " s10", " s11", " t3 ", " t4 ", " t5 ", " t6 ",
 ];
 
+/// Privilege level of a hart
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Mode {
+  User = 0b00,
+  Supervisor = 0b01,
+  Machine = 0b11,
+}
+
+impl Mode {
+  /// Decode `mstatus.MPP`, where the reserved `0b10` never appears as it's WARL
+  pub fn from_bits(bits: u64) -> Mode {
+    match bits {
+      0b00 => Mode::User,
+      0b01 => Mode::Supervisor,
+      _ => Mode::Machine,
+    }
+  }
+}
+
+/// Why `Cpu::step` or `Cpu::run` stops the hart
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StopReason {
+  /// `Cpu::run` has made as many steps as its limit
+  StepLimit,
+  /// `pc` reached one of `Cpu::breakpoints`, where the instruction isn't executed yet
+  Breakpoint(u64),
+  /// An exception taken as a trap with no handler to run, i.e. nothing can be
+  /// fetched from the trap vector (e.g. `mtvec` is still 0), or the trap is raised
+  /// by the first instruction of the handler itself.
+  ///
+  /// No exception is fatal by its cause: any exception the guest can handle is
+  /// left to the guest, and the CSRs are already updated as the trap is taken.
+  Fatal(Exception),
+  /// The guest requested to exit with the code through `Bus::request_exit`
+  Exit(u64),
+  /// The hart is stalled by `WFI` with no interrupt enabled in `mie`, so nothing
+  /// can wake it up
+  Idle,
+}
+
+impl fmt::Display for StopReason {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      StopReason::StepLimit => write!(f, "Step limit reached"),
+      StopReason::Breakpoint(pc) => write!(f, "Breakpoint at 0x{:016x}", pc),
+      StopReason::Fatal(e) => write!(f, "{e}"),
+      StopReason::Exit(code) => write!(f, "Exit with code {code}"),
+      StopReason::Idle => write!(f, "Hart is idle with no pending interrupt"),
+    }
+  }
+}
+
 /// RISC-V CPU
 ///
 /// - Little-Endian
 /// - 64-bit
 pub struct Cpu {
   pub gpr: [u64; 32],
+  /// Floating-point registers, where `f32` values are NaN-boxed
+  pub fpr: [u64; 32],
   pub pc: u64,
   pub bus: Bus,
   pub csr: Csr,
+  pub mode: Mode,
+  /// Whether the hart is stalled by `WFI` until an interrupt is pending
+  pub wfi: bool,
+  /// TLB of instruction fetches
+  pub itlb: Tlb,
+  /// TLB of loads & stores
+  pub dtlb: Tlb,
+  /// Addresses where `run` stops before executing the instruction
+  pub breakpoints: HashSet<u64>,
+  /// Interrupt lines of devices, each drives a bit of `mip`
+  irq_lines: Vec<(u64, IrqLine)>,
 }
 
 impl Cpu {
   /// Create a new CPU with some existing codes
   pub fn new(code: Vec<u8>) -> Self {
+    Self::with_config(&MachineConfig::default(), code).expect("the default memory map is valid")
+  }
+
+  /// Create a new CPU on the memory map of `config`, with `code` placed at the beginning of DRAM
+  pub fn with_config(config: &MachineConfig, code: Vec<u8>) -> Result<Self, BusError> {
+    let bus = Bus::with_config(config, code)?;
     let mut gpr = [0; 32];
-    gpr[2] = DRAM_END;
-    Self {
+    // The stack grows down from the end of DRAM, aligned to 16 bytes as the psABI requires
+    gpr[2] = (config.dram_base + config.dram_size) & !0xF;
+    let mut csr = Csr::default();
+    // FPU is enabled on reset, so that programs can use it without any setup
+    csr.store(MSTATUS, FS_INITIAL);
+    Ok(Self {
       gpr,
-      pc: DRAM_BASE,
-      bus: Bus::new(code),
-      csr: Csr::default(),
+      fpr: [0; 32],
+      pc: config.dram_base,
+      bus,
+      csr,
+      mode: Mode::Machine,
+      wfi: false,
+      itlb: Tlb::new(TLB_SETS, TLB_WAYS),
+      dtlb: Tlb::new(TLB_SETS, TLB_WAYS),
+      breakpoints: HashSet::new(),
+      irq_lines: Vec::new(),
+    })
+  }
+
+  /// Take a pending interrupt, then execute an instruction (or stay stalled by
+  /// `WFI`), and advance devices by a clock cycle.
+  ///
+  /// Returns why the hart must stop, if it must.
+  pub fn step(&mut self) -> Option<StopReason> {
+    self.check_interrupts();
+    if self.wfi {
+      // Only an interrupt enabled in `mie` can wake up the hart
+      if self.csr.load(MIE) == 0 {
+        return Some(StopReason::Idle);
+      }
+      self.bus.tick();
+      return self.bus.take_exit().map(StopReason::Exit);
     }
+    let result = self.fetch().and_then(|inst| self.execute(inst));
+    match result {
+      Ok(new_pc) => self.pc = new_pc,
+      Err(e) => {
+        let epc = self.pc;
+        self.take_trap(e);
+        // Otherwise the hart would trap to the same handler forever
+        if self.pc == epc || !self.can_fetch(self.pc) {
+          return Some(StopReason::Fatal(e));
+        }
+      }
+    }
+    self.bus.tick();
+    self.bus.take_exit().map(StopReason::Exit)
+  }
+
+  /// Step until the hart must stop, or for `limit` steps.
+  ///
+  /// Breakpoints are checked before every step but the first, so that another
+  /// `run` resumes from the breakpoint it stopped at.
+  pub fn run(&mut self, limit: u64) -> StopReason {
+    for i in 0..limit {
+      if i > 0 && self.breakpoints.contains(&self.pc) {
+        return StopReason::Breakpoint(self.pc);
+      }
+      if let Some(reason) = self.step() {
+        return reason;
+      }
+    }
+    StopReason::StepLimit
   }
 
   /// Read 32bit instruction from a memory
   ///
   /// ![RISC-V base instruction formats](https://book.rvemu.app/img/1-1-2.png)
-  pub fn fetch(&self) -> Result<u32, Exception> {
+  pub fn fetch(&mut self) -> Result<u32, Exception> {
     let curr_pc = self.pc;
-    let curr_code = (self.bus.fetch_inst(curr_pc)? as u32)
-      | ((self.bus.fetch_inst(curr_pc + 1)? as u32) << 8)
-      | ((self.bus.fetch_inst(curr_pc + 2)? as u32) << 16)
-      | ((self.bus.fetch_inst(curr_pc + 3)? as u32) << 24);
+    // Instructions are aligned on 16-bit boundaries with `C` extension
+    if !curr_pc.is_multiple_of(IALIGN) {
+      return Err(Exception::InstructionAddrMisaligned(curr_pc));
+    }
+    let low = self.fetch_half(curr_pc)?;
+    if rvc::is_compressed(low) {
+      return Ok(low);
+    }
+    // The upper half may lie in the next page
+    let curr_code = low | (self.fetch_half(curr_pc + 2)? << 16);
     Ok(curr_code)
   }
 
+  /// Whether an instruction can be fetched from `addr`, which is checked with
+  /// no side effect, i.e. `A` bits, TLBs & devices are left untouched
+  fn can_fetch(&mut self, addr: u64) -> bool {
+    if !addr.is_multiple_of(IALIGN) {
+      return false;
+    }
+    let satp = self.csr.load(SATP);
+    if self.mode == Mode::Machine || mmu::levels(satp).is_none() {
+      return self.bus.is_mapped(addr);
+    }
+    let mstatus = self.csr.load(MSTATUS);
+    let access = AccessType::Instruction;
+    match mmu::probe(&mut self.bus, satp, mstatus, self.mode, addr, access) {
+      Ok(translation) => self.bus.is_mapped(translation.physical_addr(addr)),
+      Err(_) => false,
+    }
+  }
+
+  /// Read 16bit from the virtual address of instruction
+  fn fetch_half(&mut self, addr: u64) -> Result<u32, Exception> {
+    let addr = self.translate(addr, AccessType::Instruction)?;
+    Ok((self.bus.fetch_inst(addr)? as u32) | ((self.bus.fetch_inst(addr + 1)? as u32) << 8))
+  }
+
   /// Decode an instruction and execute it.
   ///
   /// ![RISC-V base instruction formats](https://book.rvemu.app/img/1-1-2.png)
   pub fn execute(&mut self, inst: u32) -> Result<u64, Exception> {
+    if !rvc::is_compressed(inst) {
+      return self.execute_inst(inst, 4);
+    }
+    // 16-bit instruction is executed as its 32-bit equivalent, while
+    // `IllegalInstruction` still reports the original encoding
+    let inst = inst & 0xFFFF;
+    let illegal = Exception::IllegalInstruction(inst as u64);
+    let expanded = rvc::expand(inst as u16).ok_or(illegal)?;
+    self.execute_inst(expanded, 2).map_err(|e| match e {
+      Exception::IllegalInstruction(_) => illegal,
+      e => e,
+    })
+  }
+
+  /// Execute a 32-bit instruction, which is `len` bytes long in memory
+  fn execute_inst(&mut self, inst: u32, len: u64) -> Result<u64, Exception> {
     let opcode = inst & 0x7F;
     let rd = ((inst >> 7) & 0x1F) as usize;
     let rs1 = ((inst >> 15) & 0x1F) as usize;
@@ -57,15 +239,13 @@ impl Cpu {
     let funct3 = (inst >> 12) & 0x7;
     let funct7 = (inst >> 25) & 0x7F;
 
-    // TODO: Implement `RV32I` & `RV64I`
-    match opcode {
+    let next_pc = match opcode {
       BRANCH_OP => {
         let _imm_12 = (inst & 0x8000_0000) as i32 >> 31;
         let _imm_11 = (inst & 0x80) as i32 >> 7;
         let _imm_10_5 = (inst & 0x7E00_0000) as i32 >> 25;
         let _imm_4_1 = (inst & 0xf00) as i32 >> 8;
-        let imm =
-          ((_imm_12 << 12) | (_imm_11 << 11) | (_imm_10_5 << 5) | (_imm_4_1 << 1) | _imm_11) as i64;
+        let imm = ((_imm_12 << 12) | (_imm_11 << 11) | (_imm_10_5 << 5) | (_imm_4_1 << 1)) as i64;
         let if_jump = match funct3 {
           BEQ => self.gpr[rs1] == self.gpr[rs2],
           BNE => self.gpr[rs1] != self.gpr[rs2],
@@ -78,7 +258,7 @@ impl Cpu {
         let next_pc = if if_jump {
           (self.pc as i64).wrapping_add(imm) as u64
         } else {
-          self.pc + 4
+          self.pc + len
         };
         Ok(next_pc)
       }
@@ -86,17 +266,17 @@ impl Cpu {
         let imm = ((inst & 0xFFF0_0000) as i32 >> 20) as i64;
         let addr = (self.gpr[rs1] as i64).wrapping_add(imm) as u64;
         let value = match funct3 {
-          LB => self.bus.load(addr, SizeType::Byte)?,
-          LH => self.bus.load(addr, SizeType::Half)?,
-          LW => self.bus.load(addr, SizeType::Word)?,
-          LD => self.bus.load(addr, SizeType::DoubleWord)?,
-          LBU => self.bus.load_u(addr, SizeType::Byte)?,
-          LHU => self.bus.load_u(addr, SizeType::Half)?,
-          LWU => self.bus.load_u(addr, SizeType::Word)?,
+          LB => self.load(addr, SizeType::Byte)?,
+          LH => self.load(addr, SizeType::Half)?,
+          LW => self.load(addr, SizeType::Word)?,
+          LD => self.load(addr, SizeType::DoubleWord)?,
+          LBU => self.load_u(addr, SizeType::Byte)?,
+          LHU => self.load_u(addr, SizeType::Half)?,
+          LWU => self.load_u(addr, SizeType::Word)?,
           _ => return Err(Exception::IllegalInstruction(inst as u64)),
         };
         self.gpr[rd] = value;
-        Ok(self.pc + 4)
+        Ok(self.pc + len)
       }
       STORE_OP => {
         let _imm_11_5 = (inst & 0xFE00_0000) as i32 >> 25;
@@ -105,70 +285,111 @@ impl Cpu {
         let addr = (self.gpr[rs1] as i64).wrapping_add(imm) as u64;
         let value = self.gpr[rs2];
         match funct3 {
-          SB => self.bus.store(addr, SizeType::Byte, value)?,
-          SH => self.bus.store(addr, SizeType::Half, value)?,
-          SW => self.bus.store(addr, SizeType::Word, value)?,
-          SD => self.bus.store(addr, SizeType::DoubleWord, value)?,
+          SB => self.store(addr, SizeType::Byte, value)?,
+          SH => self.store(addr, SizeType::Half, value)?,
+          SW => self.store(addr, SizeType::Word, value)?,
+          SD => self.store(addr, SizeType::DoubleWord, value)?,
           _ => return Err(Exception::IllegalInstruction(inst as u64)),
         };
-        Ok(self.pc + 4)
+        Ok(self.pc + len)
       }
-      R_TYPE_OP => {
+      R_TYPE_OP if funct7 == MULDIV => {
+        let (a, b) = (self.gpr[rs1], self.gpr[rs2]);
         let result = match funct3 {
-          ADD_SUB => {
-            if funct7 == 0 {
-              (self.gpr[rs1] as i64).wrapping_add(self.gpr[rs2] as i64)
-            } else {
-              (self.gpr[rs1] as i64).wrapping_sub(self.gpr[rs2] as i64)
-            }
-          }
-          SLL => {
+          MUL => (a as i64).wrapping_mul(b as i64) as u64,
+          MULH => (((a as i64 as i128) * (b as i64 as i128)) >> 64) as u64,
+          MULHSU => (((a as i64 as i128) * (b as i128)) >> 64) as u64,
+          MULHU => (((a as u128) * (b as u128)) >> 64) as u64,
+          // Division by zero and overflow don't raise exceptions:
+          // - `x / 0 = -1`, `x % 0 = x`
+          // - `MIN / -1 = MIN`, `MIN % -1 = 0`
+          DIV => match b {
+            0 => u64::MAX,
+            _ => (a as i64).wrapping_div(b as i64) as u64,
+          },
+          DIVU => match b {
+            0 => u64::MAX,
+            _ => a / b,
+          },
+          REM => match b {
+            0 => a,
+            _ => (a as i64).wrapping_rem(b as i64) as u64,
+          },
+          REMU => match b {
+            0 => a,
+            _ => a % b,
+          },
+          _ => return Err(Exception::IllegalInstruction(inst as u64)),
+        };
+        self.gpr[rd] = result;
+        Ok(self.pc + len)
+      }
+      R_TYPE_OP => {
+        let result = match (funct3, funct7) {
+          (ADD_SUB, 0b0000000) => (self.gpr[rs1] as i64).wrapping_add(self.gpr[rs2] as i64),
+          (ADD_SUB, 0b0100000) => (self.gpr[rs1] as i64).wrapping_sub(self.gpr[rs2] as i64),
+          (SLL, 0b0000000) => {
             let shamt = self.gpr[rs2] & 0x3F;
             (self.gpr[rs1]).wrapping_shl(shamt as u32) as i64
           }
-          SRL_SRA => {
+          (SRL_SRA, 0b0000000) => {
             let shamt = self.gpr[rs2] & 0x3F;
-            if funct7 == 0 {
-              (self.gpr[rs1]).wrapping_shr(shamt as u32) as i64
-            } else {
-              (self.gpr[rs1] as i64).wrapping_shr(shamt as u32)
-            }
+            (self.gpr[rs1]).wrapping_shr(shamt as u32) as i64
           }
-          SLT => ((self.gpr[rs1] as i64) < (self.gpr[rs2] as i64)) as i64,
-          SLTU => (self.gpr[rs1] < self.gpr[rs2]) as i64,
-          XOR => (self.gpr[rs1] ^ self.gpr[rs2]) as i64,
-          OR => (self.gpr[rs1] | self.gpr[rs2]) as i64,
-          AND => (self.gpr[rs1] & self.gpr[rs2]) as i64,
+          (SRL_SRA, 0b0100000) => {
+            let shamt = self.gpr[rs2] & 0x3F;
+            (self.gpr[rs1] as i64).wrapping_shr(shamt as u32)
+          }
+          (SLT, 0b0000000) => ((self.gpr[rs1] as i64) < (self.gpr[rs2] as i64)) as i64,
+          (SLTU, 0b0000000) => (self.gpr[rs1] < self.gpr[rs2]) as i64,
+          (XOR, 0b0000000) => (self.gpr[rs1] ^ self.gpr[rs2]) as i64,
+          (OR, 0b0000000) => (self.gpr[rs1] | self.gpr[rs2]) as i64,
+          (AND, 0b0000000) => (self.gpr[rs1] & self.gpr[rs2]) as i64,
           _ => return Err(Exception::IllegalInstruction(inst as u64)),
         };
         self.gpr[rd] = result as u64;
-        Ok(self.pc + 4)
+        Ok(self.pc + len)
       }
-      R_W_TYPE_OP => {
+      R_W_TYPE_OP if funct7 == MULDIV => {
+        let (a, b) = (self.gpr[rs1] as i32, self.gpr[rs2] as i32);
         let result = match funct3 {
-          ADDW_SUBW => {
-            if funct7 == 0 {
-              (self.gpr[rs1] as i64).wrapping_add(self.gpr[rs2] as i64)
-            } else {
-              (self.gpr[rs1] as i64).wrapping_sub(self.gpr[rs2] as i64)
-            }
-          }
-          SLLW => {
-            let shamt = self.gpr[rs2] & 0x3F;
-            ((self.gpr[rs1]) << shamt) as i64
-          }
-          SRLW_SRAW => {
-            let shamt = self.gpr[rs2] & 0x3F;
-            if funct7 == 0 {
-              ((self.gpr[rs1]) >> shamt) as i64
-            } else {
-              self.gpr[rs1] as i64 >> shamt
-            }
-          }
+          MULW => a.wrapping_mul(b),
+          DIVW => match b {
+            0 => -1,
+            _ => a.wrapping_div(b),
+          },
+          DIVUW => match b {
+            0 => -1,
+            _ => ((a as u32) / (b as u32)) as i32,
+          },
+          REMW => match b {
+            0 => a,
+            _ => a.wrapping_rem(b),
+          },
+          REMUW => match b {
+            0 => a,
+            _ => ((a as u32) % (b as u32)) as i32,
+          },
           _ => return Err(Exception::IllegalInstruction(inst as u64)),
         };
-        self.gpr[rd] = result as u64;
-        Ok(self.pc + 4)
+        // RV64M: 32-bit result is sign-extended to 64 bits
+        self.gpr[rd] = result as i64 as u64;
+        Ok(self.pc + len)
+      }
+      R_W_TYPE_OP => {
+        let (a, b) = (self.gpr[rs1] as i32, self.gpr[rs2] as i32);
+        let shamt = (b & 0x1F) as u32;
+        let result = match (funct3, funct7) {
+          (ADDW_SUBW, 0b0000000) => a.wrapping_add(b),
+          (ADDW_SUBW, 0b0100000) => a.wrapping_sub(b),
+          (SLLW, 0b0000000) => (a as u32).wrapping_shl(shamt) as i32,
+          (SRLW_SRAW, 0b0000000) => (a as u32).wrapping_shr(shamt) as i32,
+          (SRLW_SRAW, 0b0100000) => a.wrapping_shr(shamt),
+          _ => return Err(Exception::IllegalInstruction(inst as u64)),
+        };
+        // RV64I: 32-bit result is sign-extended to 64 bits
+        self.gpr[rd] = result as i64 as u64;
+        Ok(self.pc + len)
       }
       I_TYPE_OP => {
         let imm = ((inst & 0xFFF0_0000) as i32 >> 20) as i64;
@@ -182,7 +403,8 @@ impl Cpu {
           ANDI => (self.gpr[rs1] & imm as u64) as i64,
           SLLI => (self.gpr[rs1]).wrapping_shl(shamt) as i64,
           SRLI_SRAI => {
-            if funct7 == 0 {
+            // RV64I: shamt is 6 bits wide, so only funct7[6:1] tells SRLI from SRAI
+            if funct7 >> 1 == 0 {
               (self.gpr[rs1]).wrapping_shr(shamt) as i64
             } else {
               (self.gpr[rs1] as i64).wrapping_shr(shamt)
@@ -191,28 +413,755 @@ impl Cpu {
           _ => return Err(Exception::IllegalInstruction(inst as u64)),
         };
         self.gpr[rd] = result as u64;
-        Ok(self.pc + 4)
+        Ok(self.pc + len)
       }
       I_W_TYPE_OP => {
         let imm = ((inst & 0xFFF0_0000) as i32 >> 20) as i64;
-        let shamt = (imm & 0x3F) as u32;
+        let shamt = (imm & 0x1F) as u32;
         let result = match funct3 {
-          ADDIW => (self.gpr[rs1] as i64).wrapping_add(imm),
-          SLLIW => (self.gpr[rs1]).wrapping_shl(shamt) as i64,
+          ADDIW => (self.gpr[rs1] as i32).wrapping_add(imm as i32),
+          SLLIW => (self.gpr[rs1] as u32).wrapping_shl(shamt) as i32,
           SRLIW_SRAIW => {
             if funct7 == 0 {
-              (self.gpr[rs1]).wrapping_shr(shamt) as i64
+              (self.gpr[rs1] as u32).wrapping_shr(shamt) as i32
             } else {
-              (self.gpr[rs1] as i64).wrapping_shr(shamt)
+              (self.gpr[rs1] as i32).wrapping_shr(shamt)
             }
           }
           _ => return Err(Exception::IllegalInstruction(inst as u64)),
         };
-        self.gpr[rd] = result as u64;
-        Ok(self.pc + 4)
+        // RV64I: 32-bit result is sign-extended to 64 bits
+        self.gpr[rd] = result as i64 as u64;
+        Ok(self.pc + len)
+      }
+      LUI => {
+        // U-type: imm[31:12] is placed in the upper 20 bits, then sign-extended
+        let imm = (inst & 0xFFFF_F000) as i32 as i64;
+        self.gpr[rd] = imm as u64;
+        Ok(self.pc + len)
+      }
+      AUIPC => {
+        let imm = (inst & 0xFFFF_F000) as i32 as i64;
+        self.gpr[rd] = (self.pc as i64).wrapping_add(imm) as u64;
+        Ok(self.pc + len)
+      }
+      JAL => {
+        // J-type: imm[20|10:1|11|19:12]
+        let _imm_20 = (inst & 0x8000_0000) as i32 >> 31;
+        let _imm_19_12 = (inst & 0xF_F000) as i32 >> 12;
+        let _imm_11 = (inst & 0x10_0000) as i32 >> 20;
+        let _imm_10_1 = (inst & 0x7FE0_0000) as i32 >> 21;
+        let imm =
+          ((_imm_20 << 20) | (_imm_19_12 << 12) | (_imm_11 << 11) | (_imm_10_1 << 1)) as i64;
+        let next_pc = (self.pc as i64).wrapping_add(imm) as u64;
+        self.gpr[rd] = self.pc + len;
+        Ok(next_pc)
+      }
+      JALR => {
+        let imm = ((inst & 0xFFF0_0000) as i32 >> 20) as i64;
+        // `rs1` may be the same as `rd`, so compute the target first.
+        // The least-significant bit of the target is cleared.
+        let next_pc = (self.gpr[rs1] as i64).wrapping_add(imm) as u64 & !1;
+        self.gpr[rd] = self.pc + len;
+        Ok(next_pc)
+      }
+      AMO_OP => {
+        let funct5 = funct7 >> 2;
+        let (size, n_bytes) = match funct3 {
+          AMO_W => (SizeType::Word, 4),
+          AMO_D => (SizeType::DoubleWord, 8),
+          _ => return Err(Exception::IllegalInstruction(inst as u64)),
+        };
+        let hart = self.csr.load(MHARTID);
+        let addr = self.gpr[rs1];
+        if !addr.is_multiple_of(n_bytes) {
+          return Err(match funct5 {
+            LR => Exception::LoadAccessMisaligned(addr),
+            _ => Exception::StoreAMOAddrMisaligned(addr),
+          });
+        }
+        // Reservations are registered on physical addresses
+        let access = if funct5 == LR {
+          AccessType::Load
+        } else {
+          AccessType::Store
+        };
+        let paddr = self.translate(addr, access)?;
+        match funct5 {
+          LR => {
+            if rs2 != 0 {
+              return Err(Exception::IllegalInstruction(inst as u64));
+            }
+            self.gpr[rd] = self
+              .bus
+              .load(paddr, size)
+              .map_err(|_| Exception::LoadAccessFault(addr))?;
+            self.bus.reserve(hart, paddr);
+          }
+          SC => {
+            // `rd` is set to 0 on success, otherwise a nonzero value
+            if self.bus.take_reservation(hart, paddr) {
+              self
+                .bus
+                .store(paddr, size, self.gpr[rs2])
+                .map_err(|_| Exception::StoreAMOAccessFault(addr))?;
+              self.gpr[rd] = 0;
+            } else {
+              self.gpr[rd] = 1;
+            }
+          }
+          _ => {
+            // AMOs always report access faults as `StoreAMOAccessFault`
+            let loaded = self
+              .bus
+              .load(paddr, size)
+              .map_err(|_| Exception::StoreAMOAccessFault(addr))?;
+            let src = self.gpr[rs2];
+            let value = if n_bytes == 4 {
+              let (a, b) = (loaded as i32, src as i32);
+              (match funct5 {
+                AMOSWAP => b,
+                AMOADD => a.wrapping_add(b),
+                AMOXOR => a ^ b,
+                AMOAND => a & b,
+                AMOOR => a | b,
+                AMOMIN => a.min(b),
+                AMOMAX => a.max(b),
+                AMOMINU => (a as u32).min(b as u32) as i32,
+                AMOMAXU => (a as u32).max(b as u32) as i32,
+                _ => return Err(Exception::IllegalInstruction(inst as u64)),
+              }) as u64
+            } else {
+              let (a, b) = (loaded, src);
+              match funct5 {
+                AMOSWAP => b,
+                AMOADD => a.wrapping_add(b),
+                AMOXOR => a ^ b,
+                AMOAND => a & b,
+                AMOOR => a | b,
+                AMOMIN => (a as i64).min(b as i64) as u64,
+                AMOMAX => (a as i64).max(b as i64) as u64,
+                AMOMINU => a.min(b),
+                AMOMAXU => a.max(b),
+                _ => return Err(Exception::IllegalInstruction(inst as u64)),
+              }
+            };
+            self
+              .bus
+              .store(paddr, size, value)
+              .map_err(|_| Exception::StoreAMOAccessFault(addr))?;
+            self.gpr[rd] = loaded;
+          }
+        }
+        Ok(self.pc + len)
+      }
+      LOAD_FP_OP => {
+        self.check_fs(inst)?;
+        let imm = ((inst & 0xFFF0_0000) as i32 >> 20) as i64;
+        let addr = (self.gpr[rs1] as i64).wrapping_add(imm) as u64;
+        self.fpr[rd] = match funct3 {
+          FLW_FSW => f32::mv_from_x(self.load_u(addr, SizeType::Word)?),
+          FLD_FSD => self.load_u(addr, SizeType::DoubleWord)?,
+          _ => return Err(Exception::IllegalInstruction(inst as u64)),
+        };
+        self.dirty_fs();
+        Ok(self.pc + len)
+      }
+      STORE_FP_OP => {
+        self.check_fs(inst)?;
+        let _imm_11_5 = (inst & 0xFE00_0000) as i32 >> 25;
+        let _imm_4_0 = (inst & 0xF80) as i32 >> 7;
+        let imm = ((_imm_11_5 << 5) | _imm_4_0) as i64;
+        let addr = (self.gpr[rs1] as i64).wrapping_add(imm) as u64;
+        // Raw bits are stored, no matter whether they're NaN-boxed
+        let value = self.fpr[rs2];
+        match funct3 {
+          FLW_FSW => self.store(addr, SizeType::Word, value)?,
+          FLD_FSD => self.store(addr, SizeType::DoubleWord, value)?,
+          _ => return Err(Exception::IllegalInstruction(inst as u64)),
+        };
+        Ok(self.pc + len)
+      }
+      FMADD_OP | FMSUB_OP | FNMSUB_OP | FNMADD_OP => {
+        self.check_fs(inst)?;
+        match funct7 & 0b11 {
+          FMT_S => self.execute_fma::<f32>(inst)?,
+          FMT_D => self.execute_fma::<f64>(inst)?,
+          _ => return Err(Exception::IllegalInstruction(inst as u64)),
+        }
+        Ok(self.pc + len)
+      }
+      OP_FP => {
+        self.check_fs(inst)?;
+        // `fmt` of the destination, and `rs2` holds that of the source
+        match (funct7 >> 2, funct7 & 0b11, rs2 as u32) {
+          // FCVT.S.D
+          (FCVT_F_F, FMT_S, FMT_D) => {
+            let rm = self.rounding_mode(funct3, inst)?;
+            let mut fflags = 0;
+            let result = fpu::convert::<f64, f32>(self.read_fp(rs1), rm, &mut fflags);
+            self.write_fp(rd, result);
+            self.accrue_fflags(fflags);
+          }
+          // FCVT.D.S
+          (FCVT_F_F, FMT_D, FMT_S) => {
+            let rm = self.rounding_mode(funct3, inst)?;
+            let mut fflags = 0;
+            let result = fpu::convert::<f32, f64>(self.read_fp(rs1), rm, &mut fflags);
+            self.write_fp(rd, result);
+            self.accrue_fflags(fflags);
+          }
+          _ => match funct7 & 0b11 {
+            FMT_S => self.execute_op_fp::<f32>(inst)?,
+            FMT_D => self.execute_op_fp::<f64>(inst)?,
+            _ => return Err(Exception::IllegalInstruction(inst as u64)),
+          },
+        }
+        Ok(self.pc + len)
+      }
+      FENCE => match funct3 {
+        // `FENCE`, `FENCE.TSO` and `FENCE.I` are no-ops, as this hart executes
+        // instructions and accesses memory strictly in order
+        0b000 | FENCE_I => Ok(self.pc + len),
+        _ => Err(Exception::IllegalInstruction(inst as u64)),
+      },
+      E_TYPE_OP => {
+        let csr_addr = ((inst & 0xFFF0_0000) >> 20) as usize;
+        // `rs1` field holds a 5-bit zero-extended immediate in `CSRR*I`
+        let uimm = rs1 as u64;
+        match funct3 {
+          PRIV if funct7 == SFENCE_VMA => {
+            // `SFENCE.VMA` is illegal in U-mode, and also in S-mode when `mstatus.TVM` is set
+            let tvm = self.csr.load(MSTATUS) & MASK_TVM != 0;
+            if rd != 0 || self.mode == Mode::User || (self.mode == Mode::Supervisor && tvm) {
+              return Err(Exception::IllegalInstruction(inst as u64));
+            }
+            // `rs1 == x0` flushes all addresses, `rs2 == x0` flushes all ASIDs
+            let addr = (rs1 != 0).then_some(self.gpr[rs1]);
+            let asid = (rs2 != 0).then_some(self.gpr[rs2] as u16);
+            self.itlb.flush(addr, asid);
+            self.dtlb.flush(addr, asid);
+          }
+          PRIV => {
+            let funct12 = inst >> 20;
+            return match funct12 {
+              EBREAK => Err(Exception::Breakpoint(self.pc)),
+              ECALL => Err(match self.mode {
+                Mode::User => Exception::EnvironmentCallFromUMode(self.pc),
+                Mode::Supervisor => Exception::EnvironmentCallFromSMode(self.pc),
+                Mode::Machine => Exception::EnvironmentCallFromMMode(self.pc),
+              }),
+              SRET => {
+                // `SRET` is illegal in U-mode, and also in S-mode when `mstatus.TSR` is set
+                let tsr = self.csr.load(MSTATUS) & MASK_TSR != 0;
+                if self.mode == Mode::User || (self.mode == Mode::Supervisor && tsr) {
+                  return Err(Exception::IllegalInstruction(inst as u64));
+                }
+                Ok(self.sret())
+              }
+              MRET => {
+                if self.mode != Mode::Machine {
+                  return Err(Exception::IllegalInstruction(inst as u64));
+                }
+                Ok(self.mret())
+              }
+              WFI => {
+                // `WFI` is illegal in U-mode, and also in S-mode when `mstatus.TW` is set
+                let tw = self.csr.load(MSTATUS) & MASK_TW != 0;
+                if self.mode == Mode::User || (self.mode == Mode::Supervisor && tw) {
+                  return Err(Exception::IllegalInstruction(inst as u64));
+                }
+                self.wfi = true;
+                Ok(self.pc + len)
+              }
+              _ => Err(Exception::IllegalInstruction(inst as u64)),
+            };
+          }
+          CSRRW | CSRRWI => {
+            self.check_csr_access(csr_addr, true, inst)?;
+            let value = if funct3 == CSRRW { self.gpr[rs1] } else { uimm };
+            // `CSRRW` with `rd == x0` shall not read the CSR
+            if rd != 0 {
+              self.gpr[rd] = self.csr.load(csr_addr);
+            }
+            self.store_csr(csr_addr, value);
+          }
+          CSRRS | CSRRC | CSRRSI | CSRRCI => {
+            // Set/Clear with `rs1 == x0` (or `uimm == 0`) shall not write the CSR
+            let mask = if funct3 & 0b100 == 0 {
+              self.gpr[rs1]
+            } else {
+              uimm
+            };
+            let write = rs1 != 0;
+            self.check_csr_access(csr_addr, write, inst)?;
+            let old = self.csr.load(csr_addr);
+            if write {
+              let base = self.csr.load_software(csr_addr);
+              let value = if funct3 & 0b011 == CSRRS {
+                base | mask
+              } else {
+                base & !mask
+              };
+              self.store_csr(csr_addr, value);
+            }
+            self.gpr[rd] = old;
+          }
+          _ => return Err(Exception::IllegalInstruction(inst as u64)),
+        }
+        Ok(self.pc + len)
       }
       _ => Err(Exception::IllegalInstruction(inst as u64)),
+    };
+
+    // `x0` is hardwired with all bits equal to 0
+    self.gpr[0] = 0;
+
+    next_pc
+  }
+
+  /// Execute `FMADD`, `FMSUB`, `FNMSUB` & `FNMADD`
+  fn execute_fma<F: RvFloat>(&mut self, inst: u32) -> Result<(), Exception> {
+    let opcode = inst & 0x7F;
+    let rd = ((inst >> 7) & 0x1F) as usize;
+    let rs1 = ((inst >> 15) & 0x1F) as usize;
+    let rs2 = ((inst >> 20) & 0x1F) as usize;
+    let rs3 = ((inst >> 27) & 0x1F) as usize;
+    let rm = self.rounding_mode((inst >> 12) & 0x7, inst)?;
+    let (a, b, c) = (
+      self.read_fp::<F>(rs1),
+      self.read_fp::<F>(rs2),
+      self.read_fp::<F>(rs3),
+    );
+    let mut fflags = 0;
+    let result = match opcode {
+      FMADD_OP => fpu::fma(a, b, c, rm, &mut fflags),
+      FMSUB_OP => fpu::fma(a, b, c.negate(), rm, &mut fflags),
+      FNMSUB_OP => fpu::fma(a.negate(), b, c, rm, &mut fflags),
+      FNMADD_OP => fpu::fma(a.negate(), b, c.negate(), rm, &mut fflags),
+      _ => return Err(Exception::IllegalInstruction(inst as u64)),
+    };
+    self.write_fp(rd, result);
+    self.accrue_fflags(fflags);
+    Ok(())
+  }
+
+  /// Execute `OP-FP` instructions of a single format
+  fn execute_op_fp<F: RvFloat>(&mut self, inst: u32) -> Result<(), Exception> {
+    let rd = ((inst >> 7) & 0x1F) as usize;
+    let rs1 = ((inst >> 15) & 0x1F) as usize;
+    let rs2 = ((inst >> 20) & 0x1F) as usize;
+    let funct3 = (inst >> 12) & 0x7;
+    let funct5 = inst >> 27;
+    let illegal = Exception::IllegalInstruction(inst as u64);
+    let (a, b) = (self.read_fp::<F>(rs1), self.read_fp::<F>(rs2));
+    let mut fflags = 0;
+    match funct5 {
+      FADD | FSUB | FMUL | FDIV => {
+        let rm = self.rounding_mode(funct3, inst)?;
+        let result = match funct5 {
+          FADD => fpu::add(a, b, rm, &mut fflags),
+          FSUB => fpu::sub(a, b, rm, &mut fflags),
+          FMUL => fpu::mul(a, b, rm, &mut fflags),
+          _ => fpu::div(a, b, rm, &mut fflags),
+        };
+        self.write_fp(rd, result);
+      }
+      FSQRT if rs2 == 0 => {
+        let rm = self.rounding_mode(funct3, inst)?;
+        let result = fpu::sqrt(a, rm, &mut fflags);
+        self.write_fp(rd, result);
+      }
+      FSGNJ => {
+        let result = fpu::sign_inject(a, b, funct3).ok_or(illegal)?;
+        self.write_fp(rd, result);
+      }
+      FMIN_FMAX => {
+        let result = match funct3 {
+          FMIN => fpu::min_max(a, b, false, &mut fflags),
+          FMAX => fpu::min_max(a, b, true, &mut fflags),
+          _ => return Err(illegal),
+        };
+        self.write_fp(rd, result);
+      }
+      FCMP => {
+        self.gpr[rd] = fpu::compare(a, b, funct3, &mut fflags).ok_or(illegal)? as u64;
+      }
+      FCVT_X_F => {
+        let rm = self.rounding_mode(funct3, inst)?;
+        let (signed, bits) = match rs2 {
+          0b00 => (true, 32),
+          0b01 => (false, 32),
+          0b10 => (true, 64),
+          0b11 => (false, 64),
+          _ => return Err(illegal),
+        };
+        self.gpr[rd] = fpu::to_int(a, signed, bits, rm, &mut fflags);
+      }
+      FCVT_F_X => {
+        let rm = self.rounding_mode(funct3, inst)?;
+        let x = match rs2 {
+          0b00 => self.gpr[rs1] as i32 as i128,
+          0b01 => self.gpr[rs1] as u32 as i128,
+          0b10 => self.gpr[rs1] as i64 as i128,
+          0b11 => self.gpr[rs1] as i128,
+          _ => return Err(illegal),
+        };
+        let result = fpu::from_int::<F>(x, rm, &mut fflags);
+        self.write_fp(rd, result);
+      }
+      FMV_X_F_FCLASS if rs2 == 0 => {
+        self.gpr[rd] = match funct3 {
+          FMV_X_F => F::mv_to_x(self.fpr[rs1]),
+          FCLASS => fpu::classify(a),
+          _ => return Err(illegal),
+        };
+      }
+      FMV_F_X if rs2 == 0 && funct3 == 0 => {
+        self.fpr[rd] = F::mv_from_x(self.gpr[rs1]);
+        self.dirty_fs();
+      }
+      _ => return Err(illegal),
+    }
+    self.accrue_fflags(fflags);
+    Ok(())
+  }
+
+  /// Read a floating-point value from `FPR`, which is NaN-unboxed if necessary
+  fn read_fp<F: RvFloat>(&self, r: usize) -> F {
+    F::unbox(self.fpr[r])
+  }
+
+  /// Write a floating-point value into `FPR`, which is NaN-boxed if necessary
+  fn write_fp<F: RvFloat>(&mut self, r: usize, value: F) {
+    self.fpr[r] = value.boxed();
+    self.dirty_fs();
+  }
+
+  /// Decode the `rm` field, where `DYN` selects the rounding mode in `frm`
+  fn rounding_mode(&self, rm: u32, inst: u32) -> Result<RoundingMode, Exception> {
+    let rm = match rm {
+      RM_DYN => self.csr.load(FRM),
+      _ => rm as u64,
+    };
+    RoundingMode::from_bits(rm).ok_or(Exception::IllegalInstruction(inst as u64))
+  }
+
+  /// Accumulate exception flags into `fflags`
+  fn accrue_fflags(&mut self, fflags: u64) {
+    if fflags != 0 {
+      let old = self.csr.load(FFLAGS);
+      self.csr.store(FFLAGS, old | fflags);
+      self.dirty_fs();
+    }
+  }
+
+  /// Raise `IllegalInstruction` if the FPU is off, i.e. `mstatus.FS == Off`
+  fn check_fs(&self, inst: u32) -> Result<(), Exception> {
+    if self.csr.load(MSTATUS) & MASK_FS == FS_OFF {
+      return Err(Exception::IllegalInstruction(inst as u64));
+    }
+    Ok(())
+  }
+
+  /// Mark `mstatus.FS` as dirty once the floating-point state is modified
+  fn dirty_fs(&mut self) {
+    let mstatus = self.csr.load(MSTATUS);
+    self.csr.store(MSTATUS, mstatus | FS_DIRTY);
+  }
+
+  /// Translate a virtual address into a physical one, with `MMU` of `satp`
+  pub fn translate(&mut self, addr: u64, access: AccessType) -> Result<u64, Exception> {
+    let mstatus = self.csr.load(MSTATUS);
+    // `MPRV` makes loads & stores in M-mode translated and protected as in `MPP`
+    let mode = if access != AccessType::Instruction
+      && self.mode == Mode::Machine
+      && mstatus & MASK_MPRV != 0
+    {
+      Mode::from_bits((mstatus & MASK_MPP) >> 11)
+    } else {
+      self.mode
+    };
+    let satp = self.csr.load(SATP);
+    if mode == Mode::Machine || mmu::levels(satp).is_none() {
+      return Ok(addr);
+    }
+    let asid = ((satp & MASK_SATP_ASID) >> 44) as u16;
+    let tlb = match access {
+      AccessType::Instruction => &mut self.itlb,
+      _ => &mut self.dtlb,
+    };
+    // Permissions are checked on every hit, and a store to a clean page
+    // walks again to set the `D` bit
+    let usable = |translation: &mmu::Translation| {
+      (access != AccessType::Store || translation.pte & MASK_PTE_D != 0)
+        && mmu::check_permission(translation.pte, mode, mstatus, access)
+    };
+    if let Some(translation) = tlb.lookup(asid, addr, usable) {
+      return Ok(translation.physical_addr(addr));
+    }
+    let translation = mmu::walk(&mut self.bus, satp, mstatus, mode, addr, access)?;
+    tlb.insert(asid, addr, translation);
+    Ok(translation.physical_addr(addr))
+  }
+
+  /// Load from a virtual address, the value is sign-extended
+  pub fn load(&mut self, addr: u64, size: SizeType) -> Result<u64, Exception> {
+    Ok(size.sign_extend(self.load_u(addr, size)?))
+  }
+
+  /// Load from a virtual address, the value is zero-extended
+  pub fn load_u(&mut self, addr: u64, size: SizeType) -> Result<u64, Exception> {
+    // Misalignment is checked on the virtual address, which is reported in `xtval`
+    self.bus.check_alignment(addr, size, AccessType::Load)?;
+    let n_bytes = size.how_many_bytes() as u64;
+    if addr % PAGE_SIZE + n_bytes <= PAGE_SIZE {
+      let addr = self.translate(addr, AccessType::Load)?;
+      return self.bus.load_u(addr, size);
+    }
+    // Bytes on both sides of a page boundary are translated separately
+    let mut value = 0;
+    for i in 0..n_bytes {
+      let paddr = self.translate(addr.wrapping_add(i), AccessType::Load)?;
+      value |= self.bus.load_u(paddr, SizeType::Byte)? << (8 * i);
+    }
+    Ok(value)
+  }
+
+  /// Store to a virtual address
+  pub fn store(&mut self, addr: u64, size: SizeType, value: u64) -> Result<(), Exception> {
+    self.bus.check_alignment(addr, size, AccessType::Store)?;
+    let n_bytes = size.how_many_bytes() as u64;
+    if addr % PAGE_SIZE + n_bytes <= PAGE_SIZE {
+      let addr = self.translate(addr, AccessType::Store)?;
+      return self.bus.store(addr, size, value);
+    }
+    // Both pages are translated before any byte is written
+    let mut paddrs = [0; 8];
+    for i in 0..n_bytes {
+      paddrs[i as usize] = self.translate(addr.wrapping_add(i), AccessType::Store)?;
+    }
+    for i in 0..n_bytes {
+      self
+        .bus
+        .store(paddrs[i as usize], SizeType::Byte, value >> (8 * i))?;
+    }
+    Ok(())
+  }
+
+  /// Whether any interrupt is both pending and enabled in `mie`, regardless of
+  /// the global interrupt-enable bits. This is what wakes up a hart from `WFI`.
+  pub fn has_pending_interrupt(&self) -> bool {
+    self.csr.load(MIP) & self.csr.load(MIE) != 0
+  }
+
+  /// Connect an interrupt line of a device to the `mip` bit(s) in `mask`
+  pub fn connect_irq(&mut self, mask: u64, line: IrqLine) {
+    self.irq_lines.push((mask, line));
+  }
+
+  /// The interrupt to be taken right now, which has the highest priority among
+  /// those pending, enabled in `mie` and globally enabled for the current mode.
+  ///
+  /// Interrupts delegated by `mideleg` are never taken in M-mode.
+  pub fn pending_interrupt(&self) -> Option<Interrupt> {
+    let pending = self.csr.load(MIP) & self.csr.load(MIE);
+    let mstatus = self.csr.load(MSTATUS);
+    let mideleg = self.csr.load(MIDELEG);
+    let m_enabled = self.mode < Mode::Machine || mstatus & MASK_MIE != 0;
+    let s_enabled =
+      self.mode < Mode::Supervisor || (self.mode == Mode::Supervisor && mstatus & MASK_SIE != 0);
+    let mut enabled = 0;
+    if m_enabled {
+      enabled |= pending & !mideleg;
+    }
+    if s_enabled {
+      enabled |= pending & mideleg;
+    }
+    Interrupt::PRIORITY
+      .into_iter()
+      .find(|i| enabled & i.mask() != 0)
+  }
+
+  /// Sample interrupt lines into `mip`, then take the pending interrupt if any.
+  ///
+  /// This should be called between instructions, and returns whether a trap is taken.
+  pub fn check_interrupts(&mut self) -> bool {
+    let mut seip = false;
+    for (mask, line) in self.irq_lines.iter() {
+      // `mip.SEIP` is also writable by software, so its line is ORed with it instead
+      if mask & MASK_SEIP != 0 {
+        seip |= line.is_raised();
+      }
+      self.csr.set_pending(mask & !MASK_SEIP, line.is_raised());
+    }
+    self.csr.set_seip_line(seip);
+
+    if self.wfi && self.has_pending_interrupt() {
+      self.wfi = false;
+    }
+    match self.pending_interrupt() {
+      Some(i) => {
+        self.take_interrupt(i);
+        true
+      }
+      None => false,
+    }
+  }
+
+  /// Take a trap caused by an exception.
+  ///
+  /// The trap is handled in S-mode if it's delegated by `medeleg` and the hart is
+  /// not running in M-mode, otherwise it's handled in M-mode.
+  pub fn take_trap(&mut self, e: Exception) {
+    // The value of an environment call is its `pc`, which `xepc` already holds
+    let tval = match e {
+      Exception::EnvironmentCallFromUMode(_)
+      | Exception::EnvironmentCallFromSMode(_)
+      | Exception::EnvironmentCallFromMMode(_) => 0,
+      _ => e.value(),
+    };
+    self.trap(e.code(), tval, false);
+  }
+
+  /// Take a trap caused by an interrupt, which is delegated by `mideleg` instead
+  pub fn take_interrupt(&mut self, i: Interrupt) {
+    self.wfi = false;
+    self.trap(i.code(), 0, true);
+  }
+
+  fn trap(&mut self, cause: u64, tval: u64, interrupt: bool) {
+    // A trap always breaks the `LR`/`SC` sequence
+    self.bus.release_reservation(self.csr.load(MHARTID));
+
+    let epc = self.pc;
+    let prev_mode = self.mode;
+    let deleg = if interrupt { MIDELEG } else { MEDELEG };
+    let delegated = (self.csr.load(deleg) >> cause) & 1 == 1;
+    // Only interrupts may be vectored
+    let target = |tvec: u64| {
+      let base = tvec & !MASK_TVEC_MODE;
+      if interrupt && tvec & MASK_TVEC_MODE == TVEC_MODE_VECTORED {
+        base + 4 * cause
+      } else {
+        base
+      }
+    };
+    let cause = if interrupt {
+      cause | MASK_INTERRUPT
+    } else {
+      cause
+    };
+
+    if prev_mode <= Mode::Supervisor && delegated {
+      self.mode = Mode::Supervisor;
+      self.csr.store(SEPC, epc);
+      self.csr.store(SCAUSE, cause);
+      self.csr.store(STVAL, tval);
+      self.pc = target(self.csr.load(STVEC));
+
+      let mut sstatus = self.csr.load(SSTATUS);
+      // SPIE <- SIE, SIE <- 0, SPP <- previous mode
+      if sstatus & MASK_SIE != 0 {
+        sstatus |= MASK_SPIE;
+      } else {
+        sstatus &= !MASK_SPIE;
+      }
+      sstatus &= !MASK_SIE;
+      if prev_mode == Mode::User {
+        sstatus &= !MASK_SPP;
+      } else {
+        sstatus |= MASK_SPP;
+      }
+      self.csr.store(SSTATUS, sstatus);
+    } else {
+      self.mode = Mode::Machine;
+      self.csr.store(MEPC, epc);
+      self.csr.store(MCAUSE, cause);
+      self.csr.store(MTVAL, tval);
+      self.pc = target(self.csr.load(MTVEC));
+
+      let mut mstatus = self.csr.load(MSTATUS);
+      // MPIE <- MIE, MIE <- 0, MPP <- previous mode
+      if mstatus & MASK_MIE != 0 {
+        mstatus |= MASK_MPIE;
+      } else {
+        mstatus &= !MASK_MPIE;
+      }
+      mstatus &= !MASK_MIE;
+      mstatus = (mstatus & !MASK_MPP) | ((prev_mode as u64) << 11);
+      self.csr.store(MSTATUS, mstatus);
+    }
+  }
+
+  /// Return from a trap handled in M-mode, and return the new `pc`
+  fn mret(&mut self) -> u64 {
+    let mut mstatus = self.csr.load(MSTATUS);
+    self.mode = Mode::from_bits((mstatus & MASK_MPP) >> 11);
+    // MIE <- MPIE, MPIE <- 1, MPP <- U
+    if mstatus & MASK_MPIE != 0 {
+      mstatus |= MASK_MIE;
+    } else {
+      mstatus &= !MASK_MIE;
+    }
+    mstatus |= MASK_MPIE;
+    mstatus &= !MASK_MPP;
+    if self.mode != Mode::Machine {
+      mstatus &= !MASK_MPRV;
+    }
+    self.csr.store(MSTATUS, mstatus);
+    self.csr.load(MEPC)
+  }
+
+  /// Return from a trap handled in S-mode, and return the new `pc`
+  fn sret(&mut self) -> u64 {
+    let mut mstatus = self.csr.load(MSTATUS);
+    self.mode = if mstatus & MASK_SPP != 0 {
+      Mode::Supervisor
+    } else {
+      Mode::User
+    };
+    // SIE <- SPIE, SPIE <- 1, SPP <- U
+    if mstatus & MASK_SPIE != 0 {
+      mstatus |= MASK_SIE;
+    } else {
+      mstatus &= !MASK_SIE;
+    }
+    mstatus |= MASK_SPIE;
+    mstatus &= !MASK_SPP;
+    mstatus &= !MASK_MPRV;
+    self.csr.store(MSTATUS, mstatus);
+    self.csr.load(SEPC)
+  }
+
+  /// Write a CSR by a Zicsr instruction
+  fn store_csr(&mut self, addr: usize, value: u64) {
+    self.csr.store(addr, value);
+    if (FFLAGS..=FCSR).contains(&addr) {
+      self.dirty_fs();
+    }
+    // Cached translations may belong to the previous address space
+    if addr == SATP {
+      self.itlb.flush(None, None);
+      self.dtlb.flush(None, None);
+    }
+  }
+
+  /// Raise `IllegalInstruction` if the CSR cannot be accessed from the current
+  /// privilege level, or if it's read-only but is about to be written.
+  fn check_csr_access(&self, addr: usize, write: bool, inst: u32) -> Result<(), Exception> {
+    if (write && is_read_only(addr)) || min_privilege(addr) > self.mode as u64 {
+      return Err(Exception::IllegalInstruction(inst as u64));
+    }
+    // Floating-point CSRs are inaccessible when the FPU is off
+    if (FFLAGS..=FCSR).contains(&addr) {
+      self.check_fs(inst)?;
+    }
+    // `satp` is inaccessible in S-mode when `mstatus.TVM` is set
+    if addr == SATP && self.mode == Mode::Supervisor && self.csr.load(MSTATUS) & MASK_TVM != 0 {
+      return Err(Exception::IllegalInstruction(inst as u64));
     }
+    Ok(())
   }
 
   /// Dump all registers onto the screen
@@ -240,7 +1189,7 @@ impl Cpu {
   }
 
   pub fn observe_reg(&self, r: &str) -> u64 {
-    match ABI.iter().position(|&x| x == r) {
+    match ABI.iter().position(|&x| x.trim() == r) {
       Some(i) => self.gpr[i],
       None => match r {
         "pc" => self.pc,
@@ -1,32 +1,112 @@
+use crate::mmu;
 use crate::param::*;
 
 const NUM_CSRS: usize = 4096;
 
+/// CSRs whose `addr[11:10] == 0b11` are read-only
+#[inline]
+pub fn is_read_only(addr: usize) -> bool {
+  (addr >> 10) & 0b11 == 0b11
+}
+
+/// Lowest privilege level allowed to access the CSR, encoded in `addr[9:8]`
+#[inline]
+pub fn min_privilege(addr: usize) -> u64 {
+  ((addr >> 8) & 0b11) as u64
+}
+
 pub struct Csr {
   csrs: [u64; NUM_CSRS],
+  /// Level of the external interrupt line of S-mode, which `mip.SEIP` is ORed with
+  seip_line: bool,
 }
 
 impl Csr {
   pub fn new() -> Csr {
     Self {
       csrs: [0; NUM_CSRS],
+      seip_line: false,
     }
   }
 
   pub fn load(&self, addr: usize) -> u64 {
     match addr {
       SIE => self.csrs[MIE] & self.csrs[MIDELEG],
-      SIP => self.csrs[MIP] & self.csrs[MIDELEG],
-      SSTATUS => self.csrs[MSTATUS] & MASK_SSTATUS,
+      MIP => self.csrs[MIP] | if self.seip_line { MASK_SEIP } else { 0 },
+      SIP => self.load(MIP) & self.csrs[MIDELEG],
+      SSTATUS => self.mstatus() & MASK_SSTATUS,
+      MSTATUS => self.mstatus(),
+      FFLAGS => self.csrs[FCSR] & MASK_FFLAGS,
+      FRM => (self.csrs[FCSR] & MASK_FRM) >> 5,
+      FCSR => self.csrs[FCSR] & (MASK_FRM | MASK_FFLAGS),
       _ => self.csrs[addr],
     }
   }
 
+  /// Like `load`, but `mip.SEIP` is only the bit written by software, which is what
+  /// `CSRRS` & `CSRRC` modify
+  pub fn load_software(&self, addr: usize) -> u64 {
+    match addr {
+      MIP => self.csrs[MIP],
+      SIP => self.csrs[MIP] & self.csrs[MIDELEG],
+      _ => self.load(addr),
+    }
+  }
+
+  pub fn set_seip_line(&mut self, raised: bool) {
+    self.seip_line = raised;
+  }
+
+  /// Set or clear the `mip` bits of `mask` as interrupt lines do, including
+  /// those read-only to software
+  pub fn set_pending(&mut self, mask: u64, pending: bool) {
+    if pending {
+      self.csrs[MIP] |= mask;
+    } else {
+      self.csrs[MIP] &= !mask;
+    }
+  }
+
+  /// `mstatus.SD` is read-only, which summarizes whether `FS`, `XS` or `VS` is dirty
+  fn mstatus(&self) -> u64 {
+    let mstatus = self.csrs[MSTATUS];
+    let dirty =
+      mstatus & MASK_FS == MASK_FS || mstatus & MASK_XS == MASK_XS || mstatus & MASK_VS == MASK_VS;
+    if dirty {
+      mstatus | MASK_SD
+    } else {
+      mstatus & !MASK_SD
+    }
+  }
+
   pub fn store(&mut self, addr: usize, value: u64) {
     match addr {
       SIE => self.csrs[MIE] = (self.csrs[MIE] & !self.csrs[MIDELEG]) | (value & self.csrs[MIDELEG]),
-      SIP => self.csrs[MIP] = (self.csrs[MIE] & !self.csrs[MIDELEG]) | (value & self.csrs[MIDELEG]),
+      // Bits driven by CLINT & PLIC are kept as they are
+      MIP => self.csrs[MIP] = (self.csrs[MIP] & MASK_MIP_READ_ONLY) | (value & !MASK_MIP_READ_ONLY),
+      SIP => {
+        let writable = self.csrs[MIDELEG] & !MASK_MIP_READ_ONLY;
+        self.csrs[MIP] = (self.csrs[MIP] & !writable) | (value & writable);
+      }
       SSTATUS => self.csrs[MSTATUS] = (self.csrs[MSTATUS] & !MASK_SSTATUS) | (value & MASK_SSTATUS),
+      MSTATUS => {
+        // `MPP` is WARL, and `0b10` is a reserved privilege level
+        let mpp = if value & MASK_MPP == 0b10 << 11 {
+          self.csrs[MSTATUS] & MASK_MPP
+        } else {
+          value & MASK_MPP
+        };
+        self.csrs[MSTATUS] = (value & !MASK_MPP) | mpp;
+      }
+      FFLAGS => self.csrs[FCSR] = (self.csrs[FCSR] & !MASK_FFLAGS) | (value & MASK_FFLAGS),
+      FRM => self.csrs[FCSR] = (self.csrs[FCSR] & !MASK_FRM) | ((value << 5) & MASK_FRM),
+      FCSR => self.csrs[FCSR] = value & (MASK_FRM | MASK_FFLAGS),
+      // `satp` is WARL, a write with an unsupported `MODE` has no effect
+      SATP => {
+        if mmu::is_supported_mode(value) {
+          self.csrs[SATP] = value;
+        }
+      }
       _ => self.csrs[addr] = value,
     }
   }
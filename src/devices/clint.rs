@@ -0,0 +1,143 @@
+//! # CLINT
+//!
+//! Core-Local Interruptor, which provides timer & software interrupts of each hart
+
+use crate::devices::{Device, IrqLine};
+use crate::dram::SizeType;
+use crate::exception::*;
+
+/* Register offsets */
+/// `msip` of hart 0, which is 32-bit wide, and followed by those of other harts
+const MSIP: u64 = 0x0000;
+/// `mtimecmp` of hart 0, which is 64-bit wide, and followed by those of other harts
+const MTIMECMP: u64 = 0x4000;
+const MTIME: u64 = 0xBFF8;
+
+/// Read the `size` bytes at `offset` of a register
+fn read_part(reg: u64, offset: u64, size: SizeType) -> u64 {
+  let bits = 8 * size.how_many_bytes() as u64;
+  let value = reg >> (8 * offset);
+  if bits == 64 {
+    value
+  } else {
+    value & ((1 << bits) - 1)
+  }
+}
+
+/// Write `value` to the `size` bytes at `offset` of a register
+fn write_part(reg: u64, offset: u64, size: SizeType, value: u64) -> u64 {
+  let mask = read_part(u64::MAX, 0, size) << (8 * offset);
+  (reg & !mask) | ((value << (8 * offset)) & mask)
+}
+
+/// Per-hart state
+struct Hart {
+  msip: bool,
+  mtimecmp: u64,
+  /// Drives `mip.MSIP`
+  software_irq: IrqLine,
+  /// Drives `mip.MTIP`
+  timer_irq: IrqLine,
+}
+
+pub struct Clint {
+  mtime: u64,
+  /// Hart clock cycles per `mtime` increment
+  cycles_per_tick: u64,
+  cycles: u64,
+  harts: Vec<Hart>,
+}
+
+impl Clint {
+  /// `mtime` increments once every `cycles_per_tick` (at least 1) clock cycles of the hart
+  pub fn new(n_harts: usize, cycles_per_tick: u64) -> Clint {
+    let harts = (0..n_harts)
+      .map(|_| Hart {
+        msip: false,
+        // No timer interrupt until `mtimecmp` is programmed
+        mtimecmp: u64::MAX,
+        software_irq: IrqLine::new(),
+        timer_irq: IrqLine::new(),
+      })
+      .collect();
+    Self {
+      mtime: 0,
+      cycles_per_tick: cycles_per_tick.max(1),
+      cycles: 0,
+      harts,
+    }
+  }
+
+  /// Line of the machine software interrupt of `hart`, which should be connected to `mip.MSIP`
+  pub fn software_irq(&self, hart: usize) -> IrqLine {
+    self.harts[hart].software_irq.clone()
+  }
+
+  /// Line of the machine timer interrupt of `hart`, which should be connected to `mip.MTIP`
+  pub fn timer_irq(&self, hart: usize) -> IrqLine {
+    self.harts[hart].timer_irq.clone()
+  }
+
+  pub fn mtime(&self) -> u64 {
+    self.mtime
+  }
+
+  fn update_irq(&self) {
+    for hart in self.harts.iter() {
+      hart.software_irq.set(hart.msip);
+      hart.timer_irq.set(self.mtime >= hart.mtimecmp);
+    }
+  }
+
+  /// Find the register which contains `offset`, as `(hart, offset in the register)`
+  fn locate(&self, base: u64, width: u64, offset: u64) -> Option<(usize, u64)> {
+    let hart = (offset.checked_sub(base)? / width) as usize;
+    (hart < self.harts.len()).then_some((hart, (offset - base) % width))
+  }
+}
+
+impl Device for Clint {
+  fn load(&mut self, offset: u64, size: SizeType) -> Result<u64, Exception> {
+    if (MTIME..MTIME + 8).contains(&offset) {
+      return Ok(read_part(self.mtime, offset - MTIME, size));
+    }
+    if offset >= MTIMECMP {
+      if let Some((hart, part)) = self.locate(MTIMECMP, 8, offset) {
+        return Ok(read_part(self.harts[hart].mtimecmp, part, size));
+      }
+    } else if let Some((hart, part)) = self.locate(MSIP, 4, offset) {
+      return Ok(read_part(self.harts[hart].msip as u64, part, size));
+    }
+    Err(Exception::LoadAccessFault(offset))
+  }
+
+  fn store(&mut self, offset: u64, size: SizeType, value: u64) -> Result<(), Exception> {
+    if (MTIME..MTIME + 8).contains(&offset) {
+      self.mtime = write_part(self.mtime, offset - MTIME, size, value);
+    } else if offset >= MTIMECMP {
+      let (hart, part) = self
+        .locate(MTIMECMP, 8, offset)
+        .ok_or(Exception::StoreAMOAccessFault(offset))?;
+      let hart = &mut self.harts[hart];
+      hart.mtimecmp = write_part(hart.mtimecmp, part, size, value);
+    } else {
+      let (hart, part) = self
+        .locate(MSIP, 4, offset)
+        .ok_or(Exception::StoreAMOAccessFault(offset))?;
+      // Only the lowest bit of `msip` is writable
+      let hart = &mut self.harts[hart];
+      hart.msip = write_part(hart.msip as u64, part, size, value) & 1 != 0;
+    }
+    self.update_irq();
+    Ok(())
+  }
+
+  fn tick(&mut self) {
+    self.cycles += 1;
+    if self.cycles >= self.cycles_per_tick {
+      self.cycles = 0;
+      self.mtime = self.mtime.wrapping_add(1);
+    }
+    self.update_irq();
+  }
+}
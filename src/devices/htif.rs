@@ -0,0 +1,122 @@
+//! # HTIF
+//!
+//! Host-Target Interface of Spike & riscv-tests, where the guest writes a command to
+//! `tohost` and the host responds in `fromhost`.
+//!
+//! Both words lie in the memory of the guest (usually the `.tohost` section of an ELF),
+//! so HTIF isn't mapped on `Bus` but polled by it after a store to `tohost`.
+//!
+//! A command is encoded as `device[63:56] | cmd[55:48] | payload[47:0]`.
+
+use std::io::Write;
+
+use crate::bus::Bus;
+use crate::dram::SizeType;
+use crate::exception::*;
+
+/// Syscall proxy, where an odd payload requests exit with `payload >> 1`, and an even
+/// one is the address of the syscall arguments
+const DEVICE_SYSCALL: u64 = 0;
+/// Blocking character device, whose `cmd` 1 writes the lowest byte of payload
+const DEVICE_BCD: u64 = 1;
+const BCD_PUTCHAR: u64 = 1;
+
+const MASK_PAYLOAD: u64 = (1 << 48) - 1;
+
+/* Syscalls of the proxy, numbered as in Linux */
+const SYS_WRITE: u64 = 64;
+const SYS_EXIT: u64 = 93;
+const ENOSYS: i64 = 38;
+
+pub struct Htif {
+  tohost: u64,
+  fromhost: Option<u64>,
+  /// Where the guest writes to, through `SYS_write` or the character device
+  console: Box<dyn Write>,
+}
+
+impl Htif {
+  /// HTIF at the physical address of `tohost`, where responses are written to
+  /// `fromhost` if it exists
+  pub fn new(tohost: u64, fromhost: Option<u64>, console: Box<dyn Write>) -> Htif {
+    Self {
+      tohost,
+      fromhost,
+      console,
+    }
+  }
+
+  pub fn tohost(&self) -> u64 {
+    self.tohost
+  }
+
+  /// Handle the command in `tohost` (if any), then clear it
+  pub fn poll(&mut self, bus: &mut Bus) -> Result<(), Exception> {
+    let command = bus.load_u(self.tohost, SizeType::DoubleWord)?;
+    if command == 0 {
+      return Ok(());
+    }
+    bus.write_bytes(self.tohost, &0u64.to_le_bytes())?;
+    let (device, cmd, payload) = (
+      command >> 56,
+      (command >> 48) & 0xFF,
+      command & MASK_PAYLOAD,
+    );
+    let response = match (device, cmd) {
+      (DEVICE_SYSCALL, 0) if payload & 1 != 0 => {
+        bus.request_exit(payload >> 1);
+        return Ok(());
+      }
+      (DEVICE_SYSCALL, 0) => {
+        self.syscall(bus, payload)?;
+        1
+      }
+      (DEVICE_BCD, BCD_PUTCHAR) => {
+        let byte = payload as u8;
+        // The guest can't do anything about a broken console
+        let _ = self
+          .console
+          .write_all(&[byte])
+          .and_then(|_| self.console.flush());
+        0x100 | byte as u64
+      }
+      // Unknown commands are dropped
+      _ => return Ok(()),
+    };
+    if let Some(fromhost) = self.fromhost {
+      let response = (device << 56) | (cmd << 48) | response;
+      bus.write_bytes(fromhost, &response.to_le_bytes())?;
+    }
+    Ok(())
+  }
+
+  /// Run the syscall described by 8 doublewords at `addr` (the number, followed by
+  /// arguments), whose return value is written back to the first one
+  fn syscall(&mut self, bus: &mut Bus, addr: u64) -> Result<(), Exception> {
+    let mut bytes = [0; 64];
+    bus.read_bytes(addr, &mut bytes)?;
+    let args: Vec<u64> = bytes
+      .chunks_exact(8)
+      .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+      .collect();
+    let ret = match args[0] {
+      SYS_WRITE if matches!(args[1], 1 | 2) => {
+        let (buf, len) = (args[2], args[3]);
+        let mut chunk = [0; 256];
+        for start in (0..len).step_by(chunk.len()) {
+          let n = (len - start).min(chunk.len() as u64) as usize;
+          bus.read_bytes(buf.wrapping_add(start), &mut chunk[..n])?;
+          let _ = self.console.write_all(&chunk[..n]);
+        }
+        let _ = self.console.flush();
+        len
+      }
+      SYS_EXIT => {
+        bus.request_exit(args[1]);
+        0
+      }
+      _ => -ENOSYS as u64,
+    };
+    bus.write_bytes(addr, &ret.to_le_bytes())
+  }
+}
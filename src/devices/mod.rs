@@ -0,0 +1,71 @@
+//! # Devices
+//!
+//! Everything mapped on `Bus` is a [`Device`], including `Dram`.
+
+pub mod clint;
+pub mod htif;
+pub mod plic;
+pub mod uart;
+pub mod virtio_blk;
+
+use std::sync::{
+  atomic::{AtomicBool, Ordering},
+  Arc,
+};
+
+use crate::bus::Bus;
+use crate::dram::SizeType;
+use crate::exception::*;
+
+/// # Device
+///
+/// A memory-mapped device, which is accessed by `offset` from the base of
+/// its address range.
+///
+/// Any error is reported by `Bus` as an access fault of the accessed address.
+pub trait Device {
+  /// Read `size` bytes at `offset`, which are zero-extended
+  fn load(&mut self, offset: u64, size: SizeType) -> Result<u64, Exception>;
+  /// Write the lowest `size` bytes of `value` at `offset`
+  fn store(&mut self, offset: u64, size: SizeType, value: u64) -> Result<(), Exception>;
+  /// Advance the device by a clock cycle of the hart
+  fn tick(&mut self) {}
+  /// Access memory through `bus` (DMA) after `tick`, while the device itself is
+  /// detached from `bus`
+  fn dma(&mut self, _bus: &mut Bus) {}
+  /// Bytes of host memory backing the guest memory of the device
+  fn resident_bytes(&self) -> u64 {
+    0
+  }
+}
+
+/// Placeholder of a device detached from `Bus`, which faults on any access
+pub(crate) struct Detached;
+
+impl Device for Detached {
+  fn load(&mut self, offset: u64, _size: SizeType) -> Result<u64, Exception> {
+    Err(Exception::LoadAccessFault(offset))
+  }
+  fn store(&mut self, offset: u64, _size: SizeType, _value: u64) -> Result<(), Exception> {
+    Err(Exception::StoreAMOAccessFault(offset))
+  }
+}
+
+/// # Interrupt Line
+///
+/// A level-triggered interrupt signal from a device to an interrupt
+/// controller, which is shared between both of them.
+#[derive(Debug, Clone, Default)]
+pub struct IrqLine(Arc<AtomicBool>);
+
+impl IrqLine {
+  pub fn new() -> IrqLine {
+    Self::default()
+  }
+  pub fn set(&self, level: bool) {
+    self.0.store(level, Ordering::Release);
+  }
+  pub fn is_raised(&self) -> bool {
+    self.0.load(Ordering::Acquire)
+  }
+}
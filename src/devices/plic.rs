@@ -0,0 +1,230 @@
+//! # PLIC
+//!
+//! SiFive-compatible Platform-Level Interrupt Controller, whose registers are all 32-bit wide.
+//!
+//! Each hart has 2 contexts, `2 * hart` for M-mode and `2 * hart + 1` for S-mode,
+//! which drive its `mip.MEIP` & `mip.SEIP` respectively.
+
+use crate::devices::{Device, IrqLine};
+use crate::dram::SizeType;
+use crate::exception::*;
+use crate::param::*;
+
+/* Register offsets */
+/// Priority of each source, where source 0 doesn't exist
+const PRIORITY: u64 = 0x00_0000;
+/// Pending bits of all sources
+const PENDING: u64 = 0x00_1000;
+/// Enable bits of all sources, per context
+const ENABLE: u64 = 0x00_2000;
+const ENABLE_STRIDE: u64 = 0x80;
+/// Priority threshold, followed by claim/complete, per context
+const THRESHOLD: u64 = 0x20_0000;
+const CLAIM_COMPLETE: u64 = 0x20_0004;
+const CONTEXT_STRIDE: u64 = 0x1000;
+
+/// Priorities are WARL, and only the lowest 3 bits are implemented
+const MASK_PRIORITY: u32 = 0b111;
+const N_WORDS: usize = (PLIC_NUM_SOURCES as usize).div_ceil(32);
+
+struct Context {
+  enable: [u32; N_WORDS],
+  threshold: u32,
+  /// Drives `mip.MEIP` or `mip.SEIP`
+  irq: IrqLine,
+}
+
+pub struct Plic {
+  priority: [u32; PLIC_NUM_SOURCES as usize],
+  pending: [u32; N_WORDS],
+  /// Sources claimed but not completed yet, which are not pending again until completed
+  in_service: [u32; N_WORDS],
+  /// Interrupt lines of devices, with their source IDs
+  sources: Vec<(u32, IrqLine)>,
+  contexts: Vec<Context>,
+  /// Whether pending, enable, priority or threshold state has changed since the
+  /// lines of contexts are driven
+  dirty: bool,
+}
+
+fn bit(words: &[u32], id: u32) -> bool {
+  words[id as usize / 32] & (1 << (id % 32)) != 0
+}
+
+fn set_bit(words: &mut [u32], id: u32, value: bool) {
+  if value {
+    words[id as usize / 32] |= 1 << (id % 32);
+  } else {
+    words[id as usize / 32] &= !(1 << (id % 32));
+  }
+}
+
+impl Plic {
+  pub fn new(n_harts: usize) -> Plic {
+    let contexts = (0..2 * n_harts)
+      .map(|_| Context {
+        enable: [0; N_WORDS],
+        threshold: 0,
+        irq: IrqLine::new(),
+      })
+      .collect();
+    Self {
+      priority: [0; PLIC_NUM_SOURCES as usize],
+      pending: [0; N_WORDS],
+      in_service: [0; N_WORDS],
+      sources: vec![],
+      contexts,
+      dirty: false,
+    }
+  }
+
+  /// Connect the (level-triggered) interrupt line of a device as `source`, which
+  /// must be within `1..PLIC_NUM_SOURCES`
+  pub fn connect(&mut self, source: u32, line: IrqLine) {
+    assert!(
+      (1..PLIC_NUM_SOURCES).contains(&source),
+      "invalid PLIC source {source}"
+    );
+    self.sources.retain(|(id, _)| *id != source);
+    self.sources.push((source, line));
+  }
+
+  /// Line of the external interrupt of `context`, which should be connected to
+  /// `mip.MEIP` (even context) or `mip.SEIP` (odd context) of the hart
+  pub fn context_irq(&self, context: usize) -> IrqLine {
+    self.contexts[context].irq.clone()
+  }
+
+  /// The pending & enabled source with the highest priority above the threshold
+  /// of `context`, where the lowest ID wins a tie
+  fn best_source(&self, context: usize) -> Option<u32> {
+    let ctx = &self.contexts[context];
+    (1..PLIC_NUM_SOURCES)
+      .filter(|&id| bit(&self.pending, id) && bit(&ctx.enable, id))
+      .filter(|&id| self.priority[id as usize] > ctx.threshold)
+      .min_by_key(|&id| (std::cmp::Reverse(self.priority[id as usize]), id))
+  }
+
+  /// Latch the levels of source lines into pending bits, as gateways of
+  /// level-triggered sources do
+  fn sample_sources(&mut self) {
+    for (id, line) in self.sources.iter() {
+      let raised = line.is_raised();
+      if !bit(&self.in_service, *id) && bit(&self.pending, *id) != raised {
+        set_bit(&mut self.pending, *id, raised);
+        self.dirty = true;
+      }
+    }
+  }
+
+  /// Drive the line of each context, which is only recomputed once its state changes
+  fn update_irq(&mut self) {
+    self.sample_sources();
+    if !std::mem::take(&mut self.dirty) {
+      return;
+    }
+    for context in 0..self.contexts.len() {
+      let raised = self.best_source(context).is_some();
+      self.contexts[context].irq.set(raised);
+    }
+  }
+
+  fn claim(&mut self, context: usize) -> u32 {
+    match self.best_source(context) {
+      Some(id) => {
+        set_bit(&mut self.pending, id, false);
+        set_bit(&mut self.in_service, id, true);
+        self.dirty = true;
+        id
+      }
+      None => 0,
+    }
+  }
+
+  fn complete(&mut self, context: usize, id: u32) {
+    // Completion of a source not enabled for the context is ignored
+    if (1..PLIC_NUM_SOURCES).contains(&id) && bit(&self.contexts[context].enable, id) {
+      set_bit(&mut self.in_service, id, false);
+    }
+  }
+
+  /// Find the context of a per-context register, as `(context, offset in the context)`
+  fn locate(&self, base: u64, stride: u64, offset: u64) -> Option<(usize, u64)> {
+    let context = ((offset - base) / stride) as usize;
+    (context < self.contexts.len()).then_some((context, (offset - base) % stride))
+  }
+
+  fn read(&mut self, offset: u64) -> Option<u32> {
+    let value = if offset < PENDING {
+      *self.priority.get(((offset - PRIORITY) / 4) as usize)?
+    } else if offset < ENABLE {
+      *self.pending.get(((offset - PENDING) / 4) as usize)?
+    } else if offset < THRESHOLD {
+      let (context, offset) = self.locate(ENABLE, ENABLE_STRIDE, offset)?;
+      *self.contexts[context].enable.get(offset as usize / 4)?
+    } else {
+      let (context, offset) = self.locate(THRESHOLD, CONTEXT_STRIDE, offset)?;
+      match offset + THRESHOLD {
+        THRESHOLD => self.contexts[context].threshold,
+        CLAIM_COMPLETE => self.claim(context),
+        _ => return None,
+      }
+    };
+    Some(value)
+  }
+
+  fn write(&mut self, offset: u64, value: u32) -> Option<()> {
+    if offset < PENDING {
+      let priority = self.priority.get_mut(((offset - PRIORITY) / 4) as usize)?;
+      *priority = value & MASK_PRIORITY;
+    } else if offset < ENABLE {
+      // Pending bits are read-only
+      self.pending.get(((offset - PENDING) / 4) as usize)?;
+    } else if offset < THRESHOLD {
+      let (context, offset) = self.locate(ENABLE, ENABLE_STRIDE, offset)?;
+      let index = offset as usize / 4;
+      // Source 0 doesn't exist
+      let value = if index == 0 { value & !1 } else { value };
+      *self.contexts[context].enable.get_mut(index)? = value;
+    } else {
+      let (context, offset) = self.locate(THRESHOLD, CONTEXT_STRIDE, offset)?;
+      match offset + THRESHOLD {
+        THRESHOLD => self.contexts[context].threshold = value & MASK_PRIORITY,
+        CLAIM_COMPLETE => self.complete(context, value),
+        _ => return None,
+      }
+    }
+    Some(())
+  }
+}
+
+impl Device for Plic {
+  fn load(&mut self, offset: u64, size: SizeType) -> Result<u64, Exception> {
+    if !matches!(size, SizeType::Word) || !offset.is_multiple_of(4) {
+      return Err(Exception::LoadAccessFault(offset));
+    }
+    let value = self
+      .read(offset)
+      .ok_or(Exception::LoadAccessFault(offset))?;
+    self.update_irq();
+    Ok(value as u64)
+  }
+
+  fn store(&mut self, offset: u64, size: SizeType, value: u64) -> Result<(), Exception> {
+    if !matches!(size, SizeType::Word) || !offset.is_multiple_of(4) {
+      return Err(Exception::StoreAMOAccessFault(offset));
+    }
+    self
+      .write(offset, value as u32)
+      .ok_or(Exception::StoreAMOAccessFault(offset))?;
+    // Any write may change priorities, enables, thresholds or sources in service
+    self.dirty = true;
+    self.update_irq();
+    Ok(())
+  }
+
+  /// Devices may raise or lower their interrupt lines at any time
+  fn tick(&mut self) {
+    self.update_irq();
+  }
+}
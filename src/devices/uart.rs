@@ -0,0 +1,268 @@
+//! # UART
+//!
+//! NS16550A-compatible UART, whose registers are all 8-bit wide
+
+use std::{
+  collections::VecDeque,
+  fs::File,
+  io::{self, Read, Write},
+  sync::{Arc, Mutex},
+  thread,
+};
+
+use crate::devices::{Device, IrqLine};
+use crate::dram::SizeType;
+use crate::exception::*;
+
+/* Register offsets */
+/// Receiver Buffer (read) & Transmitter Holding (write), or Divisor Latch LSB when `LCR.DLAB` is set
+const RBR_THR: u64 = 0;
+/// Interrupt Enable, or Divisor Latch MSB when `LCR.DLAB` is set
+const IER: u64 = 1;
+/// Interrupt Identification (read) & FIFO Control (write)
+const IIR_FCR: u64 = 2;
+/// Line Control
+const LCR: u64 = 3;
+/// Modem Control
+const MCR: u64 = 4;
+/// Line Status
+const LSR: u64 = 5;
+/// Modem Status
+const MSR: u64 = 6;
+/// Scratch
+const SCR: u64 = 7;
+
+/* `IER` fields */
+/// Enable Received Data Available Interrupt
+const IER_ERBFI: u8 = 1 << 0;
+/// Enable Transmitter Holding Register Empty Interrupt
+const IER_ETBEI: u8 = 1 << 1;
+
+/* `IIR` values */
+const IIR_NO_INTERRUPT: u8 = 0x01;
+const IIR_THR_EMPTY: u8 = 0x02;
+const IIR_RX_DATA_AVAILABLE: u8 = 0x04;
+/// Set in `IIR` when FIFOs are enabled
+const IIR_FIFO_ENABLED: u8 = 0xC0;
+
+/* `FCR` fields */
+const FCR_FIFO_ENABLE: u8 = 1 << 0;
+const FCR_RX_FIFO_RESET: u8 = 1 << 1;
+
+/* `LCR` fields */
+/// Divisor Latch Access Bit
+const LCR_DLAB: u8 = 1 << 7;
+
+/* `LSR` fields */
+/// Data Ready
+const LSR_DR: u8 = 1 << 0;
+/// Transmitter Holding Register Empty
+const LSR_THRE: u8 = 1 << 5;
+/// Transmitter Empty
+const LSR_TEMT: u8 = 1 << 6;
+
+/// In-memory input & output of UART, which can be cloned to inspect or feed the guest
+#[derive(Debug, Clone, Default)]
+pub struct UartBuffer {
+  output: Arc<Mutex<Vec<u8>>>,
+  input: Arc<Mutex<VecDeque<u8>>>,
+}
+
+impl UartBuffer {
+  pub fn new() -> UartBuffer {
+    Self::default()
+  }
+  /// Everything transmitted by the guest so far
+  pub fn output(&self) -> Vec<u8> {
+    self.output.lock().unwrap().clone()
+  }
+  /// Same as `output`, but lossily decoded as UTF-8
+  pub fn output_string(&self) -> String {
+    String::from_utf8_lossy(&self.output()).into_owned()
+  }
+  /// Queue bytes to be received by the guest
+  pub fn push_input(&self, bytes: &[u8]) {
+    self.input.lock().unwrap().extend(bytes);
+  }
+}
+
+/// Where UART transmits to and receives from
+pub enum UartBackend {
+  /// Host stdout, with host stdin read by a background thread
+  Terminal,
+  /// A host file for output, and nothing is ever received
+  File(File),
+  /// In-memory buffers, mostly for tests
+  Buffer(UartBuffer),
+}
+
+pub struct Uart {
+  backend: UartBackend,
+  /// Receive FIFO, which may be fed by another thread
+  rx: Arc<Mutex<VecDeque<u8>>>,
+  ier: u8,
+  fcr: u8,
+  lcr: u8,
+  mcr: u8,
+  scr: u8,
+  divisor: u16,
+  /// `THR` empty interrupt, which is cleared by reading `IIR` or writing `THR`
+  thre_interrupt: bool,
+  irq: Option<IrqLine>,
+}
+
+impl Uart {
+  pub fn new(backend: UartBackend) -> Uart {
+    let rx = match &backend {
+      UartBackend::Buffer(buffer) => buffer.input.clone(),
+      _ => Arc::new(Mutex::new(VecDeque::new())),
+    };
+    if let UartBackend::Terminal = backend {
+      let rx = rx.clone();
+      thread::spawn(move || {
+        let mut byte = [0];
+        while let Ok(1) = io::stdin().read(&mut byte) {
+          rx.lock().unwrap().push_back(byte[0]);
+        }
+      });
+    }
+    Self {
+      backend,
+      rx,
+      ier: 0,
+      fcr: 0,
+      lcr: 0,
+      mcr: 0,
+      scr: 0,
+      divisor: 0,
+      thre_interrupt: false,
+      irq: None,
+    }
+  }
+
+  /// Attach to an interrupt controller, which is signaled through `irq`
+  pub fn with_irq(mut self, irq: IrqLine) -> Uart {
+    self.irq = Some(irq);
+    self
+  }
+
+  fn data_ready(&self) -> bool {
+    !self.rx.lock().unwrap().is_empty()
+  }
+
+  /// Highest priority pending interrupt, as identified by `IIR`
+  fn interrupt_id(&self) -> u8 {
+    if self.ier & IER_ERBFI != 0 && self.data_ready() {
+      IIR_RX_DATA_AVAILABLE
+    } else if self.ier & IER_ETBEI != 0 && self.thre_interrupt {
+      IIR_THR_EMPTY
+    } else {
+      IIR_NO_INTERRUPT
+    }
+  }
+
+  fn update_irq(&self) {
+    if let Some(irq) = &self.irq {
+      irq.set(self.interrupt_id() != IIR_NO_INTERRUPT);
+    }
+  }
+
+  fn transmit(&mut self, byte: u8) {
+    // The host is way faster than the line, so a failed write is dropped just
+    // like a byte lost on the wire
+    let _ = match &mut self.backend {
+      UartBackend::Terminal => {
+        let mut stdout = io::stdout();
+        stdout.write_all(&[byte]).and_then(|_| stdout.flush())
+      }
+      UartBackend::File(file) => file.write_all(&[byte]),
+      UartBackend::Buffer(buffer) => {
+        buffer.output.lock().unwrap().push(byte);
+        Ok(())
+      }
+    };
+    // Transmission completes at once, so `THR` is empty again
+    self.thre_interrupt = true;
+  }
+
+  fn read(&mut self, offset: u64) -> Result<u8, Exception> {
+    let dlab = self.lcr & LCR_DLAB != 0;
+    let value = match offset {
+      RBR_THR if dlab => self.divisor as u8,
+      RBR_THR => self.rx.lock().unwrap().pop_front().unwrap_or(0),
+      IER if dlab => (self.divisor >> 8) as u8,
+      IER => self.ier,
+      IIR_FCR => {
+        let id = self.interrupt_id();
+        // Reading `IIR` acknowledges the `THR` empty interrupt
+        if id == IIR_THR_EMPTY {
+          self.thre_interrupt = false;
+        }
+        let fifo = if self.fcr & FCR_FIFO_ENABLE != 0 {
+          IIR_FIFO_ENABLED
+        } else {
+          0
+        };
+        id | fifo
+      }
+      LCR => self.lcr,
+      MCR => self.mcr,
+      LSR => {
+        let dr = if self.data_ready() { LSR_DR } else { 0 };
+        dr | LSR_THRE | LSR_TEMT
+      }
+      MSR => 0,
+      SCR => self.scr,
+      _ => return Err(Exception::LoadAccessFault(offset)),
+    };
+    Ok(value)
+  }
+
+  fn write(&mut self, offset: u64, value: u8) -> Result<(), Exception> {
+    let dlab = self.lcr & LCR_DLAB != 0;
+    match offset {
+      RBR_THR if dlab => self.divisor = (self.divisor & 0xFF00) | value as u16,
+      RBR_THR => self.transmit(value),
+      IER if dlab => self.divisor = (self.divisor & 0x00FF) | ((value as u16) << 8),
+      IER => {
+        // Enabling the interrupt while `THR` is empty raises it at once
+        if self.ier & IER_ETBEI == 0 && value & IER_ETBEI != 0 {
+          self.thre_interrupt = true;
+        }
+        self.ier = value & 0x0F;
+      }
+      IIR_FCR => {
+        if value & FCR_RX_FIFO_RESET != 0 {
+          self.rx.lock().unwrap().clear();
+        }
+        self.fcr = value & !FCR_RX_FIFO_RESET;
+      }
+      LCR => self.lcr = value,
+      MCR => self.mcr = value,
+      // `LSR` & `MSR` are read-only
+      LSR | MSR => {}
+      SCR => self.scr = value,
+      _ => return Err(Exception::StoreAMOAccessFault(offset)),
+    }
+    Ok(())
+  }
+}
+
+impl Device for Uart {
+  fn load(&mut self, offset: u64, _size: SizeType) -> Result<u64, Exception> {
+    let value = self.read(offset)?;
+    self.update_irq();
+    Ok(value as u64)
+  }
+
+  fn store(&mut self, offset: u64, _size: SizeType, value: u64) -> Result<(), Exception> {
+    self.write(offset, value as u8)?;
+    self.update_irq();
+    Ok(())
+  }
+
+  /// Input may arrive at any time from the host
+  fn tick(&mut self) {
+    self.update_irq();
+  }
+}
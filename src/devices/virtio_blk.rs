@@ -0,0 +1,558 @@
+//! # Virtio Block Device
+//!
+//! Virtio-MMIO (version 2) block device with a single split virtqueue, which
+//! is backed by a raw disk image on the host.
+
+use std::{
+  collections::HashMap,
+  fs::{File, OpenOptions},
+  io::{self, Read, Seek, SeekFrom, Write},
+  path::Path,
+};
+
+use crate::bus::Bus;
+use crate::devices::{Device, IrqLine};
+use crate::dram::SizeType;
+use crate::exception::*;
+
+/* MMIO register offsets */
+const MAGIC_VALUE: u64 = 0x000;
+const VERSION: u64 = 0x004;
+const DEVICE_ID: u64 = 0x008;
+const VENDOR_ID: u64 = 0x00C;
+const DEVICE_FEATURES: u64 = 0x010;
+const DEVICE_FEATURES_SEL: u64 = 0x014;
+const DRIVER_FEATURES: u64 = 0x020;
+const DRIVER_FEATURES_SEL: u64 = 0x024;
+const QUEUE_SEL: u64 = 0x030;
+const QUEUE_NUM_MAX: u64 = 0x034;
+const QUEUE_NUM: u64 = 0x038;
+const QUEUE_READY: u64 = 0x044;
+const QUEUE_NOTIFY: u64 = 0x050;
+const INTERRUPT_STATUS: u64 = 0x060;
+const INTERRUPT_ACK: u64 = 0x064;
+const STATUS: u64 = 0x070;
+const QUEUE_DESC_LOW: u64 = 0x080;
+const QUEUE_DESC_HIGH: u64 = 0x084;
+const QUEUE_DRIVER_LOW: u64 = 0x090;
+const QUEUE_DRIVER_HIGH: u64 = 0x094;
+const QUEUE_DEVICE_LOW: u64 = 0x0A0;
+const QUEUE_DEVICE_HIGH: u64 = 0x0A4;
+const CONFIG_GENERATION: u64 = 0x0FC;
+/// Device-specific configuration, which starts with the capacity (in sectors)
+const CONFIG: u64 = 0x100;
+const CONFIG_SIZE: u64 = 8;
+
+/// "virt" in little-endian
+const MAGIC: u32 = 0x7472_6976;
+const BLOCK_DEVICE_ID: u32 = 2;
+/// "QEMU" in little-endian, which is what most drivers expect
+const QEMU_VENDOR_ID: u32 = 0x554D_4551;
+
+/* Feature bits */
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/* Device status bits */
+const STATUS_FEATURES_OK: u32 = 8;
+const STATUS_DEVICE_NEEDS_RESET: u32 = 64;
+
+/// Used buffer notification in `InterruptStatus`
+const INTERRUPT_USED_BUFFER: u32 = 1;
+
+/// Maximum number of descriptors of the virtqueue
+const QUEUE_SIZE_MAX: u32 = 128;
+
+/* Virtqueue */
+const DESC_SIZE: u64 = 16;
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+/* Block requests */
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+const REQUEST_HEADER_SIZE: usize = 16;
+/// Maximum data length of a request, which bounds what a guest can make the
+/// device allocate
+const MAX_REQUEST_SIZE: usize = 4 * 1024 * 1024;
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+/// Length of the ID string returned by `GET_ID`
+const ID_SIZE: usize = 20;
+const DISK_ID: &[u8] = b"rvemu-virtio-blk";
+
+pub const SECTOR_SIZE: u64 = 512;
+
+/// How the disk image is accessed
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DiskMode {
+  ReadWrite,
+  /// Any write fails, which is also reported to the guest as a feature
+  ReadOnly,
+  /// Writes are kept in memory, and the image file is never modified
+  CopyOnWrite,
+}
+
+/// # Disk Image
+///
+/// Raw disk image on the host, whose size is truncated to whole sectors
+pub struct DiskImage {
+  file: File,
+  mode: DiskMode,
+  n_sectors: u64,
+  /// Sectors written in `CopyOnWrite` mode, which shadow the image file
+  overlay: HashMap<u64, Vec<u8>>,
+}
+
+impl DiskImage {
+  pub fn open(path: impl AsRef<Path>, mode: DiskMode) -> io::Result<DiskImage> {
+    let file = OpenOptions::new()
+      .read(true)
+      .write(mode == DiskMode::ReadWrite)
+      .open(path)?;
+    let n_sectors = file.metadata()?.len() / SECTOR_SIZE;
+    Ok(Self {
+      file,
+      mode,
+      n_sectors,
+      overlay: HashMap::new(),
+    })
+  }
+
+  pub fn mode(&self) -> DiskMode {
+    self.mode
+  }
+
+  /// Size of the disk in sectors
+  pub fn n_sectors(&self) -> u64 {
+    self.n_sectors
+  }
+
+  /// Check that `len` bytes from `sector` are within the disk
+  fn check_range(&self, sector: u64, len: usize) -> io::Result<()> {
+    let end = sector
+      .checked_mul(SECTOR_SIZE)
+      .and_then(|start| start.checked_add(len as u64));
+    match end {
+      Some(end) if end <= self.n_sectors * SECTOR_SIZE => Ok(()),
+      _ => Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "access beyond the end of disk",
+      )),
+    }
+  }
+
+  pub fn read(&mut self, sector: u64, buf: &mut [u8]) -> io::Result<()> {
+    self.check_range(sector, buf.len())?;
+    for (i, chunk) in buf.chunks_mut(SECTOR_SIZE as usize).enumerate() {
+      let sector = sector + i as u64;
+      match self.overlay.get(&sector) {
+        Some(data) => chunk.copy_from_slice(&data[..chunk.len()]),
+        None => {
+          self.file.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+          self.file.read_exact(chunk)?;
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Write whole sectors from `sector`
+  pub fn write(&mut self, sector: u64, data: &[u8]) -> io::Result<()> {
+    self.check_range(sector, data.len())?;
+    if !(data.len() as u64).is_multiple_of(SECTOR_SIZE) {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "partial sector write",
+      ));
+    }
+    match self.mode {
+      DiskMode::ReadOnly => Err(io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        "read-only disk",
+      )),
+      DiskMode::ReadWrite => {
+        self.file.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+        self.file.write_all(data)
+      }
+      DiskMode::CopyOnWrite => {
+        for (i, chunk) in data.chunks(SECTOR_SIZE as usize).enumerate() {
+          self.overlay.insert(sector + i as u64, chunk.to_vec());
+        }
+        Ok(())
+      }
+    }
+  }
+
+  pub fn flush(&mut self) -> io::Result<()> {
+    match self.mode {
+      DiskMode::ReadWrite => self.file.sync_data(),
+      _ => Ok(()),
+    }
+  }
+}
+
+/// A buffer described by a descriptor
+#[derive(Debug, Copy, Clone)]
+struct Buffer {
+  addr: u64,
+  len: u32,
+  writable: bool,
+}
+
+/// Split virtqueue, whose rings are in guest memory
+#[derive(Debug, Default)]
+struct Virtqueue {
+  num: u32,
+  ready: bool,
+  desc: u64,
+  /// Available ring
+  driver: u64,
+  /// Used ring
+  device: u64,
+  /// Next index of the available ring to process
+  last_avail: u16,
+}
+
+pub struct VirtioBlk {
+  disk: DiskImage,
+  device_features_sel: u32,
+  driver_features: u64,
+  driver_features_sel: u32,
+  queue_sel: u32,
+  queue: Virtqueue,
+  interrupt_status: u32,
+  status: u32,
+  /// Whether the driver has notified the queue since it's processed
+  notified: bool,
+  irq: Option<IrqLine>,
+}
+
+/// Set the low or high half of `reg`
+fn set_half(reg: &mut u64, high: bool, value: u32) {
+  if high {
+    *reg = (*reg & 0xFFFF_FFFF) | ((value as u64) << 32);
+  } else {
+    *reg = (*reg & !0xFFFF_FFFF) | value as u64;
+  }
+}
+
+impl VirtioBlk {
+  pub fn new(disk: DiskImage) -> VirtioBlk {
+    Self {
+      disk,
+      device_features_sel: 0,
+      driver_features: 0,
+      driver_features_sel: 0,
+      queue_sel: 0,
+      queue: Virtqueue::default(),
+      interrupt_status: 0,
+      status: 0,
+      notified: false,
+      irq: None,
+    }
+  }
+
+  /// Attach to an interrupt controller, which is signaled through `irq`
+  pub fn with_irq(mut self, irq: IrqLine) -> VirtioBlk {
+    self.irq = Some(irq);
+    self
+  }
+
+  fn device_features(&self) -> u64 {
+    let ro = if self.disk.mode() == DiskMode::ReadOnly {
+      VIRTIO_BLK_F_RO
+    } else {
+      0
+    };
+    VIRTIO_F_VERSION_1 | VIRTIO_BLK_F_FLUSH | ro
+  }
+
+  fn reset(&mut self) {
+    self.driver_features = 0;
+    self.queue = Virtqueue::default();
+    self.interrupt_status = 0;
+    self.status = 0;
+    self.notified = false;
+  }
+
+  fn update_irq(&self) {
+    if let Some(irq) = &self.irq {
+      irq.set(self.interrupt_status != 0);
+    }
+  }
+
+  fn read_reg(&self, offset: u64) -> Option<u32> {
+    let queue_selected = self.queue_sel == 0;
+    let value = match offset {
+      MAGIC_VALUE => MAGIC,
+      VERSION => 2,
+      DEVICE_ID => BLOCK_DEVICE_ID,
+      VENDOR_ID => QEMU_VENDOR_ID,
+      DEVICE_FEATURES => match self.device_features_sel {
+        0 => self.device_features() as u32,
+        1 => (self.device_features() >> 32) as u32,
+        _ => 0,
+      },
+      // Only the queue 0 exists
+      QUEUE_NUM_MAX if queue_selected => QUEUE_SIZE_MAX,
+      QUEUE_NUM_MAX => 0,
+      QUEUE_READY => (queue_selected && self.queue.ready) as u32,
+      INTERRUPT_STATUS => self.interrupt_status,
+      STATUS => self.status,
+      CONFIG_GENERATION => 0,
+      _ => return None,
+    };
+    Some(value)
+  }
+
+  fn write_reg(&mut self, offset: u64, value: u32) -> Option<()> {
+    let queue = &mut self.queue;
+    let queue_selected = self.queue_sel == 0;
+    match offset {
+      DEVICE_FEATURES_SEL => self.device_features_sel = value,
+      DRIVER_FEATURES => {
+        let mut features = self.driver_features;
+        set_half(&mut features, self.driver_features_sel == 1, value);
+        if self.driver_features_sel <= 1 {
+          self.driver_features = features;
+        }
+      }
+      DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+      QUEUE_SEL => self.queue_sel = value,
+      QUEUE_NUM if queue_selected => queue.num = value.min(QUEUE_SIZE_MAX),
+      QUEUE_READY if queue_selected => queue.ready = value & 1 != 0,
+      QUEUE_DESC_LOW | QUEUE_DESC_HIGH if queue_selected => {
+        set_half(&mut queue.desc, offset == QUEUE_DESC_HIGH, value)
+      }
+      QUEUE_DRIVER_LOW | QUEUE_DRIVER_HIGH if queue_selected => {
+        set_half(&mut queue.driver, offset == QUEUE_DRIVER_HIGH, value)
+      }
+      QUEUE_DEVICE_LOW | QUEUE_DEVICE_HIGH if queue_selected => {
+        set_half(&mut queue.device, offset == QUEUE_DEVICE_HIGH, value)
+      }
+      // Writes to the registers of nonexistent queues are ignored
+      QUEUE_NUM | QUEUE_READY | QUEUE_DESC_LOW | QUEUE_DESC_HIGH | QUEUE_DRIVER_LOW
+      | QUEUE_DRIVER_HIGH | QUEUE_DEVICE_LOW | QUEUE_DEVICE_HIGH => {}
+      QUEUE_NOTIFY => self.notified |= value == 0,
+      INTERRUPT_ACK => self.interrupt_status &= !value,
+      STATUS if value == 0 => self.reset(),
+      STATUS => {
+        let mut value = value;
+        // Features are accepted only if they're offered, including `VERSION_1`
+        let accepted = self.driver_features & !self.device_features() == 0
+          && self.driver_features & VIRTIO_F_VERSION_1 != 0;
+        if value & STATUS_FEATURES_OK != 0 && !accepted {
+          value &= !STATUS_FEATURES_OK;
+        }
+        self.status = value;
+      }
+      _ => return None,
+    }
+    Some(())
+  }
+
+  /// Collect the buffers of the descriptor chain starting at `head`
+  fn descriptor_chain(&self, bus: &mut Bus, head: u16) -> Result<Vec<Buffer>, Exception> {
+    let mut buffers = vec![];
+    let mut index = head as u64;
+    // A looping chain is cut at the queue size
+    for _ in 0..self.queue.num {
+      let desc = self.queue.desc + DESC_SIZE * (index % self.queue.num as u64);
+      let addr = bus.load_u(desc, SizeType::DoubleWord)?;
+      let len = bus.load_u(desc + 8, SizeType::Word)? as u32;
+      let flags = bus.load_u(desc + 12, SizeType::Half)? as u16;
+      let next = bus.load_u(desc + 14, SizeType::Half)?;
+      // A buffer wrapping around the address space is never valid
+      if addr.checked_add(len as u64).is_none() {
+        return Err(Exception::LoadAccessFault(addr));
+      }
+      buffers.push(Buffer {
+        addr,
+        len,
+        writable: flags & VIRTQ_DESC_F_WRITE != 0,
+      });
+      if flags & VIRTQ_DESC_F_NEXT == 0 {
+        break;
+      }
+      index = next;
+    }
+    Ok(buffers)
+  }
+
+  /// Handle a block request, and return the number of bytes written into the
+  /// device-writable buffers
+  fn handle_request(&mut self, bus: &mut Bus, head: u16) -> Result<u32, Exception> {
+    // Device-readable buffers (header & data to write) precede device-writable
+    // ones (data to read & status)
+    let (writable, readable): (Vec<Buffer>, Vec<Buffer>) = self
+      .descriptor_chain(bus, head)?
+      .into_iter()
+      .partition(|buffer| buffer.writable);
+    // The status goes into the very last byte, so a chain without room for it
+    // is rejected as nothing can be reported
+    let status_addr = match writable.last() {
+      Some(last) if last.len > 0 => last.addr + last.len as u64 - 1,
+      _ => return Ok(0),
+    };
+    // Lengths are checked before anything is allocated for them
+    let readable_len: u64 = readable.iter().map(|buffer| buffer.len as u64).sum();
+    let data_len = writable.iter().map(|buffer| buffer.len as u64).sum::<u64>() - 1;
+    let (status, data) = if readable_len > (REQUEST_HEADER_SIZE + MAX_REQUEST_SIZE) as u64
+      || data_len > MAX_REQUEST_SIZE as u64
+    {
+      (VIRTIO_BLK_S_IOERR, vec![])
+    } else {
+      self.serve_request(bus, &readable, data_len as usize)?
+    };
+
+    // Scatter the data, then put the status at the very end
+    let mut rest = data.as_slice();
+    for buffer in writable.iter() {
+      let n = rest.len().min(buffer.len as usize);
+      bus.write_bytes(buffer.addr, &rest[..n])?;
+      rest = &rest[n..];
+    }
+    bus.store(status_addr, SizeType::Byte, status as u64)?;
+    Ok(data.len() as u32 + 1)
+  }
+
+  /// Serve a block request from the device-readable buffers, and return the
+  /// status & the data for up to `data_len` bytes of device-writable buffers
+  fn serve_request(
+    &mut self,
+    bus: &mut Bus,
+    readable: &[Buffer],
+    data_len: usize,
+  ) -> Result<(u8, Vec<u8>), Exception> {
+    let mut request = vec![];
+    for buffer in readable {
+      let start = request.len();
+      request.resize(start + buffer.len as usize, 0);
+      bus.read_bytes(buffer.addr, &mut request[start..])?;
+    }
+    if request.len() < REQUEST_HEADER_SIZE {
+      return Ok((VIRTIO_BLK_S_IOERR, vec![]));
+    }
+
+    let mut data = vec![];
+    let request_type = u32::from_le_bytes(request[0..4].try_into().unwrap());
+    let sector = u64::from_le_bytes(request[8..16].try_into().unwrap());
+    let result = match request_type {
+      VIRTIO_BLK_T_IN => {
+        data = vec![0; data_len];
+        self.disk.read(sector, &mut data).map(|_| VIRTIO_BLK_S_OK)
+      }
+      VIRTIO_BLK_T_OUT => self
+        .disk
+        .write(sector, &request[REQUEST_HEADER_SIZE..])
+        .map(|_| VIRTIO_BLK_S_OK),
+      VIRTIO_BLK_T_FLUSH => self.disk.flush().map(|_| VIRTIO_BLK_S_OK),
+      VIRTIO_BLK_T_GET_ID => {
+        let mut id = DISK_ID.to_vec();
+        id.resize(ID_SIZE.min(data_len), 0);
+        data = id;
+        Ok(VIRTIO_BLK_S_OK)
+      }
+      _ => Ok(VIRTIO_BLK_S_UNSUPP),
+    };
+    Ok(match result {
+      Ok(status) => (status, data),
+      Err(_) => (VIRTIO_BLK_S_IOERR, vec![]),
+    })
+  }
+
+  /// Process all requests in the available ring
+  fn process_queue(&mut self, bus: &mut Bus) -> Result<(), Exception> {
+    let queue = &self.queue;
+    if !queue.ready || queue.num == 0 {
+      return Ok(());
+    }
+    let (num, avail, used) = (queue.num as u64, queue.driver, queue.device);
+    // Rings wrapping around the address space are never valid, which keeps
+    // offsets into them from overflowing
+    for (ring, size) in [
+      (queue.desc, DESC_SIZE * num),
+      (avail, 4 + 2 * num),
+      (used, 4 + 8 * num),
+    ] {
+      if ring.checked_add(size).is_none() {
+        return Err(Exception::LoadAccessFault(ring));
+      }
+    }
+    let avail_flags = bus.load_u(avail, SizeType::Half)? as u16;
+    let avail_idx = bus.load_u(avail + 2, SizeType::Half)? as u16;
+    let mut processed = false;
+    while self.queue.last_avail != avail_idx {
+      let slot = self.queue.last_avail as u64 % num;
+      let head = bus.load_u(avail + 4 + 2 * slot, SizeType::Half)? as u16;
+      let len = self.handle_request(bus, head)?;
+
+      let used_idx = bus.load_u(used + 2, SizeType::Half)? as u16;
+      let elem = used + 4 + 8 * (used_idx as u64 % num);
+      bus.store(elem, SizeType::Word, head as u64)?;
+      bus.store(elem + 4, SizeType::Word, len as u64)?;
+      bus.store(used + 2, SizeType::Half, used_idx.wrapping_add(1) as u64)?;
+      self.queue.last_avail = self.queue.last_avail.wrapping_add(1);
+      processed = true;
+    }
+    if processed && avail_flags & VIRTQ_AVAIL_F_NO_INTERRUPT == 0 {
+      self.interrupt_status |= INTERRUPT_USED_BUFFER;
+    }
+    Ok(())
+  }
+}
+
+impl Device for VirtioBlk {
+  fn load(&mut self, offset: u64, size: SizeType) -> Result<u64, Exception> {
+    // Device configuration can be accessed in any size
+    if (CONFIG..CONFIG + CONFIG_SIZE).contains(&offset) {
+      let n_bytes = size.how_many_bytes() as u64;
+      if offset + n_bytes > CONFIG + CONFIG_SIZE {
+        return Err(Exception::LoadAccessFault(offset));
+      }
+      let bytes = self.disk.n_sectors().to_le_bytes();
+      let start = (offset - CONFIG) as usize;
+      let mut value = 0;
+      for (i, byte) in bytes[start..start + n_bytes as usize].iter().enumerate() {
+        value |= (*byte as u64) << (8 * i);
+      }
+      return Ok(value);
+    }
+    if !matches!(size, SizeType::Word) {
+      return Err(Exception::LoadAccessFault(offset));
+    }
+    self
+      .read_reg(offset)
+      .map(|value| value as u64)
+      .ok_or(Exception::LoadAccessFault(offset))
+  }
+
+  fn store(&mut self, offset: u64, size: SizeType, value: u64) -> Result<(), Exception> {
+    if !matches!(size, SizeType::Word) {
+      return Err(Exception::StoreAMOAccessFault(offset));
+    }
+    self
+      .write_reg(offset, value as u32)
+      .ok_or(Exception::StoreAMOAccessFault(offset))?;
+    self.update_irq();
+    Ok(())
+  }
+
+  fn dma(&mut self, bus: &mut Bus) {
+    if !self.notified {
+      return;
+    }
+    self.notified = false;
+    // A broken virtqueue can only be recovered by resetting the device
+    if self.process_queue(bus).is_err() {
+      self.status |= STATUS_DEVICE_NEEDS_RESET;
+    }
+    self.update_irq();
+  }
+}
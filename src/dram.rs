@@ -1,3 +1,7 @@
+use std::collections::HashMap;
+
+use crate::bus::BusError;
+use crate::devices::Device;
 use crate::exception::*;
 use crate::param::*;
 
@@ -13,12 +17,20 @@ use crate::param::*;
 ///
 /// ## Definition
 ///
-/// - 0 ..DRAM_BASE : `Instructions`
-/// - DRAM_BASE ..= DRAM_END : `Data`
+/// Mapped at `MachineConfig::dram_base` on `Bus` (`DRAM_BASE` by default), where
+/// the code is placed at its beginning
+///
+/// ## Storage
+///
+/// Sparse, where a page of `PAGE_SIZE` bytes is allocated on the first write of
+/// nonzero data to it, and untouched pages read as zero
 pub struct Dram {
-  pub dram: Vec<u8>,
+  size: u64,
+  /// Allocated pages, keyed by page number
+  pages: HashMap<u64, Box<[u8]>>,
 }
 
+#[derive(Debug, Copy, Clone)]
 pub enum SizeType {
   /// 8-bit
   Byte,
@@ -42,44 +54,146 @@ impl SizeType {
   }
 }
 
+impl SizeType {
+  /// Sign-extend the lowest `size` bytes of `value`
+  #[inline]
+  pub fn sign_extend(&self, value: u64) -> u64 {
+    let shift = 64 - 8 * self.how_many_bytes();
+    (((value << shift) as i64) >> shift) as u64
+  }
+}
+
 impl Dram {
-  pub fn new(code: Vec<u8>) -> Dram {
-    let mut dram: Vec<u8> = vec![0; DRAM_BASE as usize + DRAM_SIZE as usize];
-    dram.splice(..code.len(), code.iter().cloned());
-    Self { dram }
+  pub fn new(code: Vec<u8>) -> Result<Dram, BusError> {
+    Self::with_size(DRAM_SIZE, code)
   }
 
-  pub fn fetch_inst(&self, addr: u64) -> u8 {
-    let index = (addr - DRAM_BASE) as usize;
-    self.dram[index]
+  /// Create `size` bytes of memory, which begins with `code`, or fail with
+  /// `BusError::TooLarge` if `code` doesn't fit in it
+  pub fn with_size(size: u64, code: Vec<u8>) -> Result<Dram, BusError> {
+    if code.len() as u64 > size {
+      return Err(BusError::TooLarge {
+        name: "dram".to_owned(),
+        size,
+        len: code.len() as u64,
+      });
+    }
+    let mut dram = Self::zeroed(size);
+    dram.write(0, &code);
+    Ok(dram)
   }
 
-  pub fn load(&self, addr: u64, size: SizeType) -> Result<u64, Exception> {
-    let n_bytes = size.how_many_bytes();
-    let index = addr as usize;
-    let mut value =
-      ((((self.dram[index + n_bytes - 1] as u64) << (8 * 7)) as i64) >> (8 * (8 - n_bytes))) as u64;
-    for i in 0..n_bytes {
-      value |= (self.dram[index + i] as u64) << (8 * i);
+  /// Create `size` bytes of zero-initialized memory
+  pub fn zeroed(size: u64) -> Dram {
+    Self {
+      size,
+      pages: HashMap::new(),
     }
-    Ok(value)
   }
-  pub fn load_u(&self, addr: u64, size: SizeType) -> Result<u64, Exception> {
+
+  pub fn size(&self) -> u64 {
+    self.size
+  }
+
+  /// Whether `offset..offset + len` lies inside the memory
+  fn contains(&self, offset: u64, len: usize) -> bool {
+    offset
+      .checked_add(len as u64)
+      .is_some_and(|end| end <= self.size)
+  }
+
+  /// Split `offset..offset + len` at page boundaries, as `(page number, offset in the page, len)`
+  fn chunks(offset: u64, len: usize) -> impl Iterator<Item = (u64, usize, usize)> {
+    let mut offset = offset;
+    let mut rest = len;
+    std::iter::from_fn(move || {
+      if rest == 0 {
+        return None;
+      }
+      let in_page = (offset % PAGE_SIZE) as usize;
+      let n = rest.min(PAGE_SIZE as usize - in_page);
+      let chunk = (offset / PAGE_SIZE, in_page, n);
+      offset += n as u64;
+      rest -= n;
+      Some(chunk)
+    })
+  }
+
+  fn read(&self, offset: u64, buf: &mut [u8]) {
+    let mut done = 0;
+    for (page, in_page, n) in Self::chunks(offset, buf.len()) {
+      let dst = &mut buf[done..done + n];
+      match self.pages.get(&page) {
+        Some(data) => dst.copy_from_slice(&data[in_page..in_page + n]),
+        None => dst.fill(0),
+      }
+      done += n;
+    }
+  }
+
+  fn write(&mut self, offset: u64, data: &[u8]) {
+    let mut done = 0;
+    for (page, in_page, n) in Self::chunks(offset, data.len()) {
+      let src = &data[done..done + n];
+      done += n;
+      // Zeros written to an untouched page change nothing
+      if !self.pages.contains_key(&page) && src.iter().all(|&byte| byte == 0) {
+        continue;
+      }
+      let dst = self
+        .pages
+        .entry(page)
+        .or_insert_with(|| vec![0; PAGE_SIZE as usize].into_boxed_slice());
+      dst[in_page..in_page + n].copy_from_slice(src);
+    }
+  }
+}
+
+/// Read-only memory, where any store faults
+pub struct Rom(Dram);
+
+impl Rom {
+  pub fn new(data: Vec<u8>) -> Rom {
+    let mut rom = Dram::zeroed(data.len() as u64);
+    rom.write(0, &data);
+    Self(rom)
+  }
+}
+
+impl Device for Rom {
+  fn load(&mut self, offset: u64, size: SizeType) -> Result<u64, Exception> {
+    self.0.load(offset, size)
+  }
+  fn resident_bytes(&self) -> u64 {
+    self.0.resident_bytes()
+  }
+  fn store(&mut self, offset: u64, _size: SizeType, _value: u64) -> Result<(), Exception> {
+    Err(Exception::StoreAMOAccessFault(offset))
+  }
+}
+
+impl Device for Dram {
+  fn load(&mut self, offset: u64, size: SizeType) -> Result<u64, Exception> {
     let n_bytes = size.how_many_bytes();
-    let index = addr as usize;
-    let mut value: u64 = 0;
-    for i in 0..n_bytes {
-      value |= (self.dram[index + i] as u64) << (8 * i);
+    if !self.contains(offset, n_bytes) {
+      return Err(Exception::LoadAccessFault(offset));
     }
-    Ok(value)
+    let mut bytes = [0; 8];
+    self.read(offset, &mut bytes[..n_bytes]);
+    Ok(u64::from_le_bytes(bytes))
   }
 
-  pub fn store(&mut self, addr: u64, size: SizeType, value: u64) -> Result<(), Exception> {
+  fn store(&mut self, offset: u64, size: SizeType, value: u64) -> Result<(), Exception> {
     let n_bytes = size.how_many_bytes();
-    let index = addr as usize;
-    for i in 0..n_bytes {
-      self.dram[index + i] = ((value >> (8 * i)) & 0xff) as u8;
+    if !self.contains(offset, n_bytes) {
+      return Err(Exception::StoreAMOAccessFault(offset));
     }
+    self.write(offset, &value.to_le_bytes()[..n_bytes]);
     Ok(())
   }
+
+  /// Bytes held by allocated pages
+  fn resident_bytes(&self) -> u64 {
+    self.pages.len() as u64 * PAGE_SIZE
+  }
 }
@@ -0,0 +1,261 @@
+//! # ELF
+//!
+//! Loader of little-endian RISC-V ELF64 executables
+
+use std::fmt;
+
+use crate::bus::Bus;
+use crate::exception::*;
+use crate::param::PAGE_SIZE;
+
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_RISCV: u16 = 243;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+const SHDR_SIZE: usize = 64;
+const SYM_SIZE: usize = 24;
+
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+const STT_OBJECT: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+
+/// Error of parsing an ELF file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ElfError {
+  /// Not an ELF file at all
+  BadMagic,
+  /// A valid ELF file, but not a little-endian ELF64 executable
+  Unsupported(&'static str),
+  /// `e_machine` is not `EM_RISCV`
+  WrongMachine(u16),
+  /// A header or segment lies beyond the end of file
+  Truncated,
+}
+
+impl fmt::Display for ElfError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ElfError::BadMagic => write!(f, "BadMagic: not an ELF file"),
+      ElfError::Unsupported(what) => write!(f, "Unsupported: {what}"),
+      ElfError::WrongMachine(machine) => {
+        write!(f, "WrongMachine: e_machine {machine} is not RISC-V")
+      }
+      ElfError::Truncated => write!(f, "Truncated: ELF file ends unexpectedly"),
+    }
+  }
+}
+
+impl std::error::Error for ElfError {}
+
+/// A `PT_LOAD` segment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+  /// Physical address to load at
+  pub paddr: u64,
+  /// Bytes from the file, which are followed by zeros up to `mem_size`
+  pub data: Vec<u8>,
+  pub mem_size: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+  pub name: String,
+  pub addr: u64,
+  pub size: u64,
+}
+
+/// A parsed ELF64 executable
+#[derive(Debug, Clone)]
+pub struct Elf {
+  pub entry: u64,
+  pub segments: Vec<Segment>,
+  /// Named functions, objects & labels, sorted by address
+  pub symbols: Vec<Symbol>,
+}
+
+/// Little-endian reader of an ELF file, which fails on reading beyond the end
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+  fn bytes(&self, offset: u64, len: u64) -> Result<&[u8], ElfError> {
+    let start = usize::try_from(offset).map_err(|_| ElfError::Truncated)?;
+    let end = usize::try_from(len)
+      .ok()
+      .and_then(|len| start.checked_add(len))
+      .ok_or(ElfError::Truncated)?;
+    self.0.get(start..end).ok_or(ElfError::Truncated)
+  }
+  fn u8(&self, offset: u64) -> Result<u8, ElfError> {
+    Ok(self.bytes(offset, 1)?[0])
+  }
+  fn u16(&self, offset: u64) -> Result<u16, ElfError> {
+    Ok(u16::from_le_bytes(
+      self.bytes(offset, 2)?.try_into().unwrap(),
+    ))
+  }
+  fn u32(&self, offset: u64) -> Result<u32, ElfError> {
+    Ok(u32::from_le_bytes(
+      self.bytes(offset, 4)?.try_into().unwrap(),
+    ))
+  }
+  fn u64(&self, offset: u64) -> Result<u64, ElfError> {
+    Ok(u64::from_le_bytes(
+      self.bytes(offset, 8)?.try_into().unwrap(),
+    ))
+  }
+  /// `index`-th entry of a table at `offset`, whose entries of `size` bytes are
+  /// `entsize` bytes apart (but never overlap)
+  fn entry(
+    &self,
+    offset: u64,
+    index: u64,
+    entsize: u64,
+    size: usize,
+  ) -> Result<Reader<'_>, ElfError> {
+    let start = index
+      .checked_mul(entsize.max(size as u64))
+      .and_then(|start| start.checked_add(offset))
+      .ok_or(ElfError::Truncated)?;
+    Ok(Reader(self.bytes(start, size as u64)?))
+  }
+  /// NUL-terminated string at `offset`
+  fn str(&self, offset: u64) -> Result<String, ElfError> {
+    let rest = usize::try_from(offset)
+      .ok()
+      .and_then(|offset| self.0.get(offset..))
+      .ok_or(ElfError::Truncated)?;
+    let len = rest
+      .iter()
+      .position(|&b| b == 0)
+      .ok_or(ElfError::Truncated)?;
+    Ok(String::from_utf8_lossy(&rest[..len]).into_owned())
+  }
+}
+
+impl Elf {
+  /// Whether `bytes` starts with the ELF magic number
+  pub fn is_elf(bytes: &[u8]) -> bool {
+    bytes.starts_with(&ELF_MAGIC)
+  }
+
+  pub fn parse(bytes: &[u8]) -> Result<Elf, ElfError> {
+    if !Self::is_elf(bytes) {
+      return Err(ElfError::BadMagic);
+    }
+    let r = Reader(bytes);
+    r.bytes(0, EHDR_SIZE as u64)?;
+    if r.u8(4)? != ELFCLASS64 {
+      return Err(ElfError::Unsupported("not ELF64"));
+    }
+    if r.u8(5)? != ELFDATA2LSB {
+      return Err(ElfError::Unsupported("not little-endian"));
+    }
+    if r.u16(16)? != ET_EXEC {
+      return Err(ElfError::Unsupported("not an executable"));
+    }
+    let machine = r.u16(18)?;
+    if machine != EM_RISCV {
+      return Err(ElfError::WrongMachine(machine));
+    }
+    let entry = r.u64(24)?;
+    let (phoff, shoff) = (r.u64(32)?, r.u64(40)?);
+    let (phentsize, phnum) = (r.u16(54)? as u64, r.u16(56)? as u64);
+    let (shentsize, shnum) = (r.u16(58)? as u64, r.u16(60)? as u64);
+
+    // Fields are read from each entry, so offsets within it can't overflow
+    let mut segments = vec![];
+    for i in 0..phnum {
+      let ph = r.entry(phoff, i, phentsize, PHDR_SIZE)?;
+      if ph.u32(0)? != PT_LOAD {
+        continue;
+      }
+      let offset = ph.u64(8)?;
+      let paddr = ph.u64(24)?;
+      let file_size = ph.u64(32)?;
+      let mem_size = ph.u64(40)?;
+      if file_size > mem_size {
+        return Err(ElfError::Unsupported(
+          "segment larger in file than in memory",
+        ));
+      }
+      segments.push(Segment {
+        paddr,
+        data: r.bytes(offset, file_size)?.to_vec(),
+        mem_size,
+      });
+    }
+
+    let mut symbols = vec![];
+    for i in 0..shnum {
+      let sh = r.entry(shoff, i, shentsize, SHDR_SIZE)?;
+      if sh.u32(4)? != SHT_SYMTAB {
+        continue;
+      }
+      let (offset, size, link) = (sh.u64(24)?, sh.u64(32)?, sh.u32(40)? as u64);
+      // Offset of the linked string table
+      let strtab = r.entry(shoff, link, shentsize, SHDR_SIZE)?.u64(24)?;
+      for j in 0..size / SYM_SIZE as u64 {
+        let sym = r.entry(offset, j, SYM_SIZE as u64, SYM_SIZE)?;
+        let name = sym.u32(0)? as u64;
+        let kind = sym.u8(4)? & 0xF;
+        let shndx = sym.u16(6)?;
+        // Undefined symbols, sections & files don't name any address
+        if name == 0 || shndx == 0 || !matches!(kind, STT_NOTYPE | STT_OBJECT | STT_FUNC) {
+          continue;
+        }
+        symbols.push(Symbol {
+          name: r.str(strtab.checked_add(name).ok_or(ElfError::Truncated)?)?,
+          addr: sym.u64(8)?,
+          size: sym.u64(16)?,
+        });
+      }
+    }
+    symbols.sort_by_key(|symbol| symbol.addr);
+
+    Ok(Self {
+      entry,
+      segments,
+      symbols,
+    })
+  }
+
+  /// Copy all segments to memory through `bus`, with the rest of each segment zero-filled
+  pub fn load(&self, bus: &mut Bus) -> Result<(), Exception> {
+    for segment in self.segments.iter() {
+      bus.write_bytes(segment.paddr, &segment.data)?;
+      // `p_memsz` may be far larger than memory, so zeros are written in chunks
+      let zeros = [0; PAGE_SIZE as usize];
+      let mut addr = segment.paddr.wrapping_add(segment.data.len() as u64);
+      let mut remaining = segment.mem_size - segment.data.len() as u64;
+      while remaining > 0 {
+        let len = remaining.min(PAGE_SIZE);
+        bus.write_bytes(addr, &zeros[..len as usize])?;
+        addr = addr.wrapping_add(len);
+        remaining -= len;
+      }
+    }
+    Ok(())
+  }
+
+  /// Find a symbol by its name
+  pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+    self.symbols.iter().find(|symbol| symbol.name == name)
+  }
+
+  /// Resolve `addr` to the closest symbol at or below it, as `(symbol, offset)`
+  pub fn symbolize(&self, addr: u64) -> Option<(&Symbol, u64)> {
+    let index = self.symbols.partition_point(|symbol| symbol.addr <= addr);
+    let symbol = &self.symbols[index.checked_sub(1)?];
+    // A sized symbol only covers its own range
+    if symbol.size != 0 && addr - symbol.addr >= symbol.size {
+      return None;
+    }
+    Some((symbol, addr - symbol.addr))
+  }
+}
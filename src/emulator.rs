@@ -3,41 +3,154 @@ use std::{
   io::{self, prelude::*},
 };
 
-use crate::cpu::Cpu;
+use crate::config::MachineConfig;
+use crate::cpu::{Cpu, StopReason};
+use crate::devices::{
+  clint::Clint,
+  htif::Htif,
+  plic::Plic,
+  uart::{Uart, UartBackend},
+  virtio_blk::{DiskImage, VirtioBlk},
+  IrqLine,
+};
+use crate::elf::Elf;
 use crate::param::*;
+use crate::signature;
+
+/// Create a hart on the machine of `config` with the program loaded, where an ELF
+/// executable is loaded by its segments, otherwise `code` is a raw binary placed
+/// at the beginning of DRAM.
+///
+/// HTIF is attached at `config.tohost` or the `tohost` symbol of the ELF, if any,
+/// and the guest writes to `console` through it.
+pub fn load_program(
+  code: Vec<u8>,
+  config: &MachineConfig,
+  console: Box<dyn Write>,
+) -> io::Result<(Cpu, Option<Elf>)> {
+  let elf = Elf::is_elf(&code)
+    .then(|| Elf::parse(&code))
+    .transpose()
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+  let code = if elf.is_some() { vec![] } else { code };
+  let mut cpu = Cpu::with_config(config, code).map_err(io::Error::other)?;
+  if let Some(elf) = &elf {
+    elf
+      .load(&mut cpu.bus)
+      .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    cpu.pc = elf.entry;
+  }
+  // Programs of riscv-tests & Spike report their results through HTIF
+  let symbol = |name| elf.as_ref()?.symbol(name).map(|symbol| symbol.addr);
+  if let Some(tohost) = config.tohost.or_else(|| symbol("tohost")) {
+    let htif = Htif::new(tohost, symbol("fromhost"), console);
+    cpu.bus.attach_htif(htif);
+  }
+  Ok((cpu, elf))
+}
+
+/// Map UART, CLINT, PLIC & (if `disk` is given) virtio-blk at the addresses of `config`
+fn attach_devices(
+  cpu: &mut Cpu,
+  config: &MachineConfig,
+  disk: Option<DiskImage>,
+) -> io::Result<()> {
+  let mut plic = Plic::new(1);
+  let uart_irq = IrqLine::new();
+  plic.connect(UART_IRQ, uart_irq.clone());
+  let uart = Uart::new(UartBackend::Terminal).with_irq(uart_irq);
+  cpu
+    .bus
+    .map("uart", config.uart_base, UART_SIZE, Box::new(uart))
+    .map_err(io::Error::other)?;
+  let clint = Clint::new(1, config.clint_cycles_per_tick);
+  cpu.connect_irq(MASK_MSIP, clint.software_irq(0));
+  cpu.connect_irq(MASK_MTIP, clint.timer_irq(0));
+  cpu
+    .bus
+    .map("clint", config.clint_base, CLINT_SIZE, Box::new(clint))
+    .map_err(io::Error::other)?;
+  if let Some(disk) = disk {
+    let virtio_irq = IrqLine::new();
+    plic.connect(VIRTIO_IRQ, virtio_irq.clone());
+    let virtio = VirtioBlk::new(disk).with_irq(virtio_irq);
+    cpu
+      .bus
+      .map(
+        "virtio-blk",
+        config.virtio_base,
+        VIRTIO_SIZE,
+        Box::new(virtio),
+      )
+      .map_err(io::Error::other)?;
+  }
+  cpu.connect_irq(MASK_MEIP, plic.context_irq(0));
+  cpu.connect_irq(MASK_SEIP, plic.context_irq(1));
+  cpu
+    .bus
+    .map("plic", config.plic_base, PLIC_SIZE, Box::new(plic))
+    .map_err(io::Error::other)?;
+  Ok(())
+}
 
-pub fn run_with(mut file: File) -> io::Result<()> {
+/// Run the program in `file` on the machine of `config`, with `disk` attached as
+/// a virtio block device, until the hart stops
+pub fn run_with(
+  mut file: File,
+  disk: Option<DiskImage>,
+  config: &MachineConfig,
+) -> io::Result<StopReason> {
   eprintln!();
 
   let mut code = vec![];
   file.read_to_end(&mut code)?;
+  let (mut cpu, elf) = load_program(code, config, Box::new(io::stdout()))?;
+  attach_devices(&mut cpu, config, disk)?;
 
-  let mut cpu = Cpu::new(code);
-
-  while cpu.pc < DRAM_END {
-    let inst = match cpu.fetch() {
-      Ok(inst) => {
-        if inst == 0 {
-          eprintln!("End of program\n");
-          break;
-        };
-        inst
-      }
-      Err(e) => {
-        eprintln!("{e}");
-        break;
-      }
-    };
-    match cpu.execute(inst) {
-      Ok(new_pc) => cpu.pc = new_pc,
-      Err(e) => {
-        eprintln!("{e}");
-        break;
-      }
-    };
-  }
+  let reason = cpu.run(u64::MAX);
+  eprintln!("{reason}\n");
 
+  if let Some((symbol, offset)) = elf.as_ref().and_then(|elf| elf.symbolize(cpu.pc)) {
+    eprintln!("pc is at <{}+0x{:x}>\n", symbol.name, offset);
+  }
   cpu.dump_registers();
+  eprintln!(
+    "\nResident guest memory: {} KiB",
+    cpu.bus.resident_bytes() / 1024
+  );
 
-  Ok(())
+  Ok(reason)
+}
+
+/// Run the riscv-arch-test program `code` (an ELF executable) on the machine of
+/// `config` for up to `step_limit` steps, then write its signature to `signature`
+/// with `granularity` bytes per line.
+///
+/// Nothing is written unless the program exits through HTIF with code 0.
+pub fn run_signature(
+  code: Vec<u8>,
+  config: &MachineConfig,
+  signature: &mut dyn Write,
+  granularity: usize,
+  step_limit: u64,
+) -> io::Result<()> {
+  let (mut cpu, elf) = load_program(code, config, Box::new(io::stdout()))?;
+  let (begin, end) = elf
+    .as_ref()
+    .and_then(signature::signature_range)
+    .ok_or_else(|| {
+      io::Error::new(
+        io::ErrorKind::InvalidData,
+        "no `begin_signature` & `end_signature` symbols in the ELF",
+      )
+    })?;
+  attach_devices(&mut cpu, config, None)?;
+
+  let reason = cpu.run(step_limit);
+  if reason != StopReason::Exit(0) {
+    return Err(io::Error::other(format!(
+      "no signature as the program didn't exit cleanly: {reason}"
+    )));
+  }
+  signature::write_signature(&mut cpu.bus, begin, end, granularity, signature)
 }
@@ -1,6 +1,6 @@
 use std::fmt;
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Exception {
   InstructionAddrMisaligned(u64),
   InstructionAccessFault(u64),
@@ -82,17 +82,51 @@ impl Exception {
       StoreAMOPageFault(_) => 15,
     }
   }
+}
 
-  pub fn is_fatal(self) -> bool {
-    use Exception::*;
-    matches!(
-      self,
-      InstructionAddrMisaligned(_)
-        | InstructionAccessFault(_)
-        | LoadAccessFault(_)
-        | StoreAMOAddrMisaligned(_)
-        | StoreAMOAccessFault(_)
-        | IllegalInstruction(_)
-    )
+/// Interrupts of M-mode & S-mode, in the order of decreasing priority
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Interrupt {
+  MachineExternal,
+  MachineSoftware,
+  MachineTimer,
+  SupervisorExternal,
+  SupervisorSoftware,
+  SupervisorTimer,
+}
+
+impl fmt::Display for Interrupt {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{self:?}Interrupt")
+  }
+}
+
+impl Interrupt {
+  /// All interrupts, from the highest priority to the lowest
+  pub const PRIORITY: [Interrupt; 6] = [
+    Interrupt::MachineExternal,
+    Interrupt::MachineSoftware,
+    Interrupt::MachineTimer,
+    Interrupt::SupervisorExternal,
+    Interrupt::SupervisorSoftware,
+    Interrupt::SupervisorTimer,
+  ];
+
+  /// Exception code in `mcause` & `scause`, without the `Interrupt` bit
+  pub fn code(self) -> u64 {
+    use Interrupt::*;
+    match self {
+      SupervisorSoftware => 1,
+      MachineSoftware => 3,
+      SupervisorTimer => 5,
+      MachineTimer => 7,
+      SupervisorExternal => 9,
+      MachineExternal => 11,
+    }
+  }
+
+  /// Bit of the interrupt in `mip` & `mie`
+  pub fn mask(self) -> u64 {
+    1 << self.code()
   }
 }
@@ -0,0 +1,701 @@
+use crate::param::*;
+
+/// # Rounding Mode
+///
+/// Encoded in the `rm` field of instructions, or in `frm` when `rm == DYN`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RoundingMode {
+  /// Round to Nearest, ties to Even
+  Rne,
+  /// Round towards Zero
+  Rtz,
+  /// Round Down (towards -inf)
+  Rdn,
+  /// Round Up (towards +inf)
+  Rup,
+  /// Round to Nearest, ties to Max Magnitude
+  Rmm,
+}
+
+impl RoundingMode {
+  pub fn from_bits(rm: u64) -> Option<RoundingMode> {
+    match rm {
+      0b000 => Some(RoundingMode::Rne),
+      0b001 => Some(RoundingMode::Rtz),
+      0b010 => Some(RoundingMode::Rdn),
+      0b011 => Some(RoundingMode::Rup),
+      0b100 => Some(RoundingMode::Rmm),
+      _ => None,
+    }
+  }
+}
+
+/// # RvFloat
+///
+/// IEEE 754 binary floating-point format which is held in a 64-bit `FPR`
+///
+/// - `f32` for `F` extension, which is NaN-boxed in `FPR`
+/// - `f64` for `D` extension
+///
+/// Arithmetic is done on raw bits (see [`round_pack`]), so that every
+/// rounding mode and exception flag is honored exactly. Native operations
+/// are only used for comparisons, which are always exact.
+pub trait RvFloat: Copy + PartialEq + PartialOrd {
+  /// Width of the format
+  const BITS: u32;
+  /// Significand precision, including the implicit leading bit
+  const PRECISION: u32;
+  const EMIN: i32;
+  const EMAX: i32;
+  const CANONICAL_NAN: u64;
+
+  fn to_bits_u64(self) -> u64;
+  fn from_bits_u64(bits: u64) -> Self;
+
+  /// Read from a `FPR`, a NaN-boxing check is applied for narrower formats
+  fn unbox(fpr: u64) -> Self {
+    let box_mask = !(u64::MAX >> (64 - Self::BITS));
+    if fpr & box_mask == box_mask {
+      Self::from_bits_u64(fpr)
+    } else {
+      Self::canonical_nan()
+    }
+  }
+  /// Value to be written into a `FPR`, NaN-boxed for narrower formats
+  fn boxed(self) -> u64 {
+    Self::mv_from_x(self.to_bits_u64())
+  }
+  /// `FMV.X.*`: move raw bits of `FPR` into an integer register (sign-extended)
+  fn mv_to_x(fpr: u64) -> u64 {
+    ((fpr << (64 - Self::BITS)) as i64 >> (64 - Self::BITS)) as u64
+  }
+  /// `FMV.*.X`: move raw bits of an integer register into `FPR`
+  fn mv_from_x(x: u64) -> u64 {
+    let box_mask = !(u64::MAX >> (64 - Self::BITS));
+    box_mask | x
+  }
+
+  fn canonical_nan() -> Self {
+    Self::from_bits_u64(Self::CANONICAL_NAN)
+  }
+  fn sign_mask() -> u64 {
+    1 << (Self::BITS - 1)
+  }
+  fn frac_mask() -> u64 {
+    (1 << (Self::PRECISION - 1)) - 1
+  }
+  fn exp_field_max() -> u64 {
+    (1 << (Self::BITS - Self::PRECISION)) - 1
+  }
+  fn exp_field(self) -> u64 {
+    (self.to_bits_u64() >> (Self::PRECISION - 1)) & Self::exp_field_max()
+  }
+  fn frac(self) -> u64 {
+    self.to_bits_u64() & Self::frac_mask()
+  }
+
+  fn is_sign_negative(self) -> bool {
+    self.to_bits_u64() & Self::sign_mask() != 0
+  }
+  fn is_nan(self) -> bool {
+    self.exp_field() == Self::exp_field_max() && self.frac() != 0
+  }
+  fn is_snan(self) -> bool {
+    self.is_nan() && self.frac() >> (Self::PRECISION - 2) == 0
+  }
+  fn is_infinite(self) -> bool {
+    self.exp_field() == Self::exp_field_max() && self.frac() == 0
+  }
+  fn is_zero(self) -> bool {
+    self.exp_field() == 0 && self.frac() == 0
+  }
+  fn is_subnormal(self) -> bool {
+    self.exp_field() == 0 && self.frac() != 0
+  }
+
+  fn zero(negative: bool) -> Self {
+    Self::from_bits_u64(if negative { Self::sign_mask() } else { 0 })
+  }
+  fn infinity(negative: bool) -> Self {
+    let inf = Self::exp_field_max() << (Self::PRECISION - 1);
+    Self::from_bits_u64(if negative {
+      Self::sign_mask() | inf
+    } else {
+      inf
+    })
+  }
+  fn max_finite(negative: bool) -> Self {
+    let max = (Self::infinity(false).to_bits_u64()) - 1;
+    Self::from_bits_u64(if negative {
+      Self::sign_mask() | max
+    } else {
+      max
+    })
+  }
+  fn negate(self) -> Self {
+    Self::from_bits_u64(self.to_bits_u64() ^ Self::sign_mask())
+  }
+
+  /// Decompose a finite value into `(-1)^sign * sig * 2^exp`
+  fn unpack(self) -> Unpacked {
+    let sign = self.is_sign_negative();
+    let (sig, exp) = match self.exp_field() {
+      0 => (self.frac(), Self::EMIN - (Self::PRECISION as i32 - 1)),
+      e => (
+        self.frac() | (1 << (Self::PRECISION - 1)),
+        e as i32 + Self::EMIN - 1 - (Self::PRECISION as i32 - 1),
+      ),
+    };
+    Unpacked {
+      sign,
+      sig: sig as u128,
+      exp,
+    }
+  }
+}
+
+impl RvFloat for f32 {
+  const BITS: u32 = 32;
+  const PRECISION: u32 = 24;
+  const EMIN: i32 = -126;
+  const EMAX: i32 = 127;
+  const CANONICAL_NAN: u64 = 0x7FC0_0000;
+
+  fn to_bits_u64(self) -> u64 {
+    self.to_bits() as u64
+  }
+  fn from_bits_u64(bits: u64) -> Self {
+    f32::from_bits(bits as u32)
+  }
+}
+
+impl RvFloat for f64 {
+  const BITS: u32 = 64;
+  const PRECISION: u32 = 53;
+  const EMIN: i32 = -1022;
+  const EMAX: i32 = 1023;
+  const CANONICAL_NAN: u64 = 0x7FF8_0000_0000_0000;
+
+  fn to_bits_u64(self) -> u64 {
+    self.to_bits()
+  }
+  fn from_bits_u64(bits: u64) -> Self {
+    f64::from_bits(bits)
+  }
+  fn unbox(fpr: u64) -> Self {
+    f64::from_bits(fpr)
+  }
+  fn mv_to_x(fpr: u64) -> u64 {
+    fpr
+  }
+  fn mv_from_x(x: u64) -> u64 {
+    x
+  }
+}
+
+/// An exact value `(-1)^sign * sig * 2^exp`
+#[derive(Debug, Copy, Clone)]
+pub struct Unpacked {
+  sign: bool,
+  sig: u128,
+  exp: i32,
+}
+
+/// Shift right, while "jamming" every bit shifted out into the least-significant
+/// bit, so that an inexact result can never be mistaken for an exact one.
+fn shift_right_jam(sig: u128, shift: u32) -> u128 {
+  match shift {
+    0 => sig,
+    1..=127 => (sig >> shift) | (sig & ((1 << shift) - 1) != 0) as u128,
+    _ => (sig != 0) as u128,
+  }
+}
+
+/// Round an exact value into the format, and raise exception flags.
+///
+/// `sig` must carry at least 2 more bits than the precision of the format if
+/// it's inexact (i.e. has been jammed).
+fn round_pack<F: RvFloat>(x: Unpacked, rm: RoundingMode, fflags: &mut u64) -> F {
+  let Unpacked { sign, sig, exp } = x;
+  if sig == 0 {
+    return F::zero(sign);
+  }
+  let p = F::PRECISION as i32;
+  let top = exp + (127 - sig.leading_zeros() as i32);
+
+  // Round `sig` at the bit whose exponent is `lsb`, returns rounded magnitude
+  // (in units of `2^lsb`) and whether it's inexact
+  let round_at = |lsb: i32| -> (u128, bool) {
+    let shift = lsb - exp;
+    if shift <= 0 {
+      return (sig << -shift, false);
+    }
+    let shift = shift as u32;
+    let (kept, rem, half) = if shift > 128 {
+      (0, sig, None)
+    } else if shift == 128 {
+      (0, sig, Some(1 << 127))
+    } else {
+      (
+        sig >> shift,
+        sig & ((1 << shift) - 1),
+        Some(1_u128 << (shift - 1)),
+      )
+    };
+    let (above_half, at_half) = match half {
+      Some(half) => (rem > half, rem == half),
+      None => (false, false),
+    };
+    let up = match rm {
+      RoundingMode::Rne => above_half || (at_half && kept & 1 == 1),
+      RoundingMode::Rtz => false,
+      RoundingMode::Rdn => sign && rem != 0,
+      RoundingMode::Rup => !sign && rem != 0,
+      RoundingMode::Rmm => above_half || at_half,
+    };
+    (kept + up as u128, rem != 0)
+  };
+
+  let lsb = (top - (p - 1)).max(F::EMIN - (p - 1));
+  let (mut kept, inexact) = round_at(lsb);
+  let mut lsb = lsb;
+  if kept >> p != 0 {
+    // Carried out of the precision
+    kept >>= 1;
+    lsb += 1;
+  }
+  if inexact {
+    *fflags |= MASK_NX;
+  }
+
+  if kept != 0 && lsb + (127 - kept.leading_zeros() as i32) > F::EMAX {
+    *fflags |= MASK_OF | MASK_NX;
+    let to_inf = match rm {
+      RoundingMode::Rne | RoundingMode::Rmm => true,
+      RoundingMode::Rtz => false,
+      RoundingMode::Rdn => sign,
+      RoundingMode::Rup => !sign,
+    };
+    return if to_inf {
+      F::infinity(sign)
+    } else {
+      F::max_finite(sign)
+    };
+  }
+
+  // Tininess is detected after rounding, i.e. as if the exponent range were unbounded
+  if inexact && top < F::EMIN {
+    let (unbounded, _) = round_at(top - (p - 1));
+    let unbounded_top = top - (p - 1) + (127 - unbounded.leading_zeros() as i32);
+    if unbounded_top < F::EMIN {
+      *fflags |= MASK_UF;
+    }
+  }
+
+  // The implicit bit of a normal value is carried into the exponent field
+  let biased = (lsb - (F::EMIN - (p - 1))) as u64;
+  let bits = (biased << (p - 1)) + kept as u64;
+  let sign = if sign { F::sign_mask() } else { 0 };
+  F::from_bits_u64(sign | bits)
+}
+
+/// Exact sum of two values, in which the less significant one may be jammed
+fn add_unpacked(x: Unpacked, y: Unpacked, rm: RoundingMode) -> Unpacked {
+  let top = |v: &Unpacked| v.exp + (127 - v.sig.leading_zeros() as i32);
+  let (x, y) = if y.sig == 0 || (x.sig != 0 && top(&x) >= top(&y)) {
+    (x, y)
+  } else {
+    (y, x)
+  };
+  if x.sig == 0 {
+    return x;
+  }
+  // Place the most-significant bit of `x` at bit 125, which leaves room for
+  // the carry and keeps plenty of guard bits
+  let exp = top(&x) - 125;
+  let align = |v: Unpacked| -> u128 {
+    if v.sig == 0 {
+      0
+    } else if v.exp >= exp {
+      v.sig << (v.exp - exp)
+    } else {
+      shift_right_jam(v.sig, (exp - v.exp) as u32)
+    }
+  };
+  let (xs, ys) = (align(x), align(y));
+  if x.sign == y.sign {
+    return Unpacked {
+      sign: x.sign,
+      sig: xs + ys,
+      exp,
+    };
+  }
+  let (sign, sig) = match xs.cmp(&ys) {
+    std::cmp::Ordering::Less => (y.sign, ys - xs),
+    std::cmp::Ordering::Greater => (x.sign, xs - ys),
+    // An exact zero is `+0`, except `-0` when rounding down
+    std::cmp::Ordering::Equal => (rm == RoundingMode::Rdn, 0),
+  };
+  Unpacked { sign, sig, exp }
+}
+
+/// Result of an operation which has a NaN operand
+fn propagate_nan<F: RvFloat>(operands: &[F], fflags: &mut u64) -> F {
+  if operands.iter().any(|x| x.is_snan()) {
+    *fflags |= MASK_NV;
+  }
+  F::canonical_nan()
+}
+
+/// Result of an invalid operation
+fn invalid<F: RvFloat>(fflags: &mut u64) -> F {
+  *fflags |= MASK_NV;
+  F::canonical_nan()
+}
+
+pub fn add<F: RvFloat>(a: F, b: F, rm: RoundingMode, fflags: &mut u64) -> F {
+  if a.is_nan() || b.is_nan() {
+    return propagate_nan(&[a, b], fflags);
+  }
+  match (a.is_infinite(), b.is_infinite()) {
+    (true, true) if a.is_sign_negative() != b.is_sign_negative() => return invalid(fflags),
+    (true, _) => return a,
+    (_, true) => return b,
+    _ => {}
+  }
+  if a.is_zero() && b.is_zero() {
+    let negative = match a.is_sign_negative() == b.is_sign_negative() {
+      true => a.is_sign_negative(),
+      false => rm == RoundingMode::Rdn,
+    };
+    return F::zero(negative);
+  }
+  round_pack(add_unpacked(a.unpack(), b.unpack(), rm), rm, fflags)
+}
+
+pub fn sub<F: RvFloat>(a: F, b: F, rm: RoundingMode, fflags: &mut u64) -> F {
+  if a.is_nan() || b.is_nan() {
+    return propagate_nan(&[a, b], fflags);
+  }
+  add(a, b.negate(), rm, fflags)
+}
+
+pub fn mul<F: RvFloat>(a: F, b: F, rm: RoundingMode, fflags: &mut u64) -> F {
+  if a.is_nan() || b.is_nan() {
+    return propagate_nan(&[a, b], fflags);
+  }
+  let sign = a.is_sign_negative() != b.is_sign_negative();
+  if a.is_infinite() || b.is_infinite() {
+    if a.is_zero() || b.is_zero() {
+      return invalid(fflags);
+    }
+    return F::infinity(sign);
+  }
+  let (x, y) = (a.unpack(), b.unpack());
+  let product = Unpacked {
+    sign,
+    sig: x.sig * y.sig,
+    exp: x.exp + y.exp,
+  };
+  round_pack(product, rm, fflags)
+}
+
+pub fn div<F: RvFloat>(a: F, b: F, rm: RoundingMode, fflags: &mut u64) -> F {
+  if a.is_nan() || b.is_nan() {
+    return propagate_nan(&[a, b], fflags);
+  }
+  let sign = a.is_sign_negative() != b.is_sign_negative();
+  match (a.is_infinite(), b.is_infinite()) {
+    (true, true) => return invalid(fflags),
+    (true, false) => return F::infinity(sign),
+    (false, true) => return F::zero(sign),
+    _ => {}
+  }
+  if b.is_zero() {
+    if a.is_zero() {
+      return invalid(fflags);
+    }
+    *fflags |= MASK_DZ;
+    return F::infinity(sign);
+  }
+  if a.is_zero() {
+    return F::zero(sign);
+  }
+  let (x, y) = (a.unpack(), b.unpack());
+  // Make the dividend as wide as possible, so that the quotient has more
+  // than 64 significant bits
+  let shift = x.sig.leading_zeros() - 1;
+  let dividend = x.sig << shift;
+  let quotient = dividend / y.sig;
+  let inexact = dividend % y.sig != 0;
+  let quotient = Unpacked {
+    sign,
+    sig: quotient | inexact as u128,
+    exp: x.exp - shift as i32 - y.exp,
+  };
+  round_pack(quotient, rm, fflags)
+}
+
+pub fn sqrt<F: RvFloat>(a: F, rm: RoundingMode, fflags: &mut u64) -> F {
+  if a.is_nan() {
+    return propagate_nan(&[a], fflags);
+  }
+  if a.is_zero() {
+    return a;
+  }
+  if a.is_sign_negative() {
+    return invalid(fflags);
+  }
+  if a.is_infinite() {
+    return a;
+  }
+  let x = a.unpack();
+  // Make the radicand as wide as possible with an even exponent, so that
+  // the root has more than 60 significant bits
+  let mut shift = x.sig.leading_zeros() as i32 - 2;
+  if (x.exp - shift) % 2 != 0 {
+    shift -= 1;
+  }
+  let radicand = x.sig << shift;
+  let root = radicand.isqrt();
+  let inexact = root * root != radicand;
+  let root = Unpacked {
+    sign: false,
+    sig: root | inexact as u128,
+    exp: (x.exp - shift) / 2,
+  };
+  round_pack(root, rm, fflags)
+}
+
+/// `a * b + c` with only one rounding
+pub fn fma<F: RvFloat>(a: F, b: F, c: F, rm: RoundingMode, fflags: &mut u64) -> F {
+  let zero_mul_inf = (a.is_zero() && b.is_infinite()) || (a.is_infinite() && b.is_zero());
+  if a.is_nan() || b.is_nan() || c.is_nan() {
+    // `inf * 0 + qNaN` is invalid as well
+    if zero_mul_inf {
+      *fflags |= MASK_NV;
+    }
+    return propagate_nan(&[a, b, c], fflags);
+  }
+  if zero_mul_inf {
+    return invalid(fflags);
+  }
+  let sign = a.is_sign_negative() != b.is_sign_negative();
+  if a.is_infinite() || b.is_infinite() {
+    if c.is_infinite() && c.is_sign_negative() != sign {
+      return invalid(fflags);
+    }
+    return F::infinity(sign);
+  }
+  if c.is_infinite() {
+    return c;
+  }
+  if a.is_zero() || b.is_zero() {
+    return add(F::zero(sign), c, rm, fflags);
+  }
+  let (x, y) = (a.unpack(), b.unpack());
+  let product = Unpacked {
+    sign,
+    sig: x.sig * y.sig,
+    exp: x.exp + y.exp,
+  };
+  let addend = if c.is_zero() {
+    Unpacked {
+      sign: c.is_sign_negative(),
+      sig: 0,
+      exp: 0,
+    }
+  } else {
+    c.unpack()
+  };
+  round_pack(add_unpacked(product, addend, rm), rm, fflags)
+}
+
+pub fn min_max<F: RvFloat>(a: F, b: F, is_max: bool, fflags: &mut u64) -> F {
+  if a.is_snan() || b.is_snan() {
+    *fflags |= MASK_NV;
+  }
+  match (a.is_nan(), b.is_nan()) {
+    (true, true) => F::canonical_nan(),
+    (true, false) => b,
+    (false, true) => a,
+    _ => {
+      // -0.0 is considered to be less than +0.0
+      let a_less = a < b || (a == b && a.is_sign_negative());
+      if a_less != is_max {
+        a
+      } else {
+        b
+      }
+    }
+  }
+}
+
+/// `FEQ` is a quiet comparison, while `FLT` & `FLE` are signaling comparisons
+pub fn compare<F: RvFloat>(a: F, b: F, funct3: u32, fflags: &mut u64) -> Option<bool> {
+  let signaling = funct3 != FEQ;
+  if a.is_snan() || b.is_snan() || (signaling && (a.is_nan() || b.is_nan())) {
+    *fflags |= MASK_NV;
+  }
+  match funct3 {
+    FEQ => Some(a == b),
+    FLT => Some(a < b),
+    FLE => Some(a <= b),
+    _ => None,
+  }
+}
+
+pub fn classify<F: RvFloat>(a: F) -> u64 {
+  let negative = a.is_sign_negative();
+  let bit = if a.is_nan() {
+    if a.is_snan() {
+      8
+    } else {
+      9
+    }
+  } else if a.is_infinite() {
+    if negative {
+      0
+    } else {
+      7
+    }
+  } else if a.is_zero() {
+    if negative {
+      3
+    } else {
+      4
+    }
+  } else if a.is_subnormal() {
+    if negative {
+      2
+    } else {
+      5
+    }
+  } else if negative {
+    1
+  } else {
+    6
+  };
+  1 << bit
+}
+
+/// `FCVT.{W|WU|L|LU}.*`: convert to an integer, which is saturated on overflow.
+///
+/// 32-bit results are sign-extended to 64 bits.
+pub fn to_int<F: RvFloat>(
+  a: F,
+  signed: bool,
+  bits: u32,
+  rm: RoundingMode,
+  fflags: &mut u64,
+) -> u64 {
+  let (min, max): (i128, i128) = match signed {
+    true => (-(1 << (bits - 1)), (1 << (bits - 1)) - 1),
+    false => (0, (1 << bits) - 1),
+  };
+  let saturated = |fflags: &mut u64, negative: bool| -> i128 {
+    *fflags |= MASK_NV;
+    if negative {
+      min
+    } else {
+      max
+    }
+  };
+  let result = if a.is_nan() {
+    saturated(fflags, false)
+  } else if a.is_infinite() {
+    saturated(fflags, a.is_sign_negative())
+  } else if a.is_zero() {
+    0
+  } else {
+    let x = a.unpack();
+    let magnitude = if x.exp >= 0 {
+      // Every integral value that large overflows anyway
+      (x.sig << x.exp.min(64), false)
+    } else {
+      let shift = (-x.exp) as u32;
+      // `sig` lies entirely below half when it's shifted out by more than 128 bits
+      let (kept, rem, half) = if shift > 128 {
+        (0, x.sig, None)
+      } else if shift == 128 {
+        (0, x.sig, Some(1 << 127))
+      } else {
+        (
+          x.sig >> shift,
+          x.sig & ((1 << shift) - 1),
+          Some(1_u128 << (shift - 1)),
+        )
+      };
+      let (above_half, at_half) = match half {
+        Some(half) => (rem > half, rem == half),
+        None => (false, false),
+      };
+      let up = match rm {
+        RoundingMode::Rne => above_half || (at_half && kept & 1 == 1),
+        RoundingMode::Rtz => false,
+        RoundingMode::Rdn => x.sign && rem != 0,
+        RoundingMode::Rup => !x.sign && rem != 0,
+        RoundingMode::Rmm => above_half || at_half,
+      };
+      (kept + up as u128, rem != 0)
+    };
+    let (magnitude, inexact) = magnitude;
+    let value = if x.sign {
+      -(magnitude as i128)
+    } else {
+      magnitude as i128
+    };
+    if value < min || value > max {
+      saturated(fflags, x.sign)
+    } else {
+      if inexact {
+        *fflags |= MASK_NX;
+      }
+      value
+    }
+  };
+  if bits == 32 {
+    result as i32 as i64 as u64
+  } else {
+    result as i64 as u64
+  }
+}
+
+/// `FCVT.*.{W|WU|L|LU}`: convert from an integer
+pub fn from_int<F: RvFloat>(x: i128, rm: RoundingMode, fflags: &mut u64) -> F {
+  let value = Unpacked {
+    sign: x < 0,
+    sig: x.unsigned_abs(),
+    exp: 0,
+  };
+  round_pack(value, rm, fflags)
+}
+
+/// `FCVT.S.D` & `FCVT.D.S`: convert between formats
+pub fn convert<F: RvFloat, T: RvFloat>(a: F, rm: RoundingMode, fflags: &mut u64) -> T {
+  if a.is_nan() {
+    if a.is_snan() {
+      *fflags |= MASK_NV;
+    }
+    return T::canonical_nan();
+  }
+  if a.is_infinite() {
+    return T::infinity(a.is_sign_negative());
+  }
+  if a.is_zero() {
+    return T::zero(a.is_sign_negative());
+  }
+  round_pack(a.unpack(), rm, fflags)
+}
+
+/// `FSGNJ`, `FSGNJN` & `FSGNJX`, which never canonicalize NaNs
+pub fn sign_inject<F: RvFloat>(a: F, b: F, funct3: u32) -> Option<F> {
+  let (a, b) = (a.to_bits_u64(), b.to_bits_u64());
+  let sign = match funct3 {
+    FSGNJ_J => b & F::sign_mask(),
+    FSGNJ_JN => !b & F::sign_mask(),
+    FSGNJ_JX => (a ^ b) & F::sign_mask(),
+    _ => return None,
+  };
+  Some(F::from_bits_u64((a & !F::sign_mask()) | sign))
+}
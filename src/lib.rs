@@ -1,8 +1,15 @@
 pub mod bus;
+pub mod config;
 pub mod cpu;
 pub mod csr;
+pub mod devices;
 pub mod dram;
+pub mod elf;
 pub mod emulator;
 pub mod exception;
+pub mod fpu;
+pub mod mmu;
 pub mod param;
+pub mod rvc;
+pub mod signature;
 pub mod utils;
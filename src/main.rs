@@ -1,21 +1,134 @@
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io;
+use std::process;
 
+use rvemu_for_book::bus::MisalignedPolicy;
+use rvemu_for_book::config::MachineConfig;
+use rvemu_for_book::cpu::StopReason;
+use rvemu_for_book::devices::virtio_blk::{DiskImage, DiskMode};
+use rvemu_for_book::{emulator, signature};
+
+const USAGE: &str = "Usage:\n\
+            - cargo run <filename> [--memory <MiB>] [--misaligned <policy>] [--tohost <addr>]\n\
+            \x20   [--cycles-per-tick <cycles>]\n\
+            \x20   [--signature <file> [--signature-granularity <bytes>] [--step-limit <steps>]]\n\
+            \x20   [--disk <image> [--read-only | --snapshot]]\n\
+            \n\
+            --memory <MiB>  size of DRAM, which is 128 MiB by default\n\
+            --misaligned <trap | emulate | within-page>\n\
+            \x20               handling of misaligned loads & stores, which are emulated by default\n\
+            --tohost <addr> address of HTIF `tohost`, instead of the symbol of an ELF\n\
+            --cycles-per-tick <cycles>\n\
+            \x20               hart clock cycles per `mtime` increment, which is 1 by default\n\
+            --signature <file>\n\
+            \x20               run a riscv-arch-test ELF, then write its signature to the file\n\
+            --signature-granularity <bytes>\n\
+            \x20               bytes per line of the signature, which is 4 by default\n\
+            --step-limit <steps>\n\
+            \x20               steps the program of `--signature` may take to exit, 100000000 by default\n\
+            --disk <image>  attach a raw disk image as a virtio block device\n\
+            --read-only     reject any write to the disk\n\
+            --snapshot      keep writes to the disk in memory, leaving the image intact";
+
+/// Exit code of invalid arguments
+const USAGE_ERROR: u64 = 2;
+
+/// Parse a hexadecimal (with `0x`) or decimal address
+fn parse_addr(addr: &str) -> Option<u64> {
+  match addr.strip_prefix("0x") {
+    Some(hex) => u64::from_str_radix(hex, 16).ok(),
+    None => addr.parse().ok(),
+  }
+}
+
+/// Run the program, which returns the exit code requested by the guest, if any
 #[inline]
-fn run() -> io::Result<()> {
-  let args: Vec<String> = env::args().collect();
-  if args.len() != 2 {
-    println!(
-      "Usage:\n\
-            - cargo run <filename>"
-    );
-    return Ok(());
+fn run() -> io::Result<Option<u64>> {
+  let args: Vec<String> = env::args().skip(1).collect();
+  let mut program = None;
+  let mut disk = None;
+  let mut mode = None;
+  let mut config = MachineConfig::default();
+  let mut signature = None;
+  let mut granularity = signature::DEFAULT_GRANULARITY;
+  let mut step_limit = None;
+  let mut valid = true;
+  let mut args = args.iter();
+  while let Some(arg) = args.next() {
+    match arg.as_str() {
+      "--disk" => {
+        disk = args.next();
+        valid &= disk.is_some();
+      }
+      "--memory" => match args
+        .next()
+        .and_then(|mib| mib.parse::<u64>().ok())
+        .and_then(|mib| mib.checked_mul(1 << 20))
+      {
+        Some(size) if size > 0 => config.dram_size = size,
+        _ => valid = false,
+      },
+      "--misaligned" => match args.next().map(String::as_str) {
+        Some("trap") => config.misaligned = MisalignedPolicy::Trap,
+        Some("emulate") => config.misaligned = MisalignedPolicy::Emulate,
+        Some("within-page") => config.misaligned = MisalignedPolicy::EmulateWithinPage,
+        _ => valid = false,
+      },
+      "--tohost" => match args.next().and_then(|addr| parse_addr(addr)) {
+        Some(addr) => config.tohost = Some(addr),
+        None => valid = false,
+      },
+      "--cycles-per-tick" => match args.next().and_then(|cycles| cycles.parse().ok()) {
+        Some(cycles) if cycles > 0 => config.clint_cycles_per_tick = cycles,
+        _ => valid = false,
+      },
+      "--signature" => {
+        signature = args.next();
+        valid &= signature.is_some();
+      }
+      "--signature-granularity" => match args.next().and_then(|bytes| bytes.parse().ok()) {
+        Some(bytes) if bytes > 0 => granularity = bytes,
+        _ => valid = false,
+      },
+      "--step-limit" => match args.next().and_then(|steps| steps.parse().ok()) {
+        Some(steps) if steps > 0 => step_limit = Some(steps),
+        _ => valid = false,
+      },
+      "--read-only" => mode = Some(DiskMode::ReadOnly),
+      "--snapshot" => mode = Some(DiskMode::CopyOnWrite),
+      _ if program.is_none() && !arg.starts_with("--") => program = Some(arg),
+      _ => valid = false,
+    }
   }
-  let file = File::open(&args[1])?;
-  rvemu_for_book::emulator::run_with(file)
+  // Options of the signature & the disk only make sense with them
+  valid &= signature.is_some() || step_limit.is_none();
+  valid &= disk.is_some() || mode.is_none();
+  let Some(program) = program.filter(|_| valid) else {
+    eprintln!("{USAGE}");
+    return Ok(Some(USAGE_ERROR));
+  };
+  if let Some(path) = signature {
+    let code = fs::read(program)?;
+    let step_limit = step_limit.unwrap_or(signature::DEFAULT_STEP_LIMIT);
+    // The file is created only once there's a signature to write
+    let mut out = vec![];
+    emulator::run_signature(code, &config, &mut out, granularity, step_limit)?;
+    fs::write(path, out)?;
+    return Ok(None);
+  }
+  let file = File::open(program)?;
+  let mode = mode.unwrap_or(DiskMode::ReadWrite);
+  let disk = disk.map(|path| DiskImage::open(path, mode)).transpose()?;
+  Ok(match emulator::run_with(file, disk, &config)? {
+    StopReason::Exit(code) => Some(code),
+    _ => None,
+  })
 }
 
 fn main() -> io::Result<()> {
-  run()
+  if let Some(code) = run()? {
+    process::exit(code as i32);
+  }
+  Ok(())
 }
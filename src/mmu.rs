@@ -0,0 +1,326 @@
+//! # MMU
+//!
+//! Page-based virtual memory of `Sv39` & `Sv48`
+
+use crate::bus::*;
+use crate::cpu::Mode;
+use crate::dram::SizeType;
+use crate::exception::*;
+use crate::param::*;
+
+/// Size (in bytes) of a page table entry
+const PTE_SIZE: u64 = 8;
+
+/// Kind of a memory access, which decides the permission and exception to check
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AccessType {
+  Instruction,
+  Load,
+  /// `Store` & `AMO`
+  Store,
+}
+
+impl AccessType {
+  pub fn page_fault(self, addr: u64) -> Exception {
+    match self {
+      AccessType::Instruction => Exception::InstructionPageFault(addr),
+      AccessType::Load => Exception::LoadPageFault(addr),
+      AccessType::Store => Exception::StoreAMOPageFault(addr),
+    }
+  }
+  pub fn misaligned(self, addr: u64) -> Exception {
+    match self {
+      AccessType::Instruction => Exception::InstructionAddrMisaligned(addr),
+      AccessType::Load => Exception::LoadAccessMisaligned(addr),
+      AccessType::Store => Exception::StoreAMOAddrMisaligned(addr),
+    }
+  }
+  pub fn access_fault(self, addr: u64) -> Exception {
+    match self {
+      AccessType::Instruction => Exception::InstructionAccessFault(addr),
+      AccessType::Load => Exception::LoadAccessFault(addr),
+      AccessType::Store => Exception::StoreAMOAccessFault(addr),
+    }
+  }
+}
+
+/// Number of page table levels of the scheme selected by `satp.MODE`,
+/// or `None` for `Bare` (no translation)
+pub fn levels(satp: u64) -> Option<u32> {
+  match (satp & MASK_SATP_MODE) >> 60 {
+    SATP_MODE_SV39 => Some(3),
+    SATP_MODE_SV48 => Some(4),
+    _ => None,
+  }
+}
+
+/// Whether `satp.MODE` is implemented, other values make the `satp` write ignored
+pub fn is_supported_mode(satp: u64) -> bool {
+  matches!(
+    (satp & MASK_SATP_MODE) >> 60,
+    SATP_MODE_BARE | SATP_MODE_SV39 | SATP_MODE_SV48
+  )
+}
+
+/// Result of a successful page table walk
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Translation {
+  /// Physical address of the (super)page which contains the address
+  pub page_base: u64,
+  /// Size (in bytes) of the (super)page
+  pub page_size: u64,
+  /// Leaf PTE, with `A` & `D` bits updated unless it's from `probe`
+  pub pte: u64,
+}
+
+impl Translation {
+  pub fn physical_addr(&self, addr: u64) -> u64 {
+    self.page_base | (addr & (self.page_size - 1))
+  }
+}
+
+/// Check the permission of a leaf PTE, where `mode` is the effective privilege level
+pub fn check_permission(pte: u64, mode: Mode, mstatus: u64, access: AccessType) -> bool {
+  let user_page = pte & MASK_PTE_U != 0;
+  let privilege_ok = match mode {
+    Mode::User => user_page,
+    // S-mode may never execute user pages, and accesses them only with `SUM` set
+    Mode::Supervisor => {
+      !user_page || (access != AccessType::Instruction && mstatus & MASK_SUM != 0)
+    }
+    Mode::Machine => true,
+  };
+  let (r, w, x) = (
+    pte & MASK_PTE_R != 0,
+    pte & MASK_PTE_W != 0,
+    pte & MASK_PTE_X != 0,
+  );
+  let type_ok = match access {
+    AccessType::Instruction => x,
+    // `MXR` makes executable pages readable
+    AccessType::Load => r || (x && mstatus & MASK_MXR != 0),
+    AccessType::Store => w,
+  };
+  privilege_ok && type_ok
+}
+
+/// Walk the page tables rooted at `satp.PPN`, which are read (and
+/// updated with `A` & `D` bits) through `Bus`.
+///
+/// `mode` is the effective privilege level, which must not be M-mode.
+pub fn walk(
+  bus: &mut Bus,
+  satp: u64,
+  mstatus: u64,
+  mode: Mode,
+  addr: u64,
+  access: AccessType,
+) -> Result<Translation, Exception> {
+  let (mut translation, pte_addr) = find_leaf(bus, satp, mstatus, mode, addr, access)?;
+  let Some(pte_addr) = pte_addr else {
+    return Ok(translation);
+  };
+  // `A` & `D` bits are managed by hardware
+  let pte = translation.pte;
+  let mut updated = pte | MASK_PTE_A;
+  if access == AccessType::Store {
+    updated |= MASK_PTE_D;
+  }
+  if updated != pte {
+    bus
+      .store(pte_addr, SizeType::DoubleWord, updated)
+      .map_err(|_| access.access_fault(addr))?;
+    translation.pte = updated;
+  }
+  Ok(translation)
+}
+
+/// Walk the page tables like `walk`, but without updating `A` & `D` bits
+pub fn probe(
+  bus: &mut Bus,
+  satp: u64,
+  mstatus: u64,
+  mode: Mode,
+  addr: u64,
+  access: AccessType,
+) -> Result<Translation, Exception> {
+  find_leaf(bus, satp, mstatus, mode, addr, access).map(|(translation, _)| translation)
+}
+
+/// Find the leaf PTE of `addr`, and return the translation by it with its
+/// address, which is `None` for `Bare`
+fn find_leaf(
+  bus: &mut Bus,
+  satp: u64,
+  mstatus: u64,
+  mode: Mode,
+  addr: u64,
+  access: AccessType,
+) -> Result<(Translation, Option<u64>), Exception> {
+  let levels = match levels(satp) {
+    Some(levels) => levels,
+    None => {
+      let translation = Translation {
+        page_base: addr & !(PAGE_SIZE - 1),
+        page_size: PAGE_SIZE,
+        pte: MASK_PTE_R | MASK_PTE_W | MASK_PTE_X,
+      };
+      return Ok((translation, None));
+    }
+  };
+  let va_bits = 12 + 9 * levels;
+  // Bits above the virtual address must all equal to its highest bit
+  let upper = (addr as i64) >> (va_bits - 1);
+  if upper != 0 && upper != -1 {
+    return Err(access.page_fault(addr));
+  }
+
+  let vpn = |level: u32| (addr >> (12 + 9 * level)) & 0x1FF;
+  let mut table = (satp & MASK_SATP_PPN) * PAGE_SIZE;
+  let mut level = levels - 1;
+  let (pte_addr, pte) = loop {
+    let pte_addr = table + vpn(level) * PTE_SIZE;
+    let pte = bus
+      .load_u(pte_addr, SizeType::DoubleWord)
+      .map_err(|_| access.access_fault(addr))?;
+    let (v, r, w, x) = (
+      pte & MASK_PTE_V != 0,
+      pte & MASK_PTE_R != 0,
+      pte & MASK_PTE_W != 0,
+      pte & MASK_PTE_X != 0,
+    );
+    if !v || (!r && w) || pte & MASK_PTE_RESERVED != 0 {
+      return Err(access.page_fault(addr));
+    }
+    if r || x {
+      break (pte_addr, pte);
+    }
+    // Pointer to the next level, whose `D`, `A` & `U` bits are reserved
+    if level == 0 || pte & (MASK_PTE_D | MASK_PTE_A | MASK_PTE_U) != 0 {
+      return Err(access.page_fault(addr));
+    }
+    level -= 1;
+    table = ((pte & MASK_PTE_PPN) >> 10) * PAGE_SIZE;
+  };
+
+  if !check_permission(pte, mode, mstatus, access) {
+    return Err(access.page_fault(addr));
+  }
+  let page_size = PAGE_SIZE << (9 * level);
+  let page_base = ((pte & MASK_PTE_PPN) >> 10) * PAGE_SIZE;
+  // Superpage must be aligned to its size
+  if page_base & (page_size - 1) != 0 {
+    return Err(access.page_fault(addr));
+  }
+
+  let translation = Translation {
+    page_base,
+    page_size,
+    pte,
+  };
+  Ok((translation, Some(pte_addr)))
+}
+
+/// A cached translation
+#[derive(Debug, Copy, Clone)]
+struct TlbEntry {
+  asid: u16,
+  /// Virtual page number (of a 4 KiB page), superpages are cached per 4 KiB page
+  vpn: u64,
+  translation: Translation,
+}
+
+impl TlbEntry {
+  fn is_global(&self) -> bool {
+    self.translation.pte & MASK_PTE_G != 0
+  }
+  /// Whether the (super)page of this entry contains `addr`
+  fn covers(&self, addr: u64) -> bool {
+    let page_mask = !(self.translation.page_size - 1);
+    (self.vpn << 12) & page_mask == addr & page_mask
+  }
+}
+
+/// # TLB
+///
+/// Set-associative cache of translations, keyed by `ASID` & `VPN`
+pub struct Tlb {
+  sets: Vec<Vec<TlbEntry>>,
+  n_ways: usize,
+  /// Round-robin replacement pointer of each set
+  victims: Vec<usize>,
+  pub hits: u64,
+  pub misses: u64,
+}
+
+impl Tlb {
+  pub fn new(n_sets: usize, n_ways: usize) -> Tlb {
+    Self {
+      sets: vec![Vec::with_capacity(n_ways); n_sets],
+      n_ways,
+      victims: vec![0; n_sets],
+      hits: 0,
+      misses: 0,
+    }
+  }
+
+  fn set_index(&self, vpn: u64) -> usize {
+    (vpn % self.sets.len() as u64) as usize
+  }
+
+  /// Look up the translation of `addr`, which is a hit only if it's `usable`
+  pub fn lookup(
+    &mut self,
+    asid: u16,
+    addr: u64,
+    usable: impl Fn(&Translation) -> bool,
+  ) -> Option<Translation> {
+    let vpn = addr >> 12;
+    let hit = self.sets[self.set_index(vpn)]
+      .iter()
+      .find(|entry| entry.vpn == vpn && (entry.asid == asid || entry.is_global()))
+      .map(|entry| entry.translation)
+      .filter(usable);
+    if hit.is_some() {
+      self.hits += 1;
+    } else {
+      self.misses += 1;
+    }
+    hit
+  }
+
+  pub fn insert(&mut self, asid: u16, addr: u64, translation: Translation) {
+    let vpn = addr >> 12;
+    let index = self.set_index(vpn);
+    let entry = TlbEntry {
+      asid,
+      vpn,
+      translation,
+    };
+    let set = &mut self.sets[index];
+    if let Some(old) = set
+      .iter_mut()
+      .find(|old| old.vpn == vpn && old.asid == asid)
+    {
+      *old = entry;
+    } else if set.len() < self.n_ways {
+      set.push(entry);
+    } else {
+      set[self.victims[index]] = entry;
+      self.victims[index] = (self.victims[index] + 1) % self.n_ways;
+    }
+  }
+
+  /// Flush entries as `SFENCE.VMA` does, where `None` means all addresses or all ASIDs.
+  ///
+  /// Global entries are kept when flushing a single ASID.
+  pub fn flush(&mut self, addr: Option<u64>, asid: Option<u16>) {
+    for set in self.sets.iter_mut() {
+      set.retain(|entry| {
+        let addr_match = addr.is_none_or(|addr| entry.covers(addr));
+        let asid_match = asid.is_none_or(|asid| entry.asid == asid && !entry.is_global());
+        !(addr_match && asid_match)
+      });
+    }
+  }
+}
@@ -3,6 +3,24 @@ pub const DRAM_BASE: u64 = 0x8000_0000;
 pub const DRAM_SIZE: u64 = 1024 * 1024 * 128; // 128 MB
 pub const DRAM_END: u64 = DRAM_SIZE + DRAM_BASE - 1;
 
+/* ---*---*---*---*--- Device Params ---*---*---*---*--- */
+pub const UART_BASE: u64 = 0x1000_0000;
+pub const UART_SIZE: u64 = 0x100;
+/// Interrupt source ID of UART on the interrupt controller
+pub const UART_IRQ: u32 = 10;
+pub const CLINT_BASE: u64 = 0x200_0000;
+pub const CLINT_SIZE: u64 = 0x1_0000;
+/// Hart clock cycles per `mtime` increment
+pub const CLINT_CYCLES_PER_TICK: u64 = 1;
+pub const PLIC_BASE: u64 = 0xC00_0000;
+pub const PLIC_SIZE: u64 = 0x400_0000;
+/// Number of interrupt sources of PLIC, including the nonexistent source 0
+pub const PLIC_NUM_SOURCES: u32 = 64;
+pub const VIRTIO_BASE: u64 = 0x1000_1000;
+pub const VIRTIO_SIZE: u64 = 0x1000;
+/// Interrupt source ID of the virtio block device on the interrupt controller
+pub const VIRTIO_IRQ: u32 = 1;
+
 /* ---*---*---*---*--- RV32I Base ---*---*---*---*--- */
 /* Branch Inst */
 pub const BRANCH_OP: u32 = 0b1100011;
@@ -54,10 +72,27 @@ pub const JAL: u32 = 0b1101111;
 pub const JALR: u32 = 0b1100111;
 /* EType Inst */
 pub const E_TYPE_OP: u32 = 0b1110011;
+pub const PRIV: u32 = 0b000;
 pub const ECALL: u32 = 0;
 pub const EBREAK: u32 = 1;
+/* Trap-Return Inst (funct12 of PRIV) */
+pub const SRET: u32 = 0b0001_0000_0010;
+pub const MRET: u32 = 0b0011_0000_0010;
+/* Interrupt-Management Inst (funct12 of PRIV) */
+pub const WFI: u32 = 0b0001_0000_0101;
+/* funct7 of PRIV Inst, whose `rs2` & `rs1` are operands */
+pub const SFENCE_VMA: u32 = 0b0001001;
+/* Zicsr Inst (funct3 of EType) */
+pub const CSRRW: u32 = 0b001;
+pub const CSRRS: u32 = 0b010;
+pub const CSRRC: u32 = 0b011;
+pub const CSRRWI: u32 = 0b101;
+pub const CSRRSI: u32 = 0b110;
+pub const CSRRCI: u32 = 0b111;
 /* Other Inst */
 pub const FENCE: u32 = 0b0001111;
+/* Zifencei Inst (funct3 of FENCE) */
+pub const FENCE_I: u32 = 0b001;
 
 /* ---*---*---*---*--- RV64I Base ---*---*---*---*--- */
 /* IW_Type Inst */
@@ -71,6 +106,109 @@ pub const ADDW_SUBW: u32 = 0b000;
 pub const SLLW: u32 = 0b001;
 pub const SRLW_SRAW: u32 = 0b101;
 
+/* ---*---*---*---*--- RV64M Extension ---*---*---*---*--- */
+/* RType Inst (funct7 == MULDIV) */
+pub const MULDIV: u32 = 0b0000001;
+pub const MUL: u32 = 0b000;
+pub const MULH: u32 = 0b001;
+pub const MULHSU: u32 = 0b010;
+pub const MULHU: u32 = 0b011;
+pub const DIV: u32 = 0b100;
+pub const DIVU: u32 = 0b101;
+pub const REM: u32 = 0b110;
+pub const REMU: u32 = 0b111;
+/* RW_Type Inst (funct7 == MULDIV) */
+pub const MULW: u32 = 0b000;
+pub const DIVW: u32 = 0b100;
+pub const DIVUW: u32 = 0b101;
+pub const REMW: u32 = 0b110;
+pub const REMUW: u32 = 0b111;
+
+/* ---*---*---*---*--- RV64A Extension ---*---*---*---*--- */
+pub const AMO_OP: u32 = 0b0101111;
+/* funct3 of AMO */
+pub const AMO_W: u32 = 0b010;
+pub const AMO_D: u32 = 0b011;
+/* funct5 of AMO */
+pub const LR: u32 = 0b00010;
+pub const SC: u32 = 0b00011;
+pub const AMOSWAP: u32 = 0b00001;
+pub const AMOADD: u32 = 0b00000;
+pub const AMOXOR: u32 = 0b00100;
+pub const AMOAND: u32 = 0b01100;
+pub const AMOOR: u32 = 0b01000;
+pub const AMOMIN: u32 = 0b10000;
+pub const AMOMAX: u32 = 0b10100;
+pub const AMOMINU: u32 = 0b11000;
+pub const AMOMAXU: u32 = 0b11100;
+
+/* ---*---*---*---*--- RV64F & RV64D Extension ---*---*---*---*--- */
+/* Load & Store Inst */
+pub const LOAD_FP_OP: u32 = 0b0000111;
+pub const STORE_FP_OP: u32 = 0b0100111;
+pub const FLW_FSW: u32 = 0b010;
+pub const FLD_FSD: u32 = 0b011;
+/* Fused Multiply-Add Inst */
+pub const FMADD_OP: u32 = 0b1000011;
+pub const FMSUB_OP: u32 = 0b1000111;
+pub const FNMSUB_OP: u32 = 0b1001011;
+pub const FNMADD_OP: u32 = 0b1001111;
+/* Other Inst */
+pub const OP_FP: u32 = 0b1010011;
+/* fmt (funct7[1:0]) */
+pub const FMT_S: u32 = 0b00;
+pub const FMT_D: u32 = 0b01;
+/* funct5 (funct7[6:2]) of OP_FP */
+pub const FADD: u32 = 0b00000;
+pub const FSUB: u32 = 0b00001;
+pub const FMUL: u32 = 0b00010;
+pub const FDIV: u32 = 0b00011;
+pub const FSQRT: u32 = 0b01011;
+pub const FSGNJ: u32 = 0b00100;
+pub const FMIN_FMAX: u32 = 0b00101;
+pub const FCVT_F_F: u32 = 0b01000;
+pub const FCMP: u32 = 0b10100;
+pub const FCVT_X_F: u32 = 0b11000;
+pub const FCVT_F_X: u32 = 0b11010;
+pub const FMV_X_F_FCLASS: u32 = 0b11100;
+pub const FMV_F_X: u32 = 0b11110;
+/* funct3 of FSGNJ */
+pub const FSGNJ_J: u32 = 0b000;
+pub const FSGNJ_JN: u32 = 0b001;
+pub const FSGNJ_JX: u32 = 0b010;
+/* funct3 of FMIN_FMAX */
+pub const FMIN: u32 = 0b000;
+pub const FMAX: u32 = 0b001;
+/* funct3 of FCMP */
+pub const FLE: u32 = 0b000;
+pub const FLT: u32 = 0b001;
+pub const FEQ: u32 = 0b010;
+/* funct3 of FMV_X_F_FCLASS */
+pub const FMV_X_F: u32 = 0b000;
+pub const FCLASS: u32 = 0b001;
+/* rm (funct3) which selects the dynamic rounding mode in `frm` */
+pub const RM_DYN: u32 = 0b111;
+
+/* ---*---*---*---*--- RV64C Extension ---*---*---*---*--- */
+/* Quadrant (inst[1:0]) of 16-bit Inst, where `0b11` marks a 32-bit one */
+pub const C0: u16 = 0b00;
+pub const C1: u16 = 0b01;
+pub const C2: u16 = 0b10;
+/// Alignment (in bytes) of instructions, which are 16-bit aligned with `C` extension.
+///
+/// `C` can't be disabled, so no branch or jump can be misaligned (their offsets
+/// are even, and `JALR` clears bit 0 of the target). Only a fetch from a bad
+/// `xepc` raises `InstructionAddrMisaligned`.
+pub const IALIGN: u64 = 2;
+
+/* ---*---*---*---*--- User-level CSRs ---*---*---*---*--- */
+/// Floating-Point Accrued Exceptions.
+pub const FFLAGS: usize = 0x001;
+/// Floating-Point Dynamic Rounding Mode.
+pub const FRM: usize = 0x002;
+/// Floating-Point Control and Status Register (`frm` + `fflags`).
+pub const FCSR: usize = 0x003;
+
 /* ---*---*---*---*--- Machine-level CSRs ---*---*---*---*--- */
 pub const MHARTID: usize = 0xF14;
 /// Machine status register.
@@ -156,3 +294,61 @@ pub const MASK_STIP: u64 = 1 << 5;
 pub const MASK_MTIP: u64 = 1 << 7;
 pub const MASK_SEIP: u64 = 1 << 9;
 pub const MASK_MEIP: u64 = 1 << 11;
+/// Bits of `mip` driven by CLINT & PLIC, which are read-only to software
+pub const MASK_MIP_READ_ONLY: u64 = MASK_MSIP | MASK_MTIP | MASK_MEIP;
+
+/* ---*---*---*--- `MCAUSE` & `SCAUSE` field mask ---*---*---*--- */
+/// Set if the trap is caused by an interrupt
+pub const MASK_INTERRUPT: u64 = 1 << 63;
+
+/* ---*---*---*--- `MTVEC` & `STVEC` field mask ---*---*---*--- */
+pub const MASK_TVEC_MODE: u64 = 0b11;
+/// Asynchronous interrupts set `pc` to `BASE + 4 * cause`
+pub const TVEC_MODE_VECTORED: u64 = 0b01;
+
+/* ---*---*---*--- `fcsr` & `fflags` field mask ---*---*---*--- */
+/// Inexact
+pub const MASK_NX: u64 = 1 << 0;
+/// Underflow
+pub const MASK_UF: u64 = 1 << 1;
+/// Overflow
+pub const MASK_OF: u64 = 1 << 2;
+/// Divide by Zero
+pub const MASK_DZ: u64 = 1 << 3;
+/// Invalid Operation
+pub const MASK_NV: u64 = 1 << 4;
+pub const MASK_FFLAGS: u64 = 0b11111;
+pub const MASK_FRM: u64 = 0b111 << 5;
+
+/* ---*---*---*--- `mstatus.FS` field value ---*---*---*--- */
+pub const FS_OFF: u64 = 0b00 << 13;
+pub const FS_INITIAL: u64 = 0b01 << 13;
+pub const FS_CLEAN: u64 = 0b10 << 13;
+pub const FS_DIRTY: u64 = 0b11 << 13;
+
+/* ---*---*---*--- `satp` field mask & value ---*---*---*--- */
+pub const MASK_SATP_PPN: u64 = (1 << 44) - 1;
+pub const MASK_SATP_ASID: u64 = 0xFFFF << 44;
+pub const MASK_SATP_MODE: u64 = 0b1111 << 60;
+pub const SATP_MODE_BARE: u64 = 0;
+pub const SATP_MODE_SV39: u64 = 8;
+pub const SATP_MODE_SV48: u64 = 9;
+
+/* ---*---*---*--- Page Table Entry field mask ---*---*---*--- */
+pub const MASK_PTE_V: u64 = 1 << 0;
+pub const MASK_PTE_R: u64 = 1 << 1;
+pub const MASK_PTE_W: u64 = 1 << 2;
+pub const MASK_PTE_X: u64 = 1 << 3;
+pub const MASK_PTE_U: u64 = 1 << 4;
+pub const MASK_PTE_G: u64 = 1 << 5;
+pub const MASK_PTE_A: u64 = 1 << 6;
+pub const MASK_PTE_D: u64 = 1 << 7;
+pub const MASK_PTE_PPN: u64 = ((1 << 44) - 1) << 10;
+/// `N`, `PBMT` & reserved bits, none of which is supported
+pub const MASK_PTE_RESERVED: u64 = 0x3FF << 54;
+
+pub const PAGE_SIZE: u64 = 4096;
+/// Number of sets of each TLB
+pub const TLB_SETS: usize = 16;
+/// Associativity of each TLB
+pub const TLB_WAYS: usize = 4;
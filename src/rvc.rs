@@ -0,0 +1,249 @@
+//! # RV64C
+//!
+//! Every 16-bit instruction is expanded into its 32-bit equivalent, which
+//! is then executed as usual.
+
+use crate::param::*;
+
+/// Whether `inst` (in its lower 16 bits at least) is a 16-bit instruction
+pub fn is_compressed(inst: u32) -> bool {
+  inst & 0b11 != 0b11
+}
+
+/// `inst[hi:lo]`
+fn bits(inst: u16, hi: u32, lo: u32) -> u32 {
+  (inst as u32 >> lo) & ((1 << (hi - lo + 1)) - 1)
+}
+
+/// Sign-extend the lowest `width` bits of `x`
+fn sext(x: u32, width: u32) -> i32 {
+  ((x << (32 - width)) as i32) >> (32 - width)
+}
+
+/// `rd'`, `rs1'` & `rs2'` which only address `x8` ~ `x15`
+fn reg_prime(x: u32) -> u32 {
+  x + 8
+}
+
+fn r_type(opcode: u32, rd: u32, funct3: u32, rs1: u32, rs2: u32, funct7: u32) -> u32 {
+  (funct7 << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+}
+
+fn i_type(opcode: u32, rd: u32, funct3: u32, rs1: u32, imm: i32) -> u32 {
+  ((imm as u32 & 0xFFF) << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+}
+
+fn s_type(opcode: u32, funct3: u32, rs1: u32, rs2: u32, imm: i32) -> u32 {
+  let imm = imm as u32;
+  (((imm >> 5) & 0x7F) << 25)
+    | (rs2 << 20)
+    | (rs1 << 15)
+    | (funct3 << 12)
+    | ((imm & 0x1F) << 7)
+    | opcode
+}
+
+fn b_type(funct3: u32, rs1: u32, rs2: u32, imm: i32) -> u32 {
+  let imm = imm as u32;
+  (((imm >> 12) & 1) << 31)
+    | (((imm >> 5) & 0x3F) << 25)
+    | (rs2 << 20)
+    | (rs1 << 15)
+    | (funct3 << 12)
+    | (((imm >> 1) & 0xF) << 8)
+    | (((imm >> 11) & 1) << 7)
+    | BRANCH_OP
+}
+
+fn j_type(rd: u32, imm: i32) -> u32 {
+  let imm = imm as u32;
+  (((imm >> 20) & 1) << 31)
+    | (((imm >> 1) & 0x3FF) << 21)
+    | (((imm >> 11) & 1) << 20)
+    | (((imm >> 12) & 0xFF) << 12)
+    | (rd << 7)
+    | JAL
+}
+
+/// Expand a 16-bit instruction into its 32-bit equivalent.
+///
+/// Returns `None` for illegal or reserved encodings.
+pub fn expand(inst: u16) -> Option<u32> {
+  let funct3 = bits(inst, 15, 13);
+  // Full-width register fields of CR, CI & CSS formats
+  let rd = bits(inst, 11, 7);
+  let rs2 = bits(inst, 6, 2);
+  // Compressed register fields of CIW, CL, CS, CA & CB formats
+  let rs1_p = reg_prime(bits(inst, 9, 7));
+  let rd_p = reg_prime(bits(inst, 4, 2));
+  // imm[5] | imm[4:0] of CI format
+  let ci_imm = sext((bits(inst, 12, 12) << 5) | bits(inst, 6, 2), 6);
+  let shamt = (bits(inst, 12, 12) << 5) | bits(inst, 6, 2);
+  // uimm[5:3|7:6] of C.LD, C.SD, C.FLD & C.FSD
+  let cl_d_imm = ((bits(inst, 12, 10) << 3) | (bits(inst, 6, 5) << 6)) as i32;
+  // uimm[5:3|2|6] of C.LW & C.SW
+  let cl_w_imm =
+    ((bits(inst, 12, 10) << 3) | (bits(inst, 6, 6) << 2) | (bits(inst, 5, 5) << 6)) as i32;
+
+  let expanded = match (inst & 0b11, funct3) {
+    // C.ADDI4SPN
+    (C0, 0b000) => {
+      let imm = (bits(inst, 12, 11) << 4)
+        | (bits(inst, 10, 7) << 6)
+        | (bits(inst, 6, 6) << 2)
+        | (bits(inst, 5, 5) << 3);
+      if imm == 0 {
+        return None;
+      }
+      i_type(I_TYPE_OP, rd_p, ADDI, 2, imm as i32)
+    }
+    // C.FLD
+    (C0, 0b001) => i_type(LOAD_FP_OP, rd_p, FLD_FSD, rs1_p, cl_d_imm),
+    // C.LW
+    (C0, 0b010) => i_type(LOAD_OP, rd_p, LW, rs1_p, cl_w_imm),
+    // C.LD
+    (C0, 0b011) => i_type(LOAD_OP, rd_p, LD, rs1_p, cl_d_imm),
+    // C.FSD
+    (C0, 0b101) => s_type(STORE_FP_OP, FLD_FSD, rs1_p, rd_p, cl_d_imm),
+    // C.SW
+    (C0, 0b110) => s_type(STORE_OP, SW, rs1_p, rd_p, cl_w_imm),
+    // C.SD
+    (C0, 0b111) => s_type(STORE_OP, SD, rs1_p, rd_p, cl_d_imm),
+
+    // C.NOP & C.ADDI
+    (C1, 0b000) => i_type(I_TYPE_OP, rd, ADDI, rd, ci_imm),
+    // C.ADDIW
+    (C1, 0b001) => {
+      if rd == 0 {
+        return None;
+      }
+      i_type(I_W_TYPE_OP, rd, ADDIW, rd, ci_imm)
+    }
+    // C.LI
+    (C1, 0b010) => i_type(I_TYPE_OP, rd, ADDI, 0, ci_imm),
+    // C.ADDI16SP
+    (C1, 0b011) if rd == 2 => {
+      let imm = (bits(inst, 12, 12) << 9)
+        | (bits(inst, 6, 6) << 4)
+        | (bits(inst, 5, 5) << 6)
+        | (bits(inst, 4, 3) << 7)
+        | (bits(inst, 2, 2) << 5);
+      if imm == 0 {
+        return None;
+      }
+      i_type(I_TYPE_OP, 2, ADDI, 2, sext(imm, 10))
+    }
+    // C.LUI
+    (C1, 0b011) => {
+      if ci_imm == 0 {
+        return None;
+      }
+      ((ci_imm as u32) << 12) | (rd << 7) | LUI
+    }
+    (C1, 0b100) => match bits(inst, 11, 10) {
+      // C.SRLI
+      0b00 => i_type(I_TYPE_OP, rs1_p, SRLI_SRAI, rs1_p, shamt as i32),
+      // C.SRAI
+      0b01 => i_type(
+        I_TYPE_OP,
+        rs1_p,
+        SRLI_SRAI,
+        rs1_p,
+        (0b0100000 << 5) | shamt as i32,
+      ),
+      // C.ANDI
+      0b10 => i_type(I_TYPE_OP, rs1_p, ANDI, rs1_p, ci_imm),
+      _ => {
+        let (opcode, funct3, funct7) = match (bits(inst, 12, 12), bits(inst, 6, 5)) {
+          // C.SUB
+          (0, 0b00) => (R_TYPE_OP, ADD_SUB, 0b0100000),
+          // C.XOR
+          (0, 0b01) => (R_TYPE_OP, XOR, 0),
+          // C.OR
+          (0, 0b10) => (R_TYPE_OP, OR, 0),
+          // C.AND
+          (0, 0b11) => (R_TYPE_OP, AND, 0),
+          // C.SUBW
+          (1, 0b00) => (R_W_TYPE_OP, ADDW_SUBW, 0b0100000),
+          // C.ADDW
+          (1, 0b01) => (R_W_TYPE_OP, ADDW_SUBW, 0),
+          _ => return None,
+        };
+        r_type(opcode, rs1_p, funct3, rs1_p, rd_p, funct7)
+      }
+    },
+    // C.J
+    (C1, 0b101) => {
+      let imm = (bits(inst, 12, 12) << 11)
+        | (bits(inst, 11, 11) << 4)
+        | (bits(inst, 10, 9) << 8)
+        | (bits(inst, 8, 8) << 10)
+        | (bits(inst, 7, 7) << 6)
+        | (bits(inst, 6, 6) << 7)
+        | (bits(inst, 5, 3) << 1)
+        | (bits(inst, 2, 2) << 5);
+      j_type(0, sext(imm, 12))
+    }
+    // C.BEQZ & C.BNEZ
+    (C1, 0b110 | 0b111) => {
+      let imm = (bits(inst, 12, 12) << 8)
+        | (bits(inst, 11, 10) << 3)
+        | (bits(inst, 6, 5) << 6)
+        | (bits(inst, 4, 3) << 1)
+        | (bits(inst, 2, 2) << 5);
+      let funct3 = if funct3 == 0b110 { BEQ } else { BNE };
+      b_type(funct3, rs1_p, 0, sext(imm, 9))
+    }
+
+    // C.SLLI
+    (C2, 0b000) => i_type(I_TYPE_OP, rd, SLLI, rd, shamt as i32),
+    // C.FLDSP & C.LDSP
+    (C2, 0b001 | 0b011) => {
+      let imm = (bits(inst, 12, 12) << 5) | (bits(inst, 6, 5) << 3) | (bits(inst, 4, 2) << 6);
+      if funct3 == 0b001 {
+        i_type(LOAD_FP_OP, rd, FLD_FSD, 2, imm as i32)
+      } else if rd != 0 {
+        i_type(LOAD_OP, rd, LD, 2, imm as i32)
+      } else {
+        return None;
+      }
+    }
+    // C.LWSP
+    (C2, 0b010) => {
+      let imm = (bits(inst, 12, 12) << 5) | (bits(inst, 6, 4) << 2) | (bits(inst, 3, 2) << 6);
+      if rd == 0 {
+        return None;
+      }
+      i_type(LOAD_OP, rd, LW, 2, imm as i32)
+    }
+    (C2, 0b100) => match (bits(inst, 12, 12), rd, rs2) {
+      // C.JR
+      (0, 0, 0) => return None,
+      (0, _, 0) => i_type(JALR, 0, 0, rd, 0),
+      // C.MV
+      (0, _, _) => r_type(R_TYPE_OP, rd, ADD_SUB, 0, rs2, 0),
+      // C.EBREAK
+      (1, 0, 0) => i_type(E_TYPE_OP, 0, PRIV, 0, EBREAK as i32),
+      // C.JALR
+      (1, _, 0) => i_type(JALR, 1, 0, rd, 0),
+      // C.ADD
+      _ => r_type(R_TYPE_OP, rd, ADD_SUB, rd, rs2, 0),
+    },
+    // C.FSDSP & C.SDSP
+    (C2, 0b101 | 0b111) => {
+      let imm = ((bits(inst, 12, 10) << 3) | (bits(inst, 9, 7) << 6)) as i32;
+      if funct3 == 0b101 {
+        s_type(STORE_FP_OP, FLD_FSD, 2, rs2, imm)
+      } else {
+        s_type(STORE_OP, SD, 2, rs2, imm)
+      }
+    }
+    // C.SWSP
+    (C2, 0b110) => {
+      let imm = ((bits(inst, 12, 9) << 2) | (bits(inst, 8, 7) << 6)) as i32;
+      s_type(STORE_OP, SW, 2, rs2, imm)
+    }
+    _ => return None,
+  };
+  Some(expanded)
+}
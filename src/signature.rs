@@ -0,0 +1,55 @@
+//! # Signature
+//!
+//! Signature of a riscv-arch-test program, which RISCOF compares with that of a
+//! reference model
+
+use std::io::{self, Write};
+
+use crate::bus::Bus;
+use crate::elf::Elf;
+
+/// Symbols delimiting the signature, which excludes `end_signature`
+pub const BEGIN_SIGNATURE: &str = "begin_signature";
+pub const END_SIGNATURE: &str = "end_signature";
+/// Bytes per line of a signature, as expected by RISCOF
+pub const DEFAULT_GRANULARITY: usize = 4;
+/// Steps a program may take to exit, which is far more than any test of
+/// riscv-arch-test needs
+pub const DEFAULT_STEP_LIMIT: u64 = 100_000_000;
+
+/// The signature region `begin..end` of `elf`
+pub fn signature_range(elf: &Elf) -> Option<(u64, u64)> {
+  let begin = elf.symbol(BEGIN_SIGNATURE)?.addr;
+  let end = elf.symbol(END_SIGNATURE)?.addr;
+  Some((begin, end))
+}
+
+/// Write memory in `begin..end` to `out`, `granularity` bytes per line, each of
+/// which is a little-endian value in lowercase hexadecimal
+pub fn write_signature(
+  bus: &mut Bus,
+  begin: u64,
+  end: u64,
+  granularity: usize,
+  out: &mut dyn Write,
+) -> io::Result<()> {
+  if granularity == 0 || end < begin || !(end - begin).is_multiple_of(granularity as u64) {
+    return Err(io::Error::new(
+      io::ErrorKind::InvalidInput,
+      format!("signature 0x{begin:x}..0x{end:x} isn't made of {granularity}-byte lines"),
+    ));
+  }
+  let mut line = vec![0; granularity];
+  for addr in (begin..end).step_by(granularity) {
+    bus
+      .read_bytes(addr, &mut line)
+      .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    let hex: String = line
+      .iter()
+      .rev()
+      .map(|byte| format!("{byte:02x}"))
+      .collect();
+    writeln!(out, "{hex}")?;
+  }
+  Ok(())
+}
@@ -1,10 +1,17 @@
+use crate::config::MachineConfig;
 use crate::cpu::*;
+use crate::emulator::load_program;
+use crate::param::DRAM_BASE;
 use std::{
   fs::{self, File},
-  io::prelude::*,
+  io::{self, prelude::*},
+  path::Path,
   process::Command,
 };
 
+/// Label appended to the assembly of a test, where the test stops
+const TEST_END: &str = "__test_end";
+
 pub struct TestFramework;
 
 impl TestFramework {
@@ -19,7 +26,7 @@ impl TestFramework {
   }
   pub fn clean_temp_dir(test_name: &str) {
     Self::step_into_temp_dir();
-    for suffix in ["", ".s", ".bin"] {
+    for suffix in ["", ".s"] {
       fs::remove_file(
         std::env::current_dir()
           .unwrap()
@@ -49,7 +56,7 @@ impl TestFramework {
     let cc = "clang";
     let pieces: Vec<&str> = assembly.split('.').collect();
     let output = Command::new(cc)
-      .arg("-Wl,-Ttext=0x0")
+      .arg(format!("-Wl,-Ttext=0x{DRAM_BASE:x}"))
       .arg("-nostdlib")
       .arg("-march=rv64g")
       .arg("-mabi=lp64")
@@ -65,58 +72,51 @@ impl TestFramework {
       eprintln!("{}", raw_message);
     }
   }
-  pub fn generate_rv_binary(obj: &str) {
-    let objcopy = "llvm-objcopy";
-    let output = Command::new(objcopy)
-      .arg("-O")
-      .arg("binary")
-      .arg(obj)
-      .arg(obj.to_owned() + ".bin")
-      .output()
-      .expect("Failed to generate rv binary");
-    let raw_message = String::from_utf8_lossy(&output.stderr);
-    if raw_message.contains("error") {
-      eprintln!("{}", raw_message);
-    }
-  }
   pub fn test_from_asm(code: &str, test_name: &str, n_clock: u64) -> Result<Cpu, std::io::Error> {
+    Self::test_from_asm_with(code, test_name, n_clock, |_| {})
+  }
+  /// Same as `test_from_asm`, but `setup` is applied to `Cpu` (e.g. to map devices) before running
+  pub fn test_from_asm_with(
+    code: &str,
+    test_name: &str,
+    n_clock: u64,
+    setup: impl FnOnce(&mut Cpu),
+  ) -> Result<Cpu, std::io::Error> {
     Self::step_into_temp_dir();
 
     let filename = test_name.to_owned() + ".s";
     let mut file = File::create(&filename)?;
     file.write_all(code.as_bytes())?;
+    writeln!(file, "\n{TEST_END}:")?;
     Self::generate_rv_obj(&filename);
-    Self::generate_rv_binary(test_name);
 
-    let mut file_bin = File::open(test_name.to_owned() + ".bin")?;
-    let mut code = vec![];
-    file_bin.read_to_end(&mut code)?;
-    let mut cpu = Cpu::new(code);
+    let code = fs::read(test_name)?;
+    let (mut cpu, elf) = load_program(code, &MachineConfig::default(), Box::new(io::sink()))?;
+    let end = elf
+      .as_ref()
+      .and_then(|elf| elf.symbol(TEST_END))
+      .map(|symbol| symbol.addr)
+      .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no end of test"))?;
+    setup(&mut cpu);
 
-    for _i in 0..n_clock {
-      let inst = match cpu.fetch() {
-        Ok(inst) => {
-          if inst == 0 {
-            Self::clean_temp_dir(test_name);
-            return Ok(cpu);
-          }
-          inst
-        }
-        Err(e) => {
-          eprintln!("{e}\n");
-          break;
-        }
-      };
-      match cpu.execute(inst) {
-        Ok(new_pc) => cpu.pc = new_pc,
-        Err(e) => {
-          eprintln!("{e}\n");
-          break;
-        }
-      };
+    // Stop at the end of `code`, instead of running into the zeros beyond it
+    cpu.breakpoints.insert(end);
+    let reason = cpu.run(n_clock);
+    cpu.breakpoints.remove(&end);
+    if reason != StopReason::Breakpoint(end) {
+      eprintln!("{reason}\n");
     }
 
     Self::clean_temp_dir(test_name);
     Ok(cpu)
   }
+
+  /// Run an ELF executable (e.g. of riscv-tests) for at most `n_clock` steps, with HTIF
+  /// attached at its `tohost` symbol if any and the console discarded
+  pub fn test_from_elf(path: &Path, n_clock: u64) -> io::Result<(Cpu, StopReason)> {
+    let code = fs::read(path)?;
+    let (mut cpu, _) = load_program(code, &MachineConfig::default(), Box::new(io::sink()))?;
+    let reason = cpu.run(n_clock);
+    Ok((cpu, reason))
+  }
 }
@@ -0,0 +1,979 @@
+use rvemu_for_book::{
+  self,
+  cpu::{Cpu, Mode},
+  exception::Exception,
+  param::*,
+  utils::test_framework::TestFramework,
+};
+
+/// A device which records the last store, and counts clock cycles
+struct Recorder {
+  last_store: std::rc::Rc<std::cell::Cell<(u64, u64)>>,
+  ticks: u64,
+}
+
+impl rvemu_for_book::devices::Device for Recorder {
+  fn load(&mut self, offset: u64, _size: rvemu_for_book::dram::SizeType) -> Result<u64, Exception> {
+    match offset {
+      0 => Ok(self.ticks),
+      _ => Err(Exception::LoadAccessFault(offset)),
+    }
+  }
+  fn store(
+    &mut self,
+    offset: u64,
+    _size: rvemu_for_book::dram::SizeType,
+    value: u64,
+  ) -> Result<(), Exception> {
+    self.last_store.set((offset, value));
+    Ok(())
+  }
+  fn tick(&mut self) {
+    self.ticks += 1;
+  }
+}
+
+#[test]
+fn test_device_mapped_on_bus() {
+  use rvemu_for_book::dram::SizeType;
+
+  let last_store = std::rc::Rc::new(std::cell::Cell::new((0, 0)));
+  let mut cpu = Cpu::new(vec![]);
+  let recorder = Recorder {
+    last_store: last_store.clone(),
+    ticks: 0xFF,
+  };
+  cpu
+    .bus
+    .map("recorder", 0x4000_0000, 0x100, Box::new(recorder))
+    .unwrap();
+
+  cpu.bus.store(0x4000_0010, SizeType::Word, 42).unwrap();
+  assert_eq!(last_store.get(), (0x10, 42));
+  // `Bus` sign-extends for signed loads
+  assert_eq!(cpu.bus.load(0x4000_0000, SizeType::Byte).unwrap(), u64::MAX);
+  assert_eq!(cpu.bus.load_u(0x4000_0000, SizeType::Byte).unwrap(), 0xFF);
+  cpu.bus.tick();
+  assert_eq!(cpu.bus.load_u(0x4000_0000, SizeType::Half).unwrap(), 0x100);
+  // Device errors are reported at the accessed address
+  assert!(matches!(
+    cpu.bus.load(0x4000_0008, SizeType::Byte),
+    Err(Exception::LoadAccessFault(0x4000_0008))
+  ));
+  assert!(matches!(cpu.bus.fetch_inst(0x4000_0000), Ok(0x00)));
+}
+
+#[test]
+fn test_device_mapping_rejects_overlap() {
+  use rvemu_for_book::bus::BusError;
+
+  let mut cpu = Cpu::new(vec![]);
+  let recorder = || {
+    Box::new(Recorder {
+      last_store: Default::default(),
+      ticks: 0,
+    })
+  };
+  cpu.bus.map("a", 0x1000_0000, 0x1000, recorder()).unwrap();
+  cpu.bus.map("b", 0x1000_1000, 0x1000, recorder()).unwrap();
+
+  let err = cpu
+    .bus
+    .map("c", 0x1000_0800, 0x1000, recorder())
+    .unwrap_err();
+  assert_eq!(
+    err,
+    BusError::Overlap {
+      name: "c".to_owned(),
+      base: 0x1000_0800,
+      size: 0x1000,
+      mapped: "a".to_owned(),
+    }
+  );
+  assert_eq!(
+    err.to_string(),
+    "Overlap: `c` at 0x0000000010000800 with size 0x1000 overlaps with `a`"
+  );
+  let err = cpu.bus.map("d", DRAM_END, 2, recorder()).unwrap_err();
+  assert!(matches!(err, BusError::Overlap { mapped, .. } if mapped == "dram"));
+  let err = cpu.bus.map("e", u64::MAX, 2, recorder()).unwrap_err();
+  assert!(matches!(err, BusError::InvalidRange { .. }));
+  let err = cpu.bus.map("f", 0x2000_0000, 0, recorder()).unwrap_err();
+  assert!(matches!(err, BusError::InvalidRange { .. }));
+}
+
+#[test]
+fn test_uart_transmit_and_receive() {
+  use rvemu_for_book::devices::uart::{Uart, UartBackend, UartBuffer};
+
+  let code = "
+    li a0, 0x10000000
+    lla a1, msg
+  1:
+    lbu a2, 0(a1)
+    beqz a2, 3f
+  2:
+    lbu t0, 5(a0)
+    andi t0, t0, 0x20
+    beqz t0, 2b
+    sb a2, 0(a0)
+    addi a1, a1, 1
+    j 1b
+  3:
+    lbu t0, 5(a0)
+    andi t0, t0, 0x01
+    beqz t0, 3b
+    lbu s2, 0(a0)
+    lbu s3, 0(a0)
+    lbu s4, 5(a0)
+    j done
+  msg:
+    .string \"Hello, UART!\\n\"
+    .byte 0, 0 # keep `done` aligned
+  done:
+  ";
+  let buffer = UartBuffer::new();
+  buffer.push_input(b"ok");
+  let uart = Uart::new(UartBackend::Buffer(buffer.clone()));
+  let cpu =
+    TestFramework::test_from_asm_with(code, "test_uart_transmit_and_receive", 1000, |cpu| {
+      cpu
+        .bus
+        .map("uart", UART_BASE, UART_SIZE, Box::new(uart))
+        .unwrap();
+    })
+    .unwrap();
+  assert_eq!(buffer.output_string(), "Hello, UART!\n");
+  assert_eq!(cpu.observe_reg("s2"), b'o' as u64);
+  assert_eq!(cpu.observe_reg("s3"), b'k' as u64);
+  // `THRE` & `TEMT` without `DR`
+  assert_eq!(cpu.observe_reg("s4"), 0x60);
+}
+
+#[test]
+fn test_uart_registers_and_interrupt() {
+  use rvemu_for_book::devices::{
+    uart::{Uart, UartBackend, UartBuffer},
+    IrqLine,
+  };
+  use rvemu_for_book::dram::SizeType;
+
+  let buffer = UartBuffer::new();
+  let irq = IrqLine::new();
+  let uart = Uart::new(UartBackend::Buffer(buffer.clone())).with_irq(irq.clone());
+  let mut cpu = Cpu::new(vec![]);
+  cpu
+    .bus
+    .map("uart", UART_BASE, UART_SIZE, Box::new(uart))
+    .unwrap();
+  let read = |cpu: &mut Cpu, offset| cpu.bus.load_u(UART_BASE + offset, SizeType::Byte).unwrap();
+  let write = |cpu: &mut Cpu, offset, value| {
+    cpu
+      .bus
+      .store(UART_BASE + offset, SizeType::Byte, value)
+      .unwrap()
+  };
+
+  // No interrupt pending
+  assert_eq!(read(&mut cpu, 2), 0x01);
+  assert!(!irq.is_raised());
+
+  // `THR` empty interrupt is raised once enabled, and acknowledged by reading `IIR`
+  write(&mut cpu, 1, 0b10);
+  assert!(irq.is_raised());
+  assert_eq!(read(&mut cpu, 2), 0x02);
+  assert_eq!(read(&mut cpu, 2), 0x01);
+  assert!(!irq.is_raised());
+
+  // Received data available interrupt, which has a higher priority
+  write(&mut cpu, 1, 0b11);
+  buffer.push_input(b"xy");
+  cpu.bus.tick();
+  assert!(irq.is_raised());
+  assert_eq!(read(&mut cpu, 2), 0x04);
+  assert_eq!(read(&mut cpu, 5) & 0x01, 0x01);
+  assert_eq!(read(&mut cpu, 0), b'x' as u64);
+  // Reset RX FIFO, with FIFO enabled
+  write(&mut cpu, 2, 0b11);
+  assert_eq!(read(&mut cpu, 5) & 0x01, 0);
+  assert_eq!(read(&mut cpu, 2), 0xC1);
+
+  // Divisor latches are accessed with `LCR.DLAB` set
+  write(&mut cpu, 3, 0x83);
+  write(&mut cpu, 0, 0x12);
+  write(&mut cpu, 1, 0x34);
+  assert_eq!(read(&mut cpu, 0), 0x12);
+  assert_eq!(read(&mut cpu, 1), 0x34);
+  write(&mut cpu, 3, 0x03);
+  assert_eq!(read(&mut cpu, 1), 0b11);
+  assert_eq!(buffer.output(), b"");
+
+  write(&mut cpu, 7, 0x5A);
+  assert_eq!(read(&mut cpu, 7), 0x5A);
+}
+
+#[test]
+fn test_uart_file_backend() {
+  use rvemu_for_book::devices::uart::{Uart, UartBackend};
+  use rvemu_for_book::dram::SizeType;
+
+  let path = std::env::temp_dir().join(format!("rvemu_uart_{}.log", std::process::id()));
+  let file = std::fs::File::create(&path).unwrap();
+  let mut cpu = Cpu::new(vec![]);
+  let uart = Uart::new(UartBackend::File(file));
+  cpu
+    .bus
+    .map("uart", UART_BASE, UART_SIZE, Box::new(uart))
+    .unwrap();
+  for byte in b"log" {
+    cpu
+      .bus
+      .store(UART_BASE, SizeType::Byte, *byte as u64)
+      .unwrap();
+  }
+  // Nothing is ever received
+  assert_eq!(
+    cpu.bus.load_u(UART_BASE + 5, SizeType::Byte).unwrap() & 1,
+    0
+  );
+  assert_eq!(std::fs::read(&path).unwrap(), b"log");
+  std::fs::remove_file(path).unwrap();
+}
+
+/// Map a CLINT of a single hart, whose interrupt lines are connected to `mip`
+fn setup_clint(cpu: &mut Cpu, cycles_per_tick: u64) {
+  use rvemu_for_book::devices::clint::Clint;
+
+  let clint = Clint::new(1, cycles_per_tick);
+  cpu.connect_irq(MASK_MSIP, clint.software_irq(0));
+  cpu.connect_irq(MASK_MTIP, clint.timer_irq(0));
+  cpu
+    .bus
+    .map("clint", CLINT_BASE, CLINT_SIZE, Box::new(clint))
+    .unwrap();
+}
+
+#[test]
+fn test_clint_registers() {
+  use rvemu_for_book::devices::{clint::Clint, Device};
+  use rvemu_for_book::dram::SizeType;
+
+  let mut clint = Clint::new(2, 4);
+  let timer = clint.timer_irq(1);
+  let software = clint.software_irq(1);
+  // `mtime` increments once every 4 cycles
+  for _ in 0..10 {
+    clint.tick();
+  }
+  assert_eq!(clint.mtime(), 2);
+  assert_eq!(clint.load(0xBFF8, SizeType::DoubleWord).unwrap(), 2);
+
+  // `mtime` & `mtimecmp` can be accessed as 32-bit halves
+  clint.store(0xBFFC, SizeType::Word, 1).unwrap();
+  assert_eq!(clint.mtime(), (1 << 32) | 2);
+  assert_eq!(clint.load(0xBFFC, SizeType::Word).unwrap(), 1);
+  clint.store(0xBFF8, SizeType::DoubleWord, 0).unwrap();
+
+  // `mtimecmp` of hart 1
+  assert_eq!(clint.load(0x4008, SizeType::DoubleWord).unwrap(), u64::MAX);
+  clint.store(0x4008, SizeType::Word, 3).unwrap();
+  clint.store(0x400C, SizeType::Word, 0).unwrap();
+  assert_eq!(clint.load(0x4008, SizeType::DoubleWord).unwrap(), 3);
+  for _ in 0..8 {
+    clint.tick();
+  }
+  assert!(!timer.is_raised());
+  for _ in 0..4 {
+    clint.tick();
+  }
+  assert!(timer.is_raised());
+  assert!(!clint.timer_irq(0).is_raised());
+
+  // `msip` of hart 1, where only the lowest bit is writable
+  clint.store(0x4, SizeType::Word, 0xFF).unwrap();
+  assert!(software.is_raised());
+  assert_eq!(clint.load(0x4, SizeType::Word).unwrap(), 1);
+  clint.store(0x4, SizeType::Word, 0).unwrap();
+  assert!(!software.is_raised());
+
+  // Beyond the last hart
+  assert!(clint.load(0x8, SizeType::Word).is_err());
+  assert!(clint.store(0x4010, SizeType::DoubleWord, 0).is_err());
+}
+
+#[test]
+fn test_clint_timer_interrupt() {
+  let code = "
+    lla t0, handler
+    csrw mtvec, t0
+    li a0, 0x2000000
+    li t0, 0x4000
+    add t0, a0, t0
+    li t1, 50
+    sd t1, 0(t0)
+    li t0, 0x80
+    csrw mie, t0
+    csrsi mstatus, 0x8
+  1:
+    addi s1, s1, 1
+    j 1b
+  handler:
+    csrr s2, mcause
+    csrr s3, mstatus
+    li t0, 0xBFF8
+    add t0, a0, t0
+    ld s4, 0(t0)
+  ";
+  let cpu = TestFramework::test_from_asm_with(code, "test_clint_timer_interrupt", 1000, |cpu| {
+    setup_clint(cpu, 1)
+  })
+  .unwrap();
+  assert_eq!(cpu.observe_reg("s2"), MASK_INTERRUPT | 7);
+  assert!(cpu.observe_reg("s1") > 0);
+  // MPIE <- MIE, MIE <- 0
+  assert_eq!(cpu.observe_reg("s3") & (MASK_MPIE | MASK_MIE), MASK_MPIE);
+  assert!(cpu.observe_reg("s4") >= 50);
+  assert_eq!(cpu.mode, Mode::Machine);
+}
+
+#[test]
+fn test_clint_software_interrupt_vectored() {
+  let code = "
+    lla t0, vectors
+    ori t0, t0, 1
+    csrw mtvec, t0
+    li t0, 0x8
+    csrw mie, t0
+    csrsi mstatus, 0x8
+    li a0, 0x2000000
+    li t0, 1
+    sw t0, 0(a0)
+    addi s1, s1, 1
+    j done
+  vectors:
+    j bad
+    j bad
+    j bad
+    j msi
+  bad:
+    li s2, -1
+    j done
+  msi:
+    csrr s2, mcause
+    sw zero, 0(a0)
+    addi s3, s3, 1
+    mret
+  done:
+  ";
+  let cpu = TestFramework::test_from_asm_with(
+    code,
+    "test_clint_software_interrupt_vectored",
+    1000,
+    |cpu| setup_clint(cpu, 1),
+  )
+  .unwrap();
+  assert_eq!(cpu.observe_reg("s2"), MASK_INTERRUPT | 3);
+  // Taken exactly once, right after `msip` is written
+  assert_eq!(cpu.observe_reg("s3"), 1);
+  assert_eq!(cpu.observe_reg("s1"), 1);
+}
+
+#[test]
+fn test_clint_wfi_wakes_on_timer() {
+  let code = "
+    li a0, 0x2000000
+    li t0, 0x4000
+    add t0, a0, t0
+    li t1, 100
+    sd t1, 0(t0)
+    li t0, 0x80
+    csrw mie, t0
+    wfi
+    li t0, 0xBFF8
+    add t0, a0, t0
+    ld s1, 0(t0)
+  ";
+  let cpu = TestFramework::test_from_asm_with(code, "test_clint_wfi_wakes_on_timer", 1000, |cpu| {
+    setup_clint(cpu, 1)
+  })
+  .unwrap();
+  // Resumed without trapping, as `mstatus.MIE` is clear
+  assert!(!cpu.wfi);
+  assert!(cpu.observe_reg("s1") >= 100);
+  assert_eq!(cpu.csr.load(MCAUSE), 0);
+}
+
+#[test]
+fn test_plic_registers() {
+  use rvemu_for_book::devices::{plic::Plic, Device, IrqLine};
+  use rvemu_for_book::dram::SizeType;
+
+  let mut plic = Plic::new(1);
+  let (a, b, c) = (IrqLine::new(), IrqLine::new(), IrqLine::new());
+  plic.connect(1, a.clone());
+  plic.connect(2, b.clone());
+  plic.connect(33, c.clone());
+  let m_irq = plic.context_irq(0);
+  let s_irq = plic.context_irq(1);
+  let read = |plic: &mut Plic, offset| plic.load(offset, SizeType::Word).unwrap();
+  let write = |plic: &mut Plic, offset, value| plic.store(offset, SizeType::Word, value).unwrap();
+
+  // Priorities are 3-bit wide
+  write(&mut plic, 4, 0xFF);
+  assert_eq!(read(&mut plic, 4), 7);
+  write(&mut plic, 4, 1);
+  write(&mut plic, 8, 1);
+  write(&mut plic, 33 * 4, 2);
+
+  a.set(true);
+  b.set(true);
+  c.set(true);
+  plic.tick();
+  assert_eq!(read(&mut plic, 0x1000), 0b110);
+  assert_eq!(read(&mut plic, 0x1004), 0b10);
+  // Pending bits are read-only
+  write(&mut plic, 0x1000, 0);
+  assert_eq!(read(&mut plic, 0x1000), 0b110);
+  // Nothing is enabled yet
+  assert!(!m_irq.is_raised());
+  assert_eq!(read(&mut plic, 0x20_0004), 0);
+
+  // Source 0 can't be enabled
+  write(&mut plic, 0x2000, 0b111);
+  write(&mut plic, 0x2004, 0b10);
+  assert_eq!(read(&mut plic, 0x2000), 0b110);
+  assert!(m_irq.is_raised());
+  assert!(!s_irq.is_raised());
+
+  // The highest priority goes first, then the lowest ID
+  assert_eq!(read(&mut plic, 0x20_0004), 33);
+  assert_eq!(read(&mut plic, 0x20_0004), 1);
+  // Sources above the threshold only
+  write(&mut plic, 0x20_0000, 1);
+  assert_eq!(read(&mut plic, 0x20_0000), 1);
+  assert!(!m_irq.is_raised());
+  assert_eq!(read(&mut plic, 0x20_0004), 0);
+  write(&mut plic, 0x20_0000, 0);
+  assert_eq!(read(&mut plic, 0x20_0004), 2);
+  assert!(!m_irq.is_raised());
+
+  // A claimed source is not pending again until completed
+  plic.tick();
+  assert_eq!(read(&mut plic, 0x1000), 0);
+  write(&mut plic, 0x20_0004, 1);
+  assert_eq!(read(&mut plic, 0x1000), 0b10);
+  assert!(m_irq.is_raised());
+  // and it's not pending again if its line is lowered
+  b.set(false);
+  write(&mut plic, 0x20_0004, 2);
+  assert_eq!(read(&mut plic, 0x1000), 0b10);
+
+  // S-mode context of hart 0
+  write(&mut plic, 0x2080, 0b10);
+  assert!(s_irq.is_raised());
+  assert_eq!(read(&mut plic, 0x20_1004), 1);
+  assert!(!s_irq.is_raised());
+
+  // Only aligned 32-bit accesses within the mapped contexts
+  assert!(plic.load(0x4, SizeType::DoubleWord).is_err());
+  assert!(plic.load(0x6, SizeType::Word).is_err());
+  assert!(plic.load(0x2100, SizeType::Word).is_err());
+  assert!(plic.load(0x20_2000, SizeType::Word).is_err());
+}
+
+#[test]
+fn test_plic_uart_external_interrupt() {
+  use rvemu_for_book::devices::{
+    plic::Plic,
+    uart::{Uart, UartBackend, UartBuffer},
+    IrqLine,
+  };
+
+  let code = "
+    lla t0, handler
+    csrw mtvec, t0
+    li a0, 0xC000000
+    li t0, 1
+    sw t0, 40(a0)
+    li t0, 0x2000
+    add t0, a0, t0
+    li t1, 0x400
+    sw t1, 0(t0)
+    li t0, 0x800
+    csrw mie, t0
+    csrsi mstatus, 0x8
+    li a1, 0x10000000
+    li t0, 1
+    sb t0, 1(a1)
+  1:
+    addi s1, s1, 1
+    j 1b
+  handler:
+    csrr s4, mcause
+    li t0, 0x200004
+    add t0, a0, t0
+    lw s2, 0(t0)
+    lbu s3, 0(a1)
+    sw s2, 0(t0)
+  ";
+  let buffer = UartBuffer::new();
+  buffer.push_input(b"A");
+  let cpu =
+    TestFramework::test_from_asm_with(code, "test_plic_uart_external_interrupt", 1000, |cpu| {
+      let mut plic = Plic::new(1);
+      let irq = IrqLine::new();
+      plic.connect(UART_IRQ, irq.clone());
+      let uart = Uart::new(UartBackend::Buffer(buffer.clone())).with_irq(irq);
+      cpu.connect_irq(MASK_MEIP, plic.context_irq(0));
+      cpu.connect_irq(MASK_SEIP, plic.context_irq(1));
+      cpu
+        .bus
+        .map("uart", UART_BASE, UART_SIZE, Box::new(uart))
+        .unwrap();
+      cpu
+        .bus
+        .map("plic", PLIC_BASE, PLIC_SIZE, Box::new(plic))
+        .unwrap();
+    })
+    .unwrap();
+  assert_eq!(cpu.observe_reg("s4"), MASK_INTERRUPT | 11);
+  assert_eq!(cpu.observe_reg("s2"), UART_IRQ as u64);
+  assert_eq!(cpu.observe_reg("s3"), b'A' as u64);
+  // The interrupt is gone once the data is read & the source is completed
+  assert_eq!(cpu.csr.load(MIP) & (MASK_MEIP | MASK_SEIP), 0);
+}
+
+/// Guest memory used by `virtio_request`
+const VIRTQ_DESC: u64 = DRAM_BASE + 0x1_0000;
+const VIRTQ_AVAIL: u64 = DRAM_BASE + 0x1_1000;
+const VIRTQ_USED: u64 = DRAM_BASE + 0x1_2000;
+const VIRTIO_HEADER: u64 = DRAM_BASE + 0x1_3000;
+const VIRTIO_STATUS: u64 = DRAM_BASE + 0x1_3100;
+const VIRTIO_DATA: u64 = DRAM_BASE + 0x1_4000;
+
+/// Create a disk image of `n_sectors`, where every byte of sector `i` is `i`
+fn create_disk_image(name: &str, n_sectors: u8) -> std::path::PathBuf {
+  let path = std::env::temp_dir().join(format!("rvemu_{}_{}.img", name, std::process::id()));
+  let image: Vec<u8> = (0..n_sectors).flat_map(|i| [i; 512]).collect();
+  std::fs::write(&path, image).unwrap();
+  path
+}
+
+/// Map a virtio block device, then initialize it & its queue like a driver
+fn setup_virtio_blk(
+  cpu: &mut Cpu,
+  disk: rvemu_for_book::devices::virtio_blk::DiskImage,
+) -> rvemu_for_book::devices::IrqLine {
+  use rvemu_for_book::devices::{virtio_blk::VirtioBlk, IrqLine};
+  use rvemu_for_book::dram::SizeType;
+
+  let irq = IrqLine::new();
+  let virtio = VirtioBlk::new(disk).with_irq(irq.clone());
+  cpu
+    .bus
+    .map("virtio-blk", VIRTIO_BASE, VIRTIO_SIZE, Box::new(virtio))
+    .unwrap();
+  let write = |cpu: &mut Cpu, offset, value| {
+    cpu
+      .bus
+      .store(VIRTIO_BASE + offset, SizeType::Word, value)
+      .unwrap()
+  };
+  // ACKNOWLEDGE | DRIVER, then FEATURES_OK with `VIRTIO_F_VERSION_1`
+  write(cpu, 0x70, 0b11);
+  write(cpu, 0x24, 1);
+  write(cpu, 0x20, 1);
+  write(cpu, 0x70, 0b1011);
+  assert_eq!(
+    cpu.bus.load_u(VIRTIO_BASE + 0x70, SizeType::Word).unwrap(),
+    0b1011
+  );
+  write(cpu, 0x30, 0);
+  write(cpu, 0x38, 8);
+  write(cpu, 0x80, VIRTQ_DESC & 0xFFFF_FFFF);
+  write(cpu, 0x84, VIRTQ_DESC >> 32);
+  write(cpu, 0x90, VIRTQ_AVAIL & 0xFFFF_FFFF);
+  write(cpu, 0x94, VIRTQ_AVAIL >> 32);
+  write(cpu, 0xA0, VIRTQ_USED & 0xFFFF_FFFF);
+  write(cpu, 0xA4, VIRTQ_USED >> 32);
+  write(cpu, 0x44, 1);
+  // DRIVER_OK
+  write(cpu, 0x70, 0b1111);
+  irq
+}
+
+/// Submit a block request whose data buffer is at `VIRTIO_DATA`, and return
+/// `(status, length in the used ring)`
+fn virtio_request(cpu: &mut Cpu, request_type: u32, sector: u64, data_len: u32) -> (u8, u64) {
+  // Writes are device-readable, and others are device-writable
+  let data_flags = if request_type == 1 { 0b01 } else { 0b11 };
+  let descs = [
+    (VIRTIO_HEADER, 16, 0b01, 1),
+    (VIRTIO_DATA, data_len, data_flags, 2),
+    (VIRTIO_STATUS, 1, 0b10, 0),
+  ];
+  virtio_request_with(cpu, request_type, sector, &descs)
+}
+
+/// Submit a block request made of `(addr, len, flags, next)` descriptors
+fn virtio_request_with(
+  cpu: &mut Cpu,
+  request_type: u32,
+  sector: u64,
+  descs: &[(u64, u32, u64, u64)],
+) -> (u8, u64) {
+  use rvemu_for_book::dram::SizeType;
+
+  let bus = &mut cpu.bus;
+  bus
+    .store(VIRTIO_HEADER, SizeType::Word, request_type as u64)
+    .unwrap();
+  bus
+    .store(VIRTIO_HEADER + 8, SizeType::DoubleWord, sector)
+    .unwrap();
+  bus.store(VIRTIO_STATUS, SizeType::Byte, 0xFF).unwrap();
+  for (i, &(addr, len, flags, next)) in descs.iter().enumerate() {
+    let desc = VIRTQ_DESC + 16 * i as u64;
+    bus.store(desc, SizeType::DoubleWord, addr).unwrap();
+    bus.store(desc + 8, SizeType::Word, len as u64).unwrap();
+    bus.store(desc + 12, SizeType::Half, flags).unwrap();
+    bus.store(desc + 14, SizeType::Half, next).unwrap();
+  }
+  let avail_idx = bus.load_u(VIRTQ_AVAIL + 2, SizeType::Half).unwrap();
+  bus
+    .store(VIRTQ_AVAIL + 4 + 2 * (avail_idx % 8), SizeType::Half, 0)
+    .unwrap();
+  bus
+    .store(VIRTQ_AVAIL + 2, SizeType::Half, avail_idx + 1)
+    .unwrap();
+  bus.store(VIRTIO_BASE + 0x50, SizeType::Word, 0).unwrap();
+  bus.tick();
+
+  assert_eq!(
+    bus.load_u(VIRTQ_USED + 2, SizeType::Half).unwrap(),
+    avail_idx + 1
+  );
+  let elem = VIRTQ_USED + 4 + 8 * (avail_idx % 8);
+  assert_eq!(bus.load_u(elem, SizeType::Word).unwrap(), 0);
+  let len = bus.load_u(elem + 4, SizeType::Word).unwrap();
+  let status = bus.load_u(VIRTIO_STATUS, SizeType::Byte).unwrap() as u8;
+  (status, len)
+}
+
+#[test]
+fn test_virtio_blk_registers() {
+  use rvemu_for_book::devices::virtio_blk::{DiskImage, DiskMode};
+  use rvemu_for_book::dram::SizeType;
+
+  let path = create_disk_image("virtio_registers", 4);
+  let mut cpu = Cpu::new(vec![]);
+  let irq = setup_virtio_blk(
+    &mut cpu,
+    DiskImage::open(&path, DiskMode::ReadOnly).unwrap(),
+  );
+  let mut read = |offset| {
+    cpu
+      .bus
+      .load_u(VIRTIO_BASE + offset, SizeType::Word)
+      .unwrap()
+  };
+  assert_eq!(read(0x000), 0x7472_6976);
+  assert_eq!(read(0x004), 2);
+  assert_eq!(read(0x008), 2);
+  assert_eq!(read(0x034), 128);
+  assert_eq!(read(0x044), 1);
+  assert_eq!(read(0x060), 0);
+  assert!(!irq.is_raised());
+  // `VIRTIO_BLK_F_RO` & `VIRTIO_BLK_F_FLUSH`, then `VIRTIO_F_VERSION_1`
+  assert_eq!(read(0x010), (1 << 5) | (1 << 9));
+  cpu
+    .bus
+    .store(VIRTIO_BASE + 0x14, SizeType::Word, 1)
+    .unwrap();
+  assert_eq!(
+    cpu.bus.load_u(VIRTIO_BASE + 0x10, SizeType::Word).unwrap(),
+    1
+  );
+  // Capacity in sectors, which can be read in any size
+  assert_eq!(
+    cpu
+      .bus
+      .load_u(VIRTIO_BASE + 0x100, SizeType::DoubleWord)
+      .unwrap(),
+    4
+  );
+  assert_eq!(
+    cpu.bus.load_u(VIRTIO_BASE + 0x100, SizeType::Byte).unwrap(),
+    4
+  );
+  // Registers are 32-bit wide
+  assert!(cpu.bus.load_u(VIRTIO_BASE, SizeType::DoubleWord).is_err());
+
+  // Features not offered are rejected
+  cpu
+    .bus
+    .store(VIRTIO_BASE + 0x70, SizeType::Word, 0)
+    .unwrap();
+  cpu
+    .bus
+    .store(VIRTIO_BASE + 0x24, SizeType::Word, 0)
+    .unwrap();
+  cpu
+    .bus
+    .store(VIRTIO_BASE + 0x20, SizeType::Word, 1 << 28)
+    .unwrap();
+  cpu
+    .bus
+    .store(VIRTIO_BASE + 0x70, SizeType::Word, 0b1011)
+    .unwrap();
+  assert_eq!(
+    cpu.bus.load_u(VIRTIO_BASE + 0x70, SizeType::Word).unwrap(),
+    0b0011
+  );
+  // and the queue is gone after the reset
+  assert_eq!(
+    cpu.bus.load_u(VIRTIO_BASE + 0x44, SizeType::Word).unwrap(),
+    0
+  );
+  std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_virtio_blk_requests() {
+  use rvemu_for_book::devices::virtio_blk::{DiskImage, DiskMode};
+  use rvemu_for_book::dram::SizeType;
+
+  let path = create_disk_image("virtio_requests", 4);
+  let mut cpu = Cpu::new(vec![]);
+  let irq = setup_virtio_blk(
+    &mut cpu,
+    DiskImage::open(&path, DiskMode::ReadWrite).unwrap(),
+  );
+  let mut data = [0; 512];
+
+  // Read sector 2
+  assert_eq!(virtio_request(&mut cpu, 0, 2, 512), (0, 513));
+  cpu.bus.read_bytes(VIRTIO_DATA, &mut data).unwrap();
+  assert_eq!(data, [2; 512]);
+  assert!(irq.is_raised());
+  assert_eq!(
+    cpu.bus.load_u(VIRTIO_BASE + 0x60, SizeType::Word).unwrap(),
+    1
+  );
+  cpu
+    .bus
+    .store(VIRTIO_BASE + 0x64, SizeType::Word, 1)
+    .unwrap();
+  assert!(!irq.is_raised());
+
+  // Write sector 1, then flush it
+  cpu.bus.write_bytes(VIRTIO_DATA, &[0xAB; 512]).unwrap();
+  assert_eq!(virtio_request(&mut cpu, 1, 1, 512), (0, 1));
+  assert_eq!(virtio_request(&mut cpu, 4, 0, 0), (0, 1));
+  let image = std::fs::read(&path).unwrap();
+  assert_eq!(image[512..1024], [0xAB; 512]);
+  assert_eq!(image[1024..1536], [2; 512]);
+
+  // Device ID
+  assert_eq!(virtio_request(&mut cpu, 8, 0, 20), (0, 21));
+  cpu.bus.read_bytes(VIRTIO_DATA, &mut data[..20]).unwrap();
+  assert!(data.starts_with(b"rvemu-virtio-blk\0\0\0\0"));
+
+  // Beyond the end of disk
+  assert_eq!(virtio_request(&mut cpu, 0, 4, 512).0, 1);
+  assert_eq!(virtio_request(&mut cpu, 0, 3, 1024).0, 1);
+  // Unsupported request
+  assert_eq!(virtio_request(&mut cpu, 11, 0, 0).0, 2);
+  std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_virtio_blk_read_only_and_snapshot() {
+  use rvemu_for_book::devices::virtio_blk::{DiskImage, DiskMode};
+
+  let path = create_disk_image("virtio_snapshot", 2);
+  let mut data = [0; 512];
+
+  let mut cpu = Cpu::new(vec![]);
+  setup_virtio_blk(
+    &mut cpu,
+    DiskImage::open(&path, DiskMode::ReadOnly).unwrap(),
+  );
+  cpu.bus.write_bytes(VIRTIO_DATA, &[0xCD; 512]).unwrap();
+  assert_eq!(virtio_request(&mut cpu, 1, 0, 512).0, 1);
+  assert_eq!(virtio_request(&mut cpu, 0, 0, 512), (0, 513));
+  cpu.bus.read_bytes(VIRTIO_DATA, &mut data).unwrap();
+  assert_eq!(data, [0; 512]);
+
+  // Writes are visible to the guest, but never reach the image file
+  let mut cpu = Cpu::new(vec![]);
+  setup_virtio_blk(
+    &mut cpu,
+    DiskImage::open(&path, DiskMode::CopyOnWrite).unwrap(),
+  );
+  cpu.bus.write_bytes(VIRTIO_DATA, &[0xCD; 512]).unwrap();
+  assert_eq!(virtio_request(&mut cpu, 1, 1, 512), (0, 1));
+  cpu.bus.write_bytes(VIRTIO_DATA, &[0; 512]).unwrap();
+  assert_eq!(virtio_request(&mut cpu, 0, 1, 512), (0, 513));
+  cpu.bus.read_bytes(VIRTIO_DATA, &mut data).unwrap();
+  assert_eq!(data, [0xCD; 512]);
+  let image = std::fs::read(&path).unwrap();
+  assert_eq!(image[512..], [1; 512]);
+  std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_virtio_blk_malformed_requests() {
+  use rvemu_for_book::devices::virtio_blk::{DiskImage, DiskMode};
+  use rvemu_for_book::dram::SizeType;
+
+  let path = create_disk_image("virtio_malformed", 2);
+  let mut cpu = Cpu::new(vec![]);
+  setup_virtio_blk(
+    &mut cpu,
+    DiskImage::open(&path, DiskMode::ReadWrite).unwrap(),
+  );
+
+  // Requests larger than the limit fail before their buffers are touched
+  assert_eq!(virtio_request(&mut cpu, 0, 0, 0xFFFF_FFFF), (1, 1));
+  assert_eq!(virtio_request(&mut cpu, 1, 0, 0x8000_0000), (1, 1));
+  // A zero-length status buffer is rejected without writing anything
+  cpu
+    .bus
+    .store(VIRTIO_STATUS - 1, SizeType::Byte, 0xEE)
+    .unwrap();
+  let descs = [
+    (VIRTIO_HEADER, 16, 0b01, 1),
+    (VIRTIO_DATA, 512, 0b11, 2),
+    (VIRTIO_STATUS, 0, 0b10, 0),
+  ];
+  assert_eq!(virtio_request_with(&mut cpu, 0, 0, &descs), (0xFF, 0));
+  assert_eq!(
+    cpu.bus.load_u(VIRTIO_STATUS - 1, SizeType::Byte).unwrap(),
+    0xEE
+  );
+  assert_eq!(
+    cpu.bus.load_u(VIRTIO_BASE + 0x70, SizeType::Word).unwrap(),
+    0b1111
+  );
+
+  // A buffer wrapping around the address space breaks the device
+  let descs = [
+    (VIRTIO_HEADER, 16, 0b01, 1),
+    (u64::MAX - 0xFF, 512, 0b11, 2),
+    (VIRTIO_STATUS, 1, 0b10, 0),
+  ];
+  let avail_idx = cpu.bus.load_u(VIRTQ_AVAIL + 2, SizeType::Half).unwrap();
+  for (i, (addr, len, flags, next)) in descs.into_iter().enumerate() {
+    let desc = VIRTQ_DESC + 16 * i as u64;
+    cpu.bus.store(desc, SizeType::DoubleWord, addr).unwrap();
+    cpu.bus.store(desc + 8, SizeType::Word, len).unwrap();
+    cpu.bus.store(desc + 12, SizeType::Half, flags).unwrap();
+    cpu.bus.store(desc + 14, SizeType::Half, next).unwrap();
+  }
+  cpu
+    .bus
+    .store(VIRTQ_AVAIL + 2, SizeType::Half, avail_idx + 1)
+    .unwrap();
+  cpu
+    .bus
+    .store(VIRTIO_BASE + 0x50, SizeType::Word, 0)
+    .unwrap();
+  cpu.bus.tick();
+  assert_eq!(
+    cpu.bus.load_u(VIRTIO_BASE + 0x70, SizeType::Word).unwrap(),
+    0b100_1111
+  );
+
+  // and so does a ring wrapping around it
+  let mut cpu = Cpu::new(vec![]);
+  setup_virtio_blk(
+    &mut cpu,
+    DiskImage::open(&path, DiskMode::ReadWrite).unwrap(),
+  );
+  for (offset, value) in [(0x80, 0xFFFF_FFF0), (0x84, 0xFFFF_FFFF)] {
+    cpu
+      .bus
+      .store(VIRTIO_BASE + offset, SizeType::Word, value)
+      .unwrap();
+  }
+  cpu.bus.store(VIRTQ_AVAIL + 2, SizeType::Half, 1).unwrap();
+  cpu
+    .bus
+    .store(VIRTIO_BASE + 0x50, SizeType::Word, 0)
+    .unwrap();
+  cpu.bus.tick();
+  assert_eq!(
+    cpu.bus.load_u(VIRTIO_BASE + 0x70, SizeType::Word).unwrap(),
+    0b100_1111
+  );
+  std::fs::remove_file(path).unwrap();
+}
+
+/// Console shared with a device, to inspect what the guest writes
+#[derive(Clone, Default)]
+struct SharedConsole(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl std::io::Write for SharedConsole {
+  fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+    self.0.lock().unwrap().extend_from_slice(buf);
+    Ok(buf.len())
+  }
+  fn flush(&mut self) -> std::io::Result<()> {
+    Ok(())
+  }
+}
+
+#[test]
+fn test_htif() {
+  use rvemu_for_book::{cpu::StopReason, devices::htif::Htif, dram::SizeType};
+
+  let tohost = DRAM_BASE + 0x1000;
+  let console = SharedConsole::default();
+  let code = "
+    auipc a0, 1
+    li a1, (1 << 56) | (1 << 48) | 0x41
+    sd a1, 0(a0)
+    addi a1, a0, 0x100
+    sd a1, 0(a0)
+    addi a1, a0, 0x180
+    sd a1, 0(a0)
+    addi a2, x0, 1
+  ";
+  let mut cpu = TestFramework::test_from_asm_with(code, "test_htif", 32, |cpu| {
+    let htif = Htif::new(tohost, Some(tohost + 0x40), Box::new(console.clone()));
+    cpu.bus.attach_htif(htif);
+    // write(1, "hi\n", 3), then exit(7)
+    for (addr, args) in [(0x100, [64, 1, tohost + 0x200, 3]), (0x180, [93, 7, 0, 0])] {
+      let bytes: Vec<u8> = args.iter().flat_map(|arg| arg.to_le_bytes()).collect();
+      cpu.bus.write_bytes(tohost + addr, &bytes).unwrap();
+    }
+    cpu.bus.write_bytes(tohost + 0x200, b"hi\n").unwrap();
+  })
+  .unwrap();
+  assert_eq!(console.0.lock().unwrap().as_slice(), b"Ahi\n");
+  // Stopped right after the exit
+  assert_eq!(cpu.observe_reg("a2"), 0);
+  assert_eq!(
+    cpu
+      .bus
+      .load_u(tohost + 0x100, SizeType::DoubleWord)
+      .unwrap(),
+    3
+  );
+  assert_eq!(cpu.bus.load_u(tohost, SizeType::DoubleWord).unwrap(), 0);
+  assert_eq!(
+    cpu.bus.load_u(tohost + 0x40, SizeType::DoubleWord).unwrap(),
+    1
+  );
+
+  // An odd payload (as riscv-tests writes) exits with `payload >> 1`.
+  // sd a1, 0(a0)
+  let mut cpu = Cpu::new(0x00B5_3023u32.to_le_bytes().to_vec());
+  cpu
+    .bus
+    .attach_htif(Htif::new(tohost, None, Box::new(SharedConsole::default())));
+  cpu.gpr[10] = tohost;
+  cpu.gpr[11] = (21 << 1) | 1;
+  assert_eq!(cpu.run(10), StopReason::Exit(21));
+}
@@ -0,0 +1,445 @@
+use rvemu_for_book::{self, cpu::Cpu, exception::Exception, param::*};
+
+#[test]
+fn test_dram_bounds() {
+  use rvemu_for_book::dram::SizeType;
+
+  let mut cpu = Cpu::new(vec![]);
+  cpu
+    .bus
+    .store(DRAM_END - 7, SizeType::DoubleWord, 42)
+    .unwrap();
+  assert_eq!(
+    cpu.bus.load_u(DRAM_END - 7, SizeType::DoubleWord).unwrap(),
+    42
+  );
+  // Neither below `DRAM_BASE` nor across `DRAM_END` is backed by DRAM
+  assert!(matches!(
+    cpu.bus.load_u(0x100, SizeType::Byte),
+    Err(Exception::LoadAccessFault(0x100))
+  ));
+  assert!(matches!(
+    cpu.bus.load_u(DRAM_END - 3, SizeType::DoubleWord),
+    Err(Exception::LoadAccessFault(_))
+  ));
+  assert!(matches!(
+    cpu.bus.store(DRAM_END + 1, SizeType::Byte, 0),
+    Err(Exception::StoreAMOAccessFault(_))
+  ));
+}
+
+#[test]
+fn test_machine_config() {
+  use rvemu_for_book::{
+    bus::BusError,
+    config::MachineConfig,
+    dram::{Dram, SizeType},
+  };
+
+  let config = MachineConfig::default()
+    .with_dram(0x4000_0000, 0x1_0000)
+    .with_rom("boot", 0x1000, vec![0x13, 0x05, 0xA0, 0x02])
+    .with_sram("sram", 0x2000_0000, 0x100);
+  let mut cpu = Cpu::with_config(&config, vec![0x93, 0x00, 0x10, 0x00]).unwrap();
+  assert_eq!(cpu.pc, 0x4000_0000);
+  assert_eq!(cpu.observe_reg("sp"), 0x4001_0000);
+  // The stack pointer is 16-byte aligned, no matter the size of DRAM
+  let odd = MachineConfig::default().with_dram(0x4000_0000, 0x1_0009);
+  let odd_cpu = Cpu::with_config(&odd, vec![]).unwrap();
+  assert_eq!(odd_cpu.observe_reg("sp"), 0x4001_0000);
+  assert_eq!(cpu.fetch().unwrap(), 0x0010_0093);
+  assert!(matches!(
+    cpu.bus.load_u(DRAM_BASE, SizeType::Byte),
+    Err(Exception::LoadAccessFault(DRAM_BASE))
+  ));
+  // ROM is read-only
+  assert_eq!(cpu.bus.load_u(0x1000, SizeType::Word).unwrap(), 0x02A0_0513);
+  assert!(matches!(
+    cpu.bus.store(0x1000, SizeType::Byte, 0),
+    Err(Exception::StoreAMOAccessFault(0x1000))
+  ));
+  cpu.bus.store(0x2000_00F8, SizeType::DoubleWord, 7).unwrap();
+  assert_eq!(
+    cpu.bus.load_u(0x2000_00F8, SizeType::DoubleWord).unwrap(),
+    7
+  );
+  assert!(cpu.bus.load_u(0x2000_0100, SizeType::Byte).is_err());
+
+  let overlapping = MachineConfig::default().with_sram("sram", DRAM_BASE - 0x10, 0x20);
+  assert!(matches!(
+    Cpu::with_config(&overlapping, vec![]),
+    Err(BusError::Overlap { mapped, .. }) if mapped == "dram"
+  ));
+  let empty = MachineConfig::default().with_dram(DRAM_BASE, 0);
+  assert!(matches!(
+    Cpu::with_config(&empty, vec![]),
+    Err(BusError::InvalidRange { .. })
+  ));
+  // A raw image larger than DRAM (e.g. of `--memory`) is an error rather than a panic
+  let small = MachineConfig::default().with_dram(DRAM_BASE, 0x10);
+  assert!(matches!(
+    Cpu::with_config(&small, vec![0x13; 0x11]),
+    Err(BusError::TooLarge {
+      size: 0x10,
+      len: 0x11,
+      ..
+    })
+  ));
+  assert_eq!(
+    Dram::with_size(0x10, vec![0x13; 0x11]).err(),
+    Some(BusError::TooLarge {
+      name: "dram".to_owned(),
+      size: 0x10,
+      len: 0x11,
+    })
+  );
+}
+
+#[test]
+fn test_sparse_dram() {
+  use rvemu_for_book::{config::MachineConfig, dram::SizeType};
+
+  // 16 GiB of DRAM only costs the pages written to
+  let config = MachineConfig::default().with_dram(DRAM_BASE, 16 << 30);
+  let mut cpu = Cpu::with_config(&config, vec![0x13, 0, 0, 0]).unwrap();
+  assert_eq!(cpu.bus.resident_bytes(), PAGE_SIZE);
+  let far = DRAM_BASE + (12 << 30);
+  assert_eq!(cpu.bus.load_u(far, SizeType::DoubleWord).unwrap(), 0);
+  cpu.bus.store(far, SizeType::DoubleWord, 0).unwrap();
+  assert_eq!(cpu.bus.resident_bytes(), PAGE_SIZE);
+  // A store across a page boundary allocates both pages
+  cpu
+    .bus
+    .store(
+      far + PAGE_SIZE - 4,
+      SizeType::DoubleWord,
+      0x1122_3344_5566_7788,
+    )
+    .unwrap();
+  assert_eq!(cpu.bus.resident_bytes(), 3 * PAGE_SIZE);
+  assert_eq!(
+    cpu.bus.load_u(far + PAGE_SIZE - 2, SizeType::Word).unwrap(),
+    0x3344_5566
+  );
+  assert_eq!(cpu.bus.load_u(DRAM_BASE, SizeType::Word).unwrap(), 0x13);
+}
+
+/// Build a RISC-V ELF64 executable with `(paddr, data, mem_size)` segments and
+/// `(name, addr, size)` function symbols
+fn build_elf(entry: u64, segments: &[(u64, &[u8], u64)], symbols: &[(&str, u64, u64)]) -> Vec<u8> {
+  let phoff = 64;
+  let mut data_offset = phoff + 56 * segments.len();
+  let mut elf = vec![0; data_offset];
+  elf[..8].copy_from_slice(&[0x7F, b'E', b'L', b'F', 2, 1, 1, 0]);
+  elf[16..18].copy_from_slice(&2u16.to_le_bytes());
+  elf[18..20].copy_from_slice(&243u16.to_le_bytes());
+  elf[24..32].copy_from_slice(&entry.to_le_bytes());
+  elf[32..40].copy_from_slice(&(phoff as u64).to_le_bytes());
+  elf[54..56].copy_from_slice(&56u16.to_le_bytes());
+  elf[56..58].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+  for (i, (paddr, data, mem_size)) in segments.iter().enumerate() {
+    let ph = phoff + 56 * i;
+    elf[ph..ph + 4].copy_from_slice(&1u32.to_le_bytes());
+    elf[ph + 8..ph + 16].copy_from_slice(&(data_offset as u64).to_le_bytes());
+    elf[ph + 16..ph + 24].copy_from_slice(&paddr.to_le_bytes());
+    elf[ph + 24..ph + 32].copy_from_slice(&paddr.to_le_bytes());
+    elf[ph + 32..ph + 40].copy_from_slice(&(data.len() as u64).to_le_bytes());
+    elf[ph + 40..ph + 48].copy_from_slice(&mem_size.to_le_bytes());
+    data_offset += data.len();
+  }
+  for (_, data, _) in segments {
+    elf.extend_from_slice(data);
+  }
+
+  // String table, then symbol table (with the null symbol first)
+  let strtab_offset = elf.len();
+  elf.push(0);
+  let mut names = vec![];
+  for (name, _, _) in symbols {
+    names.push(elf.len() - strtab_offset);
+    elf.extend_from_slice(name.as_bytes());
+    elf.push(0);
+  }
+  let strtab_size = elf.len() - strtab_offset;
+  let symtab_offset = elf.len();
+  elf.extend_from_slice(&[0; 24]);
+  for ((_, addr, size), name) in symbols.iter().zip(names) {
+    elf.extend_from_slice(&(name as u32).to_le_bytes());
+    // STB_GLOBAL & STT_FUNC
+    elf.extend_from_slice(&[0x12, 0]);
+    elf.extend_from_slice(&1u16.to_le_bytes());
+    elf.extend_from_slice(&addr.to_le_bytes());
+    elf.extend_from_slice(&size.to_le_bytes());
+  }
+  let symtab_size = elf.len() - symtab_offset;
+
+  // Section headers: null, `.symtab` & `.strtab`
+  let shoff = elf.len();
+  elf[40..48].copy_from_slice(&(shoff as u64).to_le_bytes());
+  elf[58..60].copy_from_slice(&64u16.to_le_bytes());
+  elf[60..62].copy_from_slice(&3u16.to_le_bytes());
+  elf.extend_from_slice(&[0; 64]);
+  for (sh_type, offset, size, link) in [
+    (2u32, symtab_offset, symtab_size, 2u32),
+    (3, strtab_offset, strtab_size, 0),
+  ] {
+    let mut sh = [0; 64];
+    sh[4..8].copy_from_slice(&sh_type.to_le_bytes());
+    sh[24..32].copy_from_slice(&(offset as u64).to_le_bytes());
+    sh[32..40].copy_from_slice(&(size as u64).to_le_bytes());
+    sh[40..44].copy_from_slice(&link.to_le_bytes());
+    elf.extend_from_slice(&sh);
+  }
+  elf
+}
+
+#[test]
+fn test_elf_loader() {
+  use rvemu_for_book::dram::SizeType;
+  use rvemu_for_book::elf::Elf;
+
+  // addi a0, zero, 42 ; ld a1, 0(a2)
+  let text: Vec<u8> = [0x02A0_0513u32, 0x0006_3583]
+    .iter()
+    .flat_map(|inst| inst.to_le_bytes())
+    .collect();
+  let data = [0x11, 0x22, 0x33, 0x44];
+  let bytes = build_elf(
+    DRAM_BASE + 0x1000,
+    &[
+      (DRAM_BASE + 0x1000, &text, 8),
+      (DRAM_BASE + 0x2000, &data, 16),
+    ],
+    &[
+      ("_start", DRAM_BASE + 0x1000, 8),
+      ("buffer", DRAM_BASE + 0x2000, 0),
+    ],
+  );
+  let elf = Elf::parse(&bytes).unwrap();
+  assert_eq!(elf.entry, DRAM_BASE + 0x1000);
+  assert_eq!(elf.segments.len(), 2);
+
+  let mut cpu = Cpu::new(vec![]);
+  // BSS is zero-filled no matter what's in memory
+  cpu
+    .bus
+    .write_bytes(DRAM_BASE + 0x2000, &[0xFF; 32])
+    .unwrap();
+  elf.load(&mut cpu.bus).unwrap();
+  cpu.pc = elf.entry;
+  assert_eq!(
+    cpu
+      .bus
+      .load_u(DRAM_BASE + 0x2000, SizeType::DoubleWord)
+      .unwrap(),
+    0x4433_2211
+  );
+  assert_eq!(
+    cpu
+      .bus
+      .load_u(DRAM_BASE + 0x2008, SizeType::DoubleWord)
+      .unwrap(),
+    0
+  );
+  assert_eq!(
+    cpu.bus.load_u(DRAM_BASE + 0x2010, SizeType::Byte).unwrap(),
+    0xFF
+  );
+
+  cpu.gpr[12] = DRAM_BASE + 0x2000;
+  for _ in 0..2 {
+    let inst = cpu.fetch().unwrap();
+    cpu.pc = cpu.execute(inst).unwrap();
+  }
+  assert_eq!(cpu.observe_reg("a0"), 42);
+  assert_eq!(cpu.observe_reg("a1"), 0x4433_2211);
+
+  // Symbols
+  assert_eq!(elf.symbol("buffer").unwrap().addr, DRAM_BASE + 0x2000);
+  assert!(elf.symbol("main").is_none());
+  let (symbol, offset) = elf.symbolize(DRAM_BASE + 0x1004).unwrap();
+  assert_eq!((symbol.name.as_str(), offset), ("_start", 4));
+  // Beyond the size of `_start`
+  assert!(elf.symbolize(DRAM_BASE + 0x1008).is_none());
+  assert!(elf.symbolize(DRAM_BASE).is_none());
+  let (symbol, offset) = elf.symbolize(DRAM_BASE + 0x2010).unwrap();
+  assert_eq!((symbol.name.as_str(), offset), ("buffer", 0x10));
+}
+
+#[test]
+fn test_elf_loader_errors() {
+  use rvemu_for_book::elf::{Elf, ElfError};
+
+  let bytes = build_elf(DRAM_BASE, &[(DRAM_BASE, &[0x13, 0, 0, 0], 4)], &[]);
+  assert!(Elf::parse(&bytes).is_ok());
+  assert_eq!(Elf::parse(b"\x7FELF").unwrap_err(), ElfError::Truncated);
+  assert_eq!(Elf::parse(&[0; 64]).unwrap_err(), ElfError::BadMagic);
+
+  // EM_X86_64
+  let mut wrong = bytes.clone();
+  wrong[18] = 62;
+  assert_eq!(Elf::parse(&wrong).unwrap_err(), ElfError::WrongMachine(62));
+  // ELFCLASS32
+  let mut wrong = bytes.clone();
+  wrong[4] = 1;
+  assert!(matches!(
+    Elf::parse(&wrong).unwrap_err(),
+    ElfError::Unsupported(_)
+  ));
+  // The segment lies beyond the end of file
+  let mut wrong = bytes.clone();
+  wrong[64 + 32] = 0xFF;
+  wrong[64 + 40] = 0xFF;
+  assert_eq!(Elf::parse(&wrong).unwrap_err(), ElfError::Truncated);
+
+  // Offsets of tables & entries overflowing `u64` are beyond the end of file
+  let bytes = build_elf(
+    DRAM_BASE,
+    &[(DRAM_BASE, &[0x13, 0, 0, 0], 4)],
+    &[("f", DRAM_BASE, 4)],
+  );
+  assert!(Elf::parse(&bytes).is_ok());
+  let shoff = u64::from_le_bytes(bytes[40..48].try_into().unwrap()) as usize;
+  let symtab = shoff + 64;
+  for (offset, value) in [
+    (32, u64::MAX - 8),
+    (40, u64::MAX - 8),
+    (symtab + 24, u64::MAX - 8),
+  ] {
+    let mut wrong = bytes.clone();
+    wrong[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    assert_eq!(Elf::parse(&wrong).unwrap_err(), ElfError::Truncated);
+  }
+}
+
+#[test]
+fn test_elf_loader_huge_mem_size() {
+  use rvemu_for_book::{config::MachineConfig, elf::Elf};
+
+  let bytes = build_elf(DRAM_BASE, &[(DRAM_BASE, &[0x13, 0, 0, 0], 1 << 60)], &[]);
+  let elf = Elf::parse(&bytes).unwrap();
+  let config = MachineConfig::default().with_dram(DRAM_BASE, 0x10_0000);
+  let mut cpu = Cpu::with_config(&config, vec![]).unwrap();
+  // Zero-filled up to the end of DRAM, without allocating the whole segment
+  assert_eq!(
+    elf.load(&mut cpu.bus),
+    Err(Exception::StoreAMOAccessFault(DRAM_BASE + 0x10_0000))
+  );
+}
+
+#[test]
+fn test_signature() {
+  use rvemu_for_book::{config::MachineConfig, emulator};
+
+  // Store 0x123 to the 2nd word of the signature, then exit through HTIF
+  let text: Vec<u8> = [
+    0x0000_1517u32, // auipc a0, 1
+    0x1230_0593,    // addi a1, zero, 0x123
+    0x00B5_2223,    // sw a1, 4(a0)
+    0x1005_0613,    // addi a2, a0, 0x100
+    0x0010_0693,    // addi a3, zero, 1
+    0x00D6_3023,    // sd a3, 0(a2)
+    0x0000_006F,    // j .
+  ]
+  .iter()
+  .flat_map(|inst| inst.to_le_bytes())
+  .collect();
+  let data: Vec<u8> = [0xDEAD_BEEFu32; 4]
+    .iter()
+    .flat_map(|word| word.to_le_bytes())
+    .collect();
+  let symbols = [
+    ("begin_signature", DRAM_BASE + 0x1000, 0),
+    ("end_signature", DRAM_BASE + 0x1010, 0),
+    ("tohost", DRAM_BASE + 0x1100, 8),
+  ];
+  let elf = build_elf(
+    DRAM_BASE,
+    &[
+      (DRAM_BASE, &text, text.len() as u64),
+      (DRAM_BASE + 0x1000, &data, 0x108),
+    ],
+    &symbols,
+  );
+  let config = MachineConfig::default();
+  let limit = 100;
+
+  let mut signature = vec![];
+  emulator::run_signature(elf.clone(), &config, &mut signature, 4, limit).unwrap();
+  assert_eq!(
+    String::from_utf8(signature).unwrap(),
+    "deadbeef\n00000123\ndeadbeef\ndeadbeef\n"
+  );
+  let mut signature = vec![];
+  emulator::run_signature(elf.clone(), &config, &mut signature, 8, limit).unwrap();
+  assert_eq!(
+    String::from_utf8(signature).unwrap(),
+    "00000123deadbeef\ndeadbeefdeadbeef\n"
+  );
+
+  let err = emulator::run_signature(elf, &config, &mut vec![], 3, limit).unwrap_err();
+  assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+  let no_signature = build_elf(DRAM_BASE, &[(DRAM_BASE, &text, 28)], &symbols[2..]);
+  let err = emulator::run_signature(no_signature, &config, &mut vec![], 4, limit).unwrap_err();
+  assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+  // No signature unless the program exits with code 0
+  let mut text = text;
+  text[16..20].copy_from_slice(&0x0070_0693u32.to_le_bytes()); // addi a3, zero, 7
+  let segments = [
+    (DRAM_BASE, text.as_slice(), text.len() as u64),
+    (DRAM_BASE + 0x1000, data.as_slice(), 0x108),
+  ];
+  let exit_3 = build_elf(DRAM_BASE, &segments, &symbols);
+  let mut signature = vec![];
+  let err = emulator::run_signature(exit_3, &config, &mut signature, 4, limit).unwrap_err();
+  assert!(err.to_string().contains("Exit with code 3"));
+  assert!(signature.is_empty());
+  // or runs out of steps
+  let no_tohost = build_elf(DRAM_BASE, &segments, &symbols[..2]);
+  let err = emulator::run_signature(no_tohost, &config, &mut signature, 4, limit).unwrap_err();
+  assert!(err.to_string().contains("Step limit reached"));
+  assert!(signature.is_empty());
+}
+
+#[test]
+fn test_mtime_rate() {
+  use rvemu_for_book::{config::MachineConfig, emulator};
+
+  // Count down from 100, store `mtime` to the signature, then exit through HTIF
+  let text: Vec<u8> = [
+    0x0640_0293u32, // addi t0, zero, 100
+    0xFFF2_8293,    // addi t0, t0, -1
+    0xFE02_9EE3,    // bnez t0, -4
+    0x0200_C537,    // lui a0, 0x200c
+    0xFF85_3583,    // ld a1, -8(a0)
+    0x0000_1617,    // auipc a2, 1
+    0xFEB6_3623,    // sd a1, -20(a2)
+    0x0EC6_0613,    // addi a2, a2, 0xEC
+    0x0010_0693,    // addi a3, zero, 1
+    0x00D6_3023,    // sd a3, 0(a2)
+    0x0000_006F,    // j .
+  ]
+  .iter()
+  .flat_map(|inst| inst.to_le_bytes())
+  .collect();
+  let symbols = [
+    ("begin_signature", DRAM_BASE + 0x1000, 0),
+    ("end_signature", DRAM_BASE + 0x1008, 0),
+    ("tohost", DRAM_BASE + 0x1100, 8),
+  ];
+  let elf = build_elf(
+    DRAM_BASE,
+    &[(DRAM_BASE, &text, text.len() as u64)],
+    &symbols,
+  );
+  let mtime = |cycles_per_tick| {
+    let config = MachineConfig::default().with_clint_cycles_per_tick(cycles_per_tick);
+    let mut signature = vec![];
+    emulator::run_signature(elf.clone(), &config, &mut signature, 8, 1000).unwrap();
+    u64::from_str_radix(String::from_utf8(signature).unwrap().trim(), 16).unwrap()
+  };
+  // Every step but the one reading `mtime` is a clock cycle before it
+  assert_eq!(mtime(1), 202);
+  assert_eq!(mtime(10), 20);
+}
@@ -0,0 +1,122 @@
+use std::{
+  env, fs,
+  path::{Path, PathBuf},
+};
+
+use rvemu_for_book::{cpu::StopReason, utils::test_framework::TestFramework};
+
+/// Steps a test may take before it's considered hung
+const STEP_LIMIT: u64 = 1_000_000;
+
+/// Directory of prebuilt riscv-tests binaries (e.g. `rv64ui-p-add`), and whether
+/// it's required to hold them, i.e. it's given by `RISCV_TESTS_DIR` (as the Docker
+/// image does) rather than the default `tests/riscv-tests/isa`
+fn tests_dir() -> (PathBuf, bool) {
+  match env::var_os("RISCV_TESTS_DIR") {
+    Some(dir) => (PathBuf::from(dir), true),
+    None => (
+      Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/riscv-tests/isa"),
+      false,
+    ),
+  }
+}
+
+/// Run all binaries of `suite` in the physical-memory (`p`) environment, each of
+/// which is reported as it passes or fails
+fn run_suite(suite: &str) {
+  let (dir, required) = tests_dir();
+  let entries = match fs::read_dir(&dir) {
+    Ok(entries) => entries,
+    Err(e) if !required => {
+      eprintln!(
+        "SKIP {suite}: riscv-tests aren't found in {}: {e}",
+        dir.display()
+      );
+      return;
+    }
+    Err(e) => panic!("riscv-tests aren't found in {}: {e}", dir.display()),
+  };
+  // Binaries only, without e.g. `.dump`s
+  let prefix = format!("{suite}-p-");
+  let mut tests: Vec<PathBuf> = entries
+    .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+    .filter(|path| {
+      path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with(&prefix) && !name.contains('.'))
+    })
+    .collect();
+  tests.sort();
+  assert!(
+    !tests.is_empty(),
+    "no riscv-tests of {suite} are found in {}",
+    dir.display()
+  );
+
+  let mut failures = vec![];
+  for path in tests.iter() {
+    let name = path.file_name().unwrap().to_string_lossy();
+    // A test writes `(TESTNUM << 1) | 1` to `tohost`, where `TESTNUM` is 0 on success
+    let failure = match TestFramework::test_from_elf(path, STEP_LIMIT) {
+      Ok((_, StopReason::Exit(0))) => None,
+      Ok((_, StopReason::Exit(n))) => Some(format!("failed at test {n}")),
+      Ok((cpu, reason)) => Some(format!("{reason} (pc 0x{:x})", cpu.pc)),
+      Err(e) => Some(e.to_string()),
+    };
+    match failure {
+      Some(failure) => {
+        eprintln!("FAIL {name}: {failure}");
+        failures.push(format!("{name}: {failure}"));
+      }
+      None => eprintln!("PASS {name}"),
+    }
+  }
+  assert!(
+    failures.is_empty(),
+    "{} of {} tests of {suite} failed:\n{}",
+    failures.len(),
+    tests.len(),
+    failures.join("\n")
+  );
+}
+
+#[test]
+fn test_rv64ui() {
+  run_suite("rv64ui");
+}
+
+#[test]
+fn test_rv64um() {
+  run_suite("rv64um");
+}
+
+#[test]
+fn test_rv64ua() {
+  run_suite("rv64ua");
+}
+
+#[test]
+fn test_rv64uf() {
+  run_suite("rv64uf");
+}
+
+#[test]
+fn test_rv64ud() {
+  run_suite("rv64ud");
+}
+
+#[test]
+fn test_rv64uc() {
+  run_suite("rv64uc");
+}
+
+#[test]
+fn test_rv64mi() {
+  run_suite("rv64mi");
+}
+
+#[test]
+fn test_rv64si() {
+  run_suite("rv64si");
+}
@@ -1,28 +1,33 @@
-use std::{ops::Not, sync::Arc};
-
-use rvemu_for_book::{self, param::*, utils::test_framework::TestFramework};
+use std::ops::Not;
 
+use rvemu_for_book::{
+  self,
+  cpu::{Cpu, Mode},
+  exception::Exception,
+  mmu::AccessType,
+  param::*,
+  utils::test_framework::TestFramework,
+};
 #[inline]
 fn test_from_asm_snippet_with_auto_clock<'a>(
   code: &str,
   test_name: &str,
   cmp_iter: impl Iterator<Item = (&'a str, u64)>,
 ) {
-  let disable_auto_clock: Arc<[&str]> =
-    Arc::new(["beq", "bne", "blt", "bge", "bltu", "bgeu", "jal", "jalr"]);
-  let should_disable_auto_clock = || -> bool {
-    if test_name
-      .lines()
-      .any(|line| disable_auto_clock.iter().any(|&inst| line.contains(inst)))
-    {
-      true
-    } else {
-      code
-        .lines()
-        .any(|line| disable_auto_clock.iter().any(|&inst| line.contains(inst)))
-    }
-  };
-  let n_clock = if should_disable_auto_clock() {
+  // Control transfers (including pseudo-instructions) may run any number of steps
+  let disable_auto_clock = [
+    "beq", "bne", "blt", "bge", "bltu", "bgeu", "beqz", "bnez", "blez", "bgez", "bltz", "bgtz",
+    "bgt", "ble", "bgtu", "bleu", "jal", "jalr", "j", "jr", "call", "tail", "ret",
+  ];
+  // The mnemonic of each line (after its label, if any), or each word of the test name
+  let mnemonics = code
+    .lines()
+    .filter_map(|line| line.rsplit(':').next()?.split_whitespace().next());
+  let should_disable_auto_clock = test_name
+    .split('_')
+    .chain(mnemonics)
+    .any(|word| disable_auto_clock.contains(&word));
+  let n_clock = if should_disable_auto_clock {
     DRAM_END
   } else {
     code.lines().count() as u64
@@ -116,7 +121,7 @@ fn test_xor() {
 #[test]
 fn test_sb_lb() {
   let code = "
-    addi x29, x0, 0x100
+    auipc x29, 1
     addi x30, x0, 0x10
     sb x30, 0(x29)
     lb x31, 0(x29)
@@ -128,7 +133,7 @@ fn test_sb_lb() {
 #[test]
 fn test_sh_lh() {
   let code = "
-    addi x29, x0, 0x100
+    auipc x29, 1
     addi x30, x0, 0x100
     sh x30, 0(x29)
     lh x31, 0(x29)
@@ -140,7 +145,7 @@ fn test_sh_lh() {
 #[test]
 fn test_sw_lw() {
   let code = "
-    addi x29, x0, 0x100
+    auipc x29, 1
     addi x30, x0, 0x200
     sw x30, 0(x29)
     lw x31, 0(x29)
@@ -152,7 +157,7 @@ fn test_sw_lw() {
 #[test]
 fn test_sd_ld() {
   let code = "
-    addi x29, x0, 0x100
+    auipc x29, 1
     addi x30, x0, 0x200
     sd x30, 0(x29)
     ld x31, 0(x29)
@@ -164,7 +169,7 @@ fn test_sd_ld() {
 #[test]
 fn test_sw_lw_with_negative() {
   let code = "
-    addi x29, x0, 0x100
+    auipc x29, 1
     addi x30, x0, -0x200
     sw x30, 0(x29)
     lw x31, 0(x29)
@@ -176,7 +181,7 @@ fn test_sw_lw_with_negative() {
 #[test]
 fn test_sw_lwu_with_negative() {
   let code = "
-    addi x29, x0, 0x100
+    auipc x29, 1
     addi x30, x0, -0x200
     sw x30, 0(x29)
     lwu x31, 0(x29)
@@ -184,3 +189,2237 @@ fn test_sw_lwu_with_negative() {
   let cmp_iter = [("x31", 0x200_u32.not().wrapping_add(1) as u64)].into_iter();
   test_from_asm_snippet_with_auto_clock(code, "test_sw_lwu_with_negative", cmp_iter);
 }
+
+#[test]
+fn test_lui() {
+  let code = "
+    lui x31, 0x12345
+  ";
+  let cmp_iter = [("x31", 0x1234_5000)].into_iter();
+  test_from_asm_snippet_with_auto_clock(code, "test_lui", cmp_iter);
+}
+
+#[test]
+fn test_lui_sign_extended() {
+  let code = "
+    lui x31, 0x80000
+  ";
+  let cmp_iter = [("x31", 0xFFFF_FFFF_8000_0000)].into_iter();
+  test_from_asm_snippet_with_auto_clock(code, "test_lui_sign_extended", cmp_iter);
+}
+
+#[test]
+fn test_auipc() {
+  let code = "
+    addi x0, x0, 0
+    auipc x30, 0
+    auipc x31, 0x1
+  ";
+  let cmp_iter = [("x30", DRAM_BASE + 4), ("x31", DRAM_BASE + 8 + 0x1000)].into_iter();
+  test_from_asm_snippet_with_auto_clock(code, "test_auipc", cmp_iter);
+}
+
+#[test]
+fn test_li_large_imm() {
+  let code = "
+    li x29, 0x12345678
+    li x30, 0x7fffffff
+    li x31, 0xdeadbeefcafebabe
+  ";
+  let cmp_iter = [
+    ("x29", 0x1234_5678),
+    ("x30", 0x7FFF_FFFF),
+    ("x31", 0xDEAD_BEEF_CAFE_BABE),
+  ]
+  .into_iter();
+  test_from_asm_snippet(code, "test_li_large_imm", 16, cmp_iter);
+}
+
+#[test]
+fn test_addiw_overflow() {
+  let code = "
+    lui x30, 0x80000
+    addiw x31, x30, -1
+  ";
+  let cmp_iter = [("x31", 0x7FFF_FFFF)].into_iter();
+  test_from_asm_snippet_with_auto_clock(code, "test_addiw_overflow", cmp_iter);
+}
+
+#[test]
+fn test_jal() {
+  let code = "
+    addi x29, x0, 1
+    jal x1, target
+    addi x29, x0, 2
+  target:
+    addi x30, x0, 3
+  ";
+  let cmp_iter = [("x29", 1), ("x30", 3), ("ra", DRAM_BASE + 8)].into_iter();
+  test_from_asm_snippet_with_auto_clock(code, "test_jal", cmp_iter);
+}
+
+#[test]
+fn test_jal_backward() {
+  let code = "
+    addi x29, x0, 0
+    addi x30, x0, 5
+  loop:
+    addi x29, x29, 1
+    beq x29, x30, end
+    jal x0, loop
+  end:
+  ";
+  let cmp_iter = [("x29", 5), ("x0", 0)].into_iter();
+  test_from_asm_snippet_with_auto_clock(code, "test_jal_backward", cmp_iter);
+}
+
+#[test]
+fn test_jalr_clears_low_bit() {
+  let code = "
+    auipc x29, 0
+    addi x29, x29, 17
+    jalr x1, 0(x29)
+    addi x30, x0, 1
+    addi x31, x0, 2
+  ";
+  let cmp_iter = [("x30", 0), ("x31", 2), ("ra", DRAM_BASE + 12)].into_iter();
+  test_from_asm_snippet_with_auto_clock(code, "test_jalr_clears_low_bit", cmp_iter);
+}
+
+#[test]
+fn test_jalr_rd_eq_rs1() {
+  let code = "
+    auipc x1, 0
+    jalr x1, 12(x1)
+    addi x30, x0, 1
+    addi x31, x0, 2
+  ";
+  let cmp_iter = [("x30", 0), ("x31", 2), ("ra", DRAM_BASE + 8)].into_iter();
+  test_from_asm_snippet_with_auto_clock(code, "test_jalr_rd_eq_rs1", cmp_iter);
+}
+
+#[test]
+fn test_jal_rd_x0() {
+  let code = "
+    j skip
+    addi x30, x0, 1
+  skip:
+    addi x31, x0, 0
+  ";
+  let cmp_iter = [("x0", 0), ("x30", 0)].into_iter();
+  test_from_asm_snippet_with_auto_clock(code, "test_jal_rd_x0", cmp_iter);
+}
+
+#[test]
+fn test_call_ret() {
+  let code = "
+    addi a0, x0, 21
+    call double
+    addi a1, a0, 0
+    j end
+  double:
+    add a0, a0, a0
+    ret
+  end:
+  ";
+  let cmp_iter = [("a0", 42), ("a1", 42)].into_iter();
+  test_from_asm_snippet_with_auto_clock(code, "test_call_ret", cmp_iter);
+}
+
+#[test]
+fn test_nested_call() {
+  let code = "
+    addi a0, x0, 5
+    call outer
+    j end
+  outer:
+    addi s1, ra, 0
+    call inner
+    addi a0, a0, 1
+    jalr x0, 0(s1)
+  inner:
+    slli a0, a0, 3
+    ret
+  end:
+  ";
+  let cmp_iter = [("a0", 41)].into_iter();
+  test_from_asm_snippet_with_auto_clock(code, "test_nested_call", cmp_iter);
+}
+
+#[test]
+fn test_srli_srai_6bit_shamt() {
+  let code = "
+    addi a0, x0, -1
+    srli a1, a0, 32
+    srai a2, a0, 40
+    slli a3, a0, 63
+    srli a3, a3, 63
+  ";
+  let cmp_iter = [("a1", 0xFFFF_FFFF), ("a2", u64::MAX), ("a3", 1)].into_iter();
+  test_from_asm_snippet_with_auto_clock(code, "test_srli_srai_6bit_shamt", cmp_iter);
+}
+
+#[test]
+fn test_csrrw_csrrs_csrrc() {
+  let code = "
+    addi t0, x0, 0b1010
+    csrrw x0, mscratch, t0
+    addi t1, x0, 0b0101
+    csrrs x29, mscratch, t1
+    addi t2, x0, 0b0011
+    csrrc x30, mscratch, t2
+    csrr x31, mscratch
+  ";
+  let cmp_iter = [("x29", 0b1010), ("x30", 0b1111), ("x31", 0b1100)].into_iter();
+  test_from_asm_snippet_with_auto_clock(code, "test_csrrw_csrrs_csrrc", cmp_iter);
+}
+
+#[test]
+fn test_csr_imm() {
+  let code = "
+    csrrwi x0, mscratch, 0b10001
+    csrrsi x29, mscratch, 0b00110
+    csrrci x30, mscratch, 0b00011
+    csrr x31, mscratch
+  ";
+  let cmp_iter = [("x29", 0b10001), ("x30", 0b10111), ("x31", 0b10100)].into_iter();
+  test_from_asm_snippet_with_auto_clock(code, "test_csr_imm", cmp_iter);
+}
+
+#[test]
+fn test_mstatus_to_sstatus_masking() {
+  let code = "
+    addi t0, x0, -1
+    csrw mstatus, t0
+    csrr x30, sstatus
+    csrr x31, mstatus
+  ";
+  let cmp_iter = [("x30", MASK_SSTATUS), ("x31", u64::MAX)].into_iter();
+  test_from_asm_snippet_with_auto_clock(code, "test_mstatus_to_sstatus_masking", cmp_iter);
+}
+
+#[test]
+fn test_sstatus_to_mstatus_masking() {
+  let code = "
+    addi t0, x0, -1
+    csrw sstatus, t0
+    csrr x30, sstatus
+    csrr x31, mstatus
+  ";
+  let cmp_iter = [("x30", MASK_SSTATUS), ("x31", MASK_SSTATUS)].into_iter();
+  test_from_asm_snippet_with_auto_clock(code, "test_sstatus_to_mstatus_masking", cmp_iter);
+}
+
+#[test]
+fn test_csr_read_only() {
+  let code = "
+    addi t0, x0, 1
+    csrr x30, mhartid
+    csrw mhartid, t0
+    addi x31, x0, 1
+  ";
+  let cmp_iter = [("x30", 0), ("x31", 0)].into_iter();
+  test_from_asm_snippet_with_auto_clock(code, "test_csr_read_only", cmp_iter);
+}
+
+#[test]
+fn test_csr_privilege() {
+  // csrr a0, mstatus
+  let csrr_mstatus = 0x3000_2573;
+  // csrr a0, sstatus
+  let csrr_sstatus = 0x1000_2573;
+  let mut cpu = Cpu::new(vec![]);
+  cpu.mode = Mode::Supervisor;
+  assert!(matches!(
+    cpu.execute(csrr_mstatus),
+    Err(Exception::IllegalInstruction(0x3000_2573))
+  ));
+  assert!(cpu.execute(csrr_sstatus).is_ok());
+  cpu.mode = Mode::User;
+  assert!(matches!(
+    cpu.execute(csrr_sstatus),
+    Err(Exception::IllegalInstruction(0x1000_2573))
+  ));
+}
+
+#[test]
+fn test_take_trap_in_m_mode() {
+  let mut cpu = Cpu::new(vec![]);
+  cpu.pc = DRAM_BASE + 0x40;
+  cpu.csr.store(MTVEC, DRAM_BASE + 0x100);
+  cpu.csr.store(MSTATUS, MASK_MIE);
+  cpu.take_trap(Exception::LoadAccessMisaligned(0x1234));
+  assert_eq!(cpu.pc, DRAM_BASE + 0x100);
+  assert_eq!(cpu.mode, Mode::Machine);
+  assert_eq!(cpu.csr.load(MEPC), DRAM_BASE + 0x40);
+  assert_eq!(cpu.csr.load(MCAUSE), 4);
+  assert_eq!(cpu.csr.load(MTVAL), 0x1234);
+  let mstatus = cpu.csr.load(MSTATUS);
+  assert_eq!(mstatus & MASK_MIE, 0);
+  assert_eq!(mstatus & MASK_MPIE, MASK_MPIE);
+  assert_eq!(mstatus & MASK_MPP, MASK_MPP);
+}
+
+#[test]
+fn test_take_trap_ecall_has_no_tval() {
+  let mut cpu = Cpu::new(vec![]);
+  cpu.pc = DRAM_BASE + 0x40;
+  cpu.csr.store(MTVAL, 0x1234);
+  cpu.take_trap(Exception::EnvironmentCallFromMMode(cpu.pc));
+  assert_eq!(cpu.csr.load(MEPC), DRAM_BASE + 0x40);
+  assert_eq!(cpu.csr.load(MCAUSE), 11);
+  assert_eq!(cpu.csr.load(MTVAL), 0);
+}
+
+#[test]
+fn test_take_trap_vectored_exception_uses_base() {
+  let mut cpu = Cpu::new(vec![]);
+  cpu.csr.store(MTVEC, (DRAM_BASE + 0x100) | 1);
+  cpu.take_trap(Exception::Breakpoint(cpu.pc));
+  assert_eq!(cpu.pc, DRAM_BASE + 0x100);
+  assert_eq!(cpu.csr.load(MCAUSE), 3);
+}
+
+#[test]
+fn test_take_trap_delegated_to_s_mode() {
+  let mut cpu = Cpu::new(vec![]);
+  cpu.mode = Mode::User;
+  cpu.pc = DRAM_BASE + 0x40;
+  cpu.csr.store(MEDELEG, 1 << 4);
+  cpu.csr.store(STVEC, DRAM_BASE + 0x200);
+  cpu.csr.store(MTVEC, DRAM_BASE + 0x100);
+  cpu.csr.store(SSTATUS, MASK_SIE);
+  cpu.take_trap(Exception::LoadAccessMisaligned(0x1234));
+  assert_eq!(cpu.pc, DRAM_BASE + 0x200);
+  assert_eq!(cpu.mode, Mode::Supervisor);
+  assert_eq!(cpu.csr.load(SEPC), DRAM_BASE + 0x40);
+  assert_eq!(cpu.csr.load(SCAUSE), 4);
+  assert_eq!(cpu.csr.load(STVAL), 0x1234);
+  assert_eq!(cpu.csr.load(MCAUSE), 0);
+  let sstatus = cpu.csr.load(SSTATUS);
+  assert_eq!(sstatus & MASK_SIE, 0);
+  assert_eq!(sstatus & MASK_SPIE, MASK_SPIE);
+  assert_eq!(sstatus & MASK_SPP, 0);
+}
+
+#[test]
+fn test_take_trap_not_delegated_from_m_mode() {
+  let mut cpu = Cpu::new(vec![]);
+  cpu.csr.store(MEDELEG, 1 << 4);
+  cpu.csr.store(STVEC, DRAM_BASE + 0x200);
+  cpu.csr.store(MTVEC, DRAM_BASE + 0x100);
+  cpu.take_trap(Exception::LoadAccessMisaligned(0x1234));
+  assert_eq!(cpu.pc, DRAM_BASE + 0x100);
+  assert_eq!(cpu.mode, Mode::Machine);
+  assert_eq!(cpu.csr.load(MCAUSE), 4);
+  assert_eq!(cpu.csr.load(SCAUSE), 0);
+}
+
+#[test]
+fn test_mret_to_s_mode() {
+  let code = "
+    lla t0, supervisor
+    csrw mepc, t0
+    li t1, 0x800
+    csrs mstatus, t1
+    csrsi mstatus, 0x8
+    mret
+    addi x30, x0, 1
+  supervisor:
+    csrr x31, sstatus
+  ";
+  let cpu = TestFramework::test_from_asm(code, "test_mret_to_s_mode", 16).unwrap();
+  assert_eq!(cpu.mode, Mode::Supervisor);
+  assert_eq!(cpu.observe_reg("x30"), 0);
+  let mstatus = cpu.csr.load(MSTATUS);
+  // MIE <- MPIE (0), MPIE <- 1, MPP <- U
+  assert_eq!(mstatus & MASK_MIE, 0);
+  assert_eq!(mstatus & MASK_MPIE, MASK_MPIE);
+  assert_eq!(mstatus & MASK_MPP, 0);
+}
+
+#[test]
+fn test_sret_to_u_mode() {
+  let code = "
+    lla t0, user
+    csrw sepc, t0
+    csrsi sstatus, 0x2
+    sret
+    addi x30, x0, 1
+  user:
+    addi x31, x0, 1
+  ";
+  let cpu = TestFramework::test_from_asm(code, "test_sret_to_u_mode", 16).unwrap();
+  assert_eq!(cpu.mode, Mode::User);
+  assert_eq!(cpu.observe_reg("x30"), 0);
+  assert_eq!(cpu.observe_reg("x31"), 1);
+  let sstatus = cpu.csr.load(SSTATUS);
+  // SIE <- SPIE (0), SPIE <- 1, SPP <- U
+  assert_eq!(sstatus & MASK_SIE, 0);
+  assert_eq!(sstatus & MASK_SPIE, MASK_SPIE);
+}
+
+#[test]
+fn test_fatal_exception_is_trapped() {
+  let code = "
+    lla t0, handler
+    csrw mtvec, t0
+    addi t1, x0, 1
+    csrw mhartid, t1
+    addi x30, x0, 1
+  handler:
+    addi x31, x0, 1
+  ";
+  let cpu = TestFramework::test_from_asm(code, "test_fatal_exception_is_trapped", 16).unwrap();
+  assert_eq!(cpu.csr.load(MCAUSE), 2);
+  assert_eq!(cpu.csr.load(MEPC), DRAM_BASE + 16);
+  // The handler runs instead of what follows the illegal instruction
+  assert_eq!(cpu.observe_reg("x30"), 0);
+  assert_eq!(cpu.observe_reg("x31"), 1);
+}
+
+#[test]
+fn test_ecall_from_each_mode() {
+  for (mode, cause) in [(Mode::User, 8), (Mode::Supervisor, 9), (Mode::Machine, 11)] {
+    let mut cpu = Cpu::new(vec![]);
+    cpu.mode = mode;
+    // ecall
+    match cpu.execute(0x0000_0073) {
+      Err(e) => {
+        assert_eq!(e.code(), cause);
+        assert_eq!(e.value(), DRAM_BASE);
+      }
+      Ok(_) => panic!("ECALL should raise an exception"),
+    }
+  }
+}
+
+#[test]
+fn test_u_mode_ecall_traps_to_m_mode() {
+  let code = "
+    lla t0, handler
+    csrw mtvec, t0
+    lla t0, user
+    csrw mepc, t0
+    mret
+  user:
+    addi x29, x0, 1
+    ecall
+    addi x29, x0, 2
+  handler:
+    csrr x30, mcause
+    csrr x31, mepc
+  ";
+  let cpu = TestFramework::test_from_asm(code, "test_u_mode_ecall_traps_to_m_mode", 16).unwrap();
+  assert_eq!(cpu.mode, Mode::Machine);
+  assert_eq!(cpu.observe_reg("x29"), 1);
+  assert_eq!(cpu.observe_reg("x30"), 8);
+  assert_eq!(cpu.observe_reg("x31"), DRAM_BASE + 32);
+  assert_eq!(cpu.csr.load(MSTATUS) & MASK_MPP, 0);
+}
+
+#[test]
+fn test_u_mode_cannot_access_s_csr() {
+  let code = "
+    lla t0, user
+    csrw mepc, t0
+    mret
+  user:
+    csrr x31, sstatus
+  ";
+  let cpu = TestFramework::test_from_asm(code, "test_u_mode_cannot_access_s_csr", 16).unwrap();
+  assert_eq!(cpu.mode, Mode::Machine);
+  assert_eq!(cpu.csr.load(MCAUSE), 2);
+  assert_eq!(cpu.csr.load(MEPC), DRAM_BASE + 16);
+}
+
+#[test]
+fn test_mret_illegal_in_s_mode() {
+  let mut cpu = Cpu::new(vec![]);
+  cpu.mode = Mode::Supervisor;
+  // mret
+  assert!(matches!(
+    cpu.execute(0x3020_0073),
+    Err(Exception::IllegalInstruction(_))
+  ));
+  // sret
+  assert!(cpu.execute(0x1020_0073).is_ok());
+}
+
+#[test]
+fn test_sret_trapped_by_tsr() {
+  let mut cpu = Cpu::new(vec![]);
+  cpu.csr.store(MSTATUS, MASK_TSR);
+  cpu.mode = Mode::Supervisor;
+  // sret
+  assert!(matches!(
+    cpu.execute(0x1020_0073),
+    Err(Exception::IllegalInstruction(_))
+  ));
+  cpu.mode = Mode::User;
+  cpu.csr.store(MSTATUS, 0);
+  assert!(matches!(
+    cpu.execute(0x1020_0073),
+    Err(Exception::IllegalInstruction(_))
+  ));
+}
+
+#[test]
+fn test_satp_trapped_by_tvm() {
+  // csrr a0, satp
+  let csrr_satp = 0x1800_2573;
+  let mut cpu = Cpu::new(vec![]);
+  cpu.mode = Mode::Supervisor;
+  assert!(cpu.execute(csrr_satp).is_ok());
+  cpu.csr.store(MSTATUS, MASK_TVM);
+  assert!(matches!(
+    cpu.execute(csrr_satp),
+    Err(Exception::IllegalInstruction(_))
+  ));
+}
+
+#[test]
+fn test_mpp_is_warl() {
+  let code = "
+    li t0, 0x1000
+    csrs mstatus, t0
+    csrr x31, mstatus
+    srli x31, x31, 11
+    andi x31, x31, 0b11
+  ";
+  let cmp_iter = [("x31", 0)].into_iter();
+  test_from_asm_snippet_with_auto_clock(code, "test_mpp_is_warl", cmp_iter);
+}
+
+#[test]
+fn test_ebreak() {
+  let code = "
+    lla t0, handler
+    csrw mtvec, t0
+    addi x29, x0, 1
+    ebreak
+    addi x29, x0, 2
+  handler:
+    csrr x30, mcause
+    csrr x31, mepc
+  ";
+  let cmp_iter = [("x29", 1), ("x30", 3), ("x31", DRAM_BASE + 16)].into_iter();
+  test_from_asm_snippet(code, "test_ebreak", 16, cmp_iter);
+}
+
+#[test]
+fn test_ecall_handler_returns() {
+  let code = "
+    lla t0, handler
+    csrw mtvec, t0
+    addi a0, x0, 20
+    ecall
+    addi a0, a0, 1
+    j end
+  handler:
+    csrr t1, mepc
+    addi t1, t1, 4
+    csrw mepc, t1
+    addi a0, a0, 1
+    mret
+  end:
+  ";
+  let cmp_iter = [("a0", 22)].into_iter();
+  test_from_asm_snippet_with_auto_clock(code, "test_ecall_handler_returns", cmp_iter);
+}
+
+#[test]
+fn test_fence() {
+  let code = "
+    addi x29, x0, 1
+    fence
+    fence.tso
+    fence rw, w
+    fence.i
+    addi x31, x0, 2
+  ";
+  let cmp_iter = [("x29", 1), ("x31", 2)].into_iter();
+  test_from_asm_snippet_with_auto_clock(code, "test_fence", cmp_iter);
+}
+
+#[test]
+fn test_wfi_without_pending_interrupt_idles() {
+  let code = "
+    addi x29, x0, 1
+    wfi
+    addi x31, x0, 1
+  ";
+  let cpu =
+    TestFramework::test_from_asm(code, "test_wfi_without_pending_interrupt_idles", 16).unwrap();
+  assert!(cpu.wfi);
+  assert_eq!(cpu.pc, DRAM_BASE + 8);
+  assert_eq!(cpu.observe_reg("x29"), 1);
+  assert_eq!(cpu.observe_reg("x31"), 0);
+}
+
+#[test]
+fn test_wfi_with_pending_interrupt_resumes() {
+  // `mip.SSIP`, since `MTIP` is read-only to software
+  let code = "
+    li t0, 0x2
+    csrw mie, t0
+    csrw mip, t0
+    wfi
+    addi x31, x0, 1
+  ";
+  let cpu =
+    TestFramework::test_from_asm(code, "test_wfi_with_pending_interrupt_resumes", 16).unwrap();
+  assert!(!cpu.wfi);
+  assert_eq!(cpu.observe_reg("x31"), 1);
+}
+
+#[test]
+fn test_wfi_privilege() {
+  // wfi
+  let wfi = 0x1050_0073;
+  let mut cpu = Cpu::new(vec![]);
+  cpu.mode = Mode::Supervisor;
+  assert!(cpu.execute(wfi).is_ok());
+  assert!(cpu.wfi);
+  cpu.wfi = false;
+  cpu.csr.store(MSTATUS, MASK_TW);
+  assert!(matches!(
+    cpu.execute(wfi),
+    Err(Exception::IllegalInstruction(0x1050_0073))
+  ));
+  cpu.mode = Mode::User;
+  cpu.csr.store(MSTATUS, 0);
+  assert!(matches!(
+    cpu.execute(wfi),
+    Err(Exception::IllegalInstruction(0x1050_0073))
+  ));
+  assert!(!cpu.wfi);
+}
+
+#[test]
+fn test_step_and_run() {
+  use rvemu_for_book::cpu::StopReason;
+
+  // addi a0, a0, 1 (x3), then zeros
+  let addi = 0x0015_0513u32.to_le_bytes();
+  let mut cpu = Cpu::new([addi, addi, addi].concat());
+  cpu.breakpoints.insert(DRAM_BASE + 8);
+  assert_eq!(cpu.run(100), StopReason::Breakpoint(DRAM_BASE + 8));
+  assert_eq!(cpu.observe_reg("a0"), 2);
+  // Resume from the breakpoint, until the illegal all-zero instruction traps to
+  // `mtvec` (0), where nothing is mapped
+  assert_eq!(
+    cpu.run(100),
+    StopReason::Fatal(Exception::IllegalInstruction(0))
+  );
+  assert_eq!(cpu.observe_reg("a0"), 3);
+  assert_eq!(cpu.csr.load(MEPC), DRAM_BASE + 12);
+  assert_eq!(cpu.csr.load(MCAUSE), 2);
+
+  // j . (an infinite loop)
+  let mut cpu = Cpu::new(0x0000_006Fu32.to_le_bytes().to_vec());
+  assert_eq!(cpu.run(10), StopReason::StepLimit);
+  assert_eq!(cpu.step(), None);
+  cpu.bus.request_exit(3);
+  assert_eq!(cpu.step(), Some(StopReason::Exit(3)));
+  assert_eq!(cpu.run(10), StopReason::StepLimit);
+
+  // wfi
+  let mut cpu = Cpu::new(0x1050_0073u32.to_le_bytes().to_vec());
+  assert_eq!(cpu.run(10), StopReason::Idle);
+
+  // The fetch from `mtvec` (0) faults, as it does from the handler itself
+  let mut cpu = Cpu::new(vec![]);
+  cpu.pc = 0;
+  assert_eq!(
+    cpu.run(10),
+    StopReason::Fatal(Exception::InstructionAccessFault(0))
+  );
+}
+
+#[test]
+fn test_seip_is_ored_with_line() {
+  use rvemu_for_book::devices::IrqLine;
+
+  let mut cpu = Cpu::new(vec![]);
+  let line = IrqLine::new();
+  cpu.connect_irq(MASK_SEIP, line.clone());
+  // Written by software while the line is low
+  cpu.csr.store(MIP, MASK_SEIP);
+  cpu.check_interrupts();
+  assert_eq!(cpu.csr.load(MIP) & MASK_SEIP, MASK_SEIP);
+
+  // Raised by the line only, which `CSRRC` doesn't latch into the software bit
+  cpu.csr.store(MIP, 0);
+  line.set(true);
+  cpu.check_interrupts();
+  assert_eq!(cpu.csr.load(MIP) & MASK_SEIP, MASK_SEIP);
+  cpu.gpr[11] = MASK_SSIP;
+  // csrrc a0, mip, a1
+  cpu.execute(0x3445_B573).unwrap();
+  assert_eq!(cpu.observe_reg("a0") & MASK_SEIP, MASK_SEIP);
+  line.set(false);
+  cpu.check_interrupts();
+  assert_eq!(cpu.csr.load(MIP) & MASK_SEIP, 0);
+}
+
+#[test]
+fn test_mip_read_only_bits() {
+  let mut cpu = Cpu::new(vec![]);
+  cpu.csr.set_pending(MASK_MTIP, true);
+  // csrrw zero, mip, a0, where `MSIP`, `MTIP` & `MEIP` are driven by devices
+  cpu.gpr[10] = MASK_SSIP | MASK_MSIP | MASK_STIP | MASK_SEIP | MASK_MEIP;
+  cpu.execute(0x3445_1073).unwrap();
+  assert_eq!(
+    cpu.csr.load(MIP),
+    MASK_SSIP | MASK_STIP | MASK_SEIP | MASK_MTIP
+  );
+  // csrrc zero, mip, a0, where `MTIP` stays raised
+  cpu.gpr[10] = !0;
+  cpu.execute(0x3445_3073).unwrap();
+  assert_eq!(cpu.csr.load(MIP), MASK_MTIP);
+  // Delegated bits are writable through `sip` in the same way
+  cpu.csr.store(MIDELEG, MASK_SSIP | MASK_MTIP);
+  cpu.csr.store(SIP, 0);
+  assert_eq!(cpu.csr.load(MIP), MASK_MTIP);
+  cpu.csr.store(SIP, MASK_SSIP);
+  assert_eq!(cpu.csr.load(MIP), MASK_SSIP | MASK_MTIP);
+}
+
+#[test]
+fn test_interrupt_priority_and_delegation() {
+  use rvemu_for_book::exception::Interrupt;
+
+  let mut cpu = Cpu::new(vec![]);
+  cpu.pc = DRAM_BASE + 0x40;
+  cpu.csr.store(MIE, MASK_MEIP | MASK_MTIP | MASK_STIP);
+  // Raised as interrupt lines do, since `MTIP` & `MEIP` are read-only to software
+  cpu.csr.set_pending(MASK_MTIP | MASK_MEIP | MASK_STIP, true);
+  cpu.csr.store(MIDELEG, MASK_STIP);
+  cpu.csr.store(STVEC, DRAM_BASE + 0x100);
+  cpu.csr.store(MTVEC, DRAM_BASE + 0x200);
+
+  // M-mode interrupts are disabled in M-mode by `mstatus.MIE`
+  assert_eq!(cpu.pending_interrupt(), None);
+  cpu.csr.store(MSTATUS, MASK_MIE);
+  assert_eq!(cpu.pending_interrupt(), Some(Interrupt::MachineExternal));
+  cpu.csr.set_pending(MASK_MEIP, false);
+  assert_eq!(cpu.pending_interrupt(), Some(Interrupt::MachineTimer));
+  // Delegated interrupts are never taken in M-mode
+  cpu.csr.set_pending(MASK_MTIP, false);
+  assert_eq!(cpu.pending_interrupt(), None);
+
+  // S-mode interrupts are always enabled in U-mode
+  cpu.mode = Mode::User;
+  cpu.csr.store(MSTATUS, 0);
+  assert!(cpu.check_interrupts());
+  assert_eq!(cpu.mode, Mode::Supervisor);
+  assert_eq!(cpu.pc, DRAM_BASE + 0x100);
+  assert_eq!(cpu.csr.load(SCAUSE), MASK_INTERRUPT | 5);
+  assert_eq!(cpu.csr.load(SEPC), DRAM_BASE + 0x40);
+  assert_eq!(cpu.csr.load(MSTATUS) & MASK_SPP, 0);
+  // but only with `sstatus.SIE` in S-mode
+  assert_eq!(cpu.pending_interrupt(), None);
+
+  // M-mode interrupts preempt S-mode, even with `mstatus.MIE` clear
+  cpu.csr.set_pending(MASK_MTIP, true);
+  assert_eq!(cpu.pending_interrupt(), Some(Interrupt::MachineTimer));
+  // Vectored mode
+  cpu.csr.store(MTVEC, DRAM_BASE + 0x200 + 1);
+  assert!(cpu.check_interrupts());
+  assert_eq!(cpu.mode, Mode::Machine);
+  assert_eq!(cpu.pc, DRAM_BASE + 0x200 + 4 * 7);
+  assert_eq!(cpu.csr.load(MCAUSE), MASK_INTERRUPT | 7);
+  assert_eq!(
+    (cpu.csr.load(MSTATUS) & MASK_MPP) >> 11,
+    Mode::Supervisor as u64
+  );
+}
+
+/// Run `op rd, rs1, rs2` against each `(rs1, rs2, expect)` case
+fn test_r_type_cases(op: &str, cases: &[(i64, i64, u64)]) {
+  for (i, &(a, b, expect)) in cases.iter().enumerate() {
+    let code = format!(
+      "
+    li a0, {a}
+    li a1, {b}
+    {op} a2, a0, a1
+  "
+    );
+    let test_name = format!("test_{}_{}", op, i);
+    test_from_asm_snippet(&code, &test_name, 32, [("a2", expect)].into_iter());
+  }
+}
+
+#[test]
+fn test_mul() {
+  test_r_type_cases(
+    "mul",
+    &[
+      (7, 6, 42),
+      (-7, 6, -42_i64 as u64),
+      (i64::MIN, -1, i64::MIN as u64),
+      (-1, -1, 1),
+      (1 << 32, 1 << 32, 0),
+    ],
+  );
+}
+
+#[test]
+fn test_mulh() {
+  test_r_type_cases(
+    "mulh",
+    &[
+      (-1, -1, 0),
+      (i64::MIN, i64::MIN, 0x4000_0000_0000_0000),
+      (i64::MIN, 1, u64::MAX),
+      (1 << 62, 4, 1),
+      (-(1 << 62), 4, u64::MAX),
+    ],
+  );
+}
+
+#[test]
+fn test_mulhsu() {
+  test_r_type_cases(
+    "mulhsu",
+    &[
+      (-1, -1, u64::MAX),
+      (i64::MIN, 2, u64::MAX),
+      (1, -1, 0),
+      (2, -1, 1),
+    ],
+  );
+}
+
+#[test]
+fn test_mulhu() {
+  test_r_type_cases(
+    "mulhu",
+    &[(-1, -1, u64::MAX - 1), (i64::MIN, 2, 1), (7, 6, 0)],
+  );
+}
+
+#[test]
+fn test_div() {
+  test_r_type_cases(
+    "div",
+    &[
+      (20, 6, 3),
+      (-20, 6, -3_i64 as u64),
+      (-20, -6, 3),
+      (20, 0, u64::MAX),
+      (i64::MIN, -1, i64::MIN as u64),
+    ],
+  );
+}
+
+#[test]
+fn test_divu() {
+  test_r_type_cases(
+    "divu",
+    &[
+      (20, 6, 3),
+      (-1, 2, i64::MAX as u64),
+      (20, 0, u64::MAX),
+      (i64::MIN, -1, 0),
+    ],
+  );
+}
+
+#[test]
+fn test_rem() {
+  test_r_type_cases(
+    "rem",
+    &[
+      (20, 6, 2),
+      (-20, 6, -2_i64 as u64),
+      (20, -6, 2),
+      (20, 0, 20),
+      (i64::MIN, -1, 0),
+    ],
+  );
+}
+
+#[test]
+fn test_remu() {
+  test_r_type_cases(
+    "remu",
+    &[
+      (20, 6, 2),
+      (-1, 10, 5),
+      (20, 0, 20),
+      (-20, 0, -20_i64 as u64),
+    ],
+  );
+}
+
+#[test]
+fn test_mulw() {
+  test_r_type_cases(
+    "mulw",
+    &[
+      (7, -6, -42_i64 as u64),
+      (0x7FFF_FFFF, 2, -2_i64 as u64),
+      ((1 << 32) + 3, 5, 15),
+    ],
+  );
+}
+
+#[test]
+fn test_divw() {
+  test_r_type_cases(
+    "divw",
+    &[
+      (-20, 6, -3_i64 as u64),
+      (20, 0, u64::MAX),
+      (i32::MIN as i64, -1, i32::MIN as u64),
+      ((1 << 32) + 20, 6, 3),
+    ],
+  );
+}
+
+#[test]
+fn test_divuw() {
+  test_r_type_cases(
+    "divuw",
+    &[
+      (-1, 2, 0x7FFF_FFFF),
+      (20, 0, u64::MAX),
+      (0x8000_0000, 1, 0xFFFF_FFFF_8000_0000),
+    ],
+  );
+}
+
+#[test]
+fn test_remw() {
+  test_r_type_cases(
+    "remw",
+    &[
+      (-20, 6, -2_i64 as u64),
+      (20, 0, 20),
+      (i32::MIN as i64, -1, 0),
+      (0x1_8000_0000, 0, 0xFFFF_FFFF_8000_0000),
+    ],
+  );
+}
+
+#[test]
+fn test_remuw() {
+  test_r_type_cases(
+    "remuw",
+    &[
+      (20, 6, 2),
+      (0xFFFF_FFFF, 0, u64::MAX),
+      ((1 << 32) + 7, 4, 3),
+    ],
+  );
+}
+
+#[test]
+fn test_r_w_type_sign_extension() {
+  test_r_type_cases(
+    "addw",
+    &[
+      (0x7FFF_FFFF, 1, 0xFFFF_FFFF_8000_0000),
+      ((1 << 32) + 1, 1, 2),
+    ],
+  );
+  test_r_type_cases("subw", &[(i32::MIN as i64, 1, 0x7FFF_FFFF)]);
+  test_r_type_cases("sllw", &[(1, 31, 0xFFFF_FFFF_8000_0000), (1, 32, 1)]);
+  test_r_type_cases("srlw", &[(-1, 4, 0x0FFF_FFFF)]);
+  test_r_type_cases("sraw", &[(0x8000_0000, 4, 0xFFFF_FFFF_F800_0000)]);
+}
+
+/// Run `op rd, rs2, (rs1)` against `mem` for each `(mem, rs2, expect_rd, expect_mem)` case
+fn test_amo_cases(op: &str, cases: &[(i64, i64, u64, u64)]) {
+  let load = if op.ends_with(".w") { "lw" } else { "ld" };
+  let store = if op.ends_with(".w") { "sw" } else { "sd" };
+  for (i, &(mem, src, expect_rd, expect_mem)) in cases.iter().enumerate() {
+    let code = format!(
+      "
+    auipc a0, 1
+    li a1, {mem}
+    {store} a1, 0(a0)
+    li a2, {src}
+    {op} a3, a2, (a0)
+    {load} a4, 0(a0)
+  "
+    );
+    let test_name = format!("test_{}_{}", op.replace('.', "_"), i);
+    test_from_asm_snippet(
+      &code,
+      &test_name,
+      32,
+      [("a3", expect_rd), ("a4", expect_mem)].into_iter(),
+    );
+  }
+}
+
+#[test]
+fn test_amo_w() {
+  test_amo_cases("amoswap.w", &[(5, 7, 5, 7)]);
+  test_amo_cases(
+    "amoadd.w",
+    &[
+      (5, 7, 5, 12),
+      (0x7FFF_FFFF, 1, 0x7FFF_FFFF, 0xFFFF_FFFF_8000_0000),
+    ],
+  );
+  test_amo_cases("amoxor.w", &[(0b1100, 0b1010, 0b1100, 0b0110)]);
+  test_amo_cases("amoand.w", &[(0b1100, 0b1010, 0b1100, 0b1000)]);
+  test_amo_cases("amoor.w", &[(0b1100, 0b1010, 0b1100, 0b1110)]);
+  test_amo_cases("amomin.w", &[(-5, 3, -5_i64 as u64, -5_i64 as u64)]);
+  test_amo_cases("amomax.w", &[(-5, 3, -5_i64 as u64, 3)]);
+  test_amo_cases("amominu.w", &[(-5, 3, -5_i64 as u64, 3)]);
+  test_amo_cases("amomaxu.w", &[(-5, 3, -5_i64 as u64, -5_i64 as u64)]);
+}
+
+#[test]
+fn test_amo_d() {
+  test_amo_cases("amoswap.d", &[(5, -7, 5, -7_i64 as u64)]);
+  test_amo_cases("amoadd.d", &[(0x7FFF_FFFF, 1, 0x7FFF_FFFF, 0x8000_0000)]);
+  test_amo_cases("amoxor.d", &[(-1, 1, u64::MAX, u64::MAX - 1)]);
+  test_amo_cases("amoand.d", &[(-1, 0xF0, u64::MAX, 0xF0)]);
+  test_amo_cases("amoor.d", &[(1 << 40, 1, 1 << 40, (1 << 40) + 1)]);
+  test_amo_cases(
+    "amomin.d",
+    &[(i64::MIN, 0, i64::MIN as u64, i64::MIN as u64)],
+  );
+  test_amo_cases("amomax.d", &[(i64::MIN, 0, i64::MIN as u64, 0)]);
+  test_amo_cases("amominu.d", &[(i64::MIN, 0, i64::MIN as u64, 0)]);
+  test_amo_cases(
+    "amomaxu.d",
+    &[(i64::MIN, 0, i64::MIN as u64, i64::MIN as u64)],
+  );
+}
+
+#[test]
+fn test_lr_sc() {
+  let code = "
+    auipc a0, 1
+    addi a1, x0, 41
+    sd a1, 0(a0)
+    lr.d a2, (a0)
+    addi a2, a2, 1
+    sc.d a3, a2, (a0)
+    ld a4, 0(a0)
+    lr.w a5, (a0)
+    sc.w a6, x0, (a0)
+    lw a7, 0(a0)
+  ";
+  let cmp_iter = [
+    ("a2", 42),
+    ("a3", 0),
+    ("a4", 42),
+    ("a5", 42),
+    ("a6", 0),
+    ("a7", 0),
+  ]
+  .into_iter();
+  test_from_asm_snippet_with_auto_clock(code, "test_lr_sc", cmp_iter);
+}
+
+#[test]
+fn test_sc_without_reservation() {
+  let code = "
+    auipc a0, 1
+    addi a1, x0, 41
+    sd a1, 0(a0)
+    lr.d a2, (a0)
+    sc.d a3, x0, (a0)
+    sc.d a4, x0, (a0)
+    ld a5, 0(a0)
+  ";
+  let cmp_iter = [("a3", 0), ("a4", 1), ("a5", 0)].into_iter();
+  test_from_asm_snippet_with_auto_clock(code, "test_sc_without_reservation", cmp_iter);
+}
+
+#[test]
+fn test_sc_after_store_to_reservation() {
+  let code = "
+    auipc a0, 1
+    addi a1, x0, 41
+    lr.w a2, (a0)
+    sw a1, 4(a0)
+    sc.w a3, a1, (a0)
+    lw a4, 0(a0)
+  ";
+  let cmp_iter = [("a3", 1), ("a4", 0)].into_iter();
+  test_from_asm_snippet_with_auto_clock(code, "test_sc_after_store_to_reservation", cmp_iter);
+}
+
+#[test]
+fn test_reservation_invalidated_by_device_store() {
+  use rvemu_for_book::dram::SizeType;
+
+  let mut cpu = Cpu::new(vec![]);
+  cpu.gpr[10] = DRAM_BASE + 0x100;
+  // lr.d a2, (a0)
+  cpu.execute(0x1005_362F).unwrap();
+  // A store which is not issued by the hart
+  cpu
+    .bus
+    .store(DRAM_BASE + 0x100, SizeType::Byte, 0xFF)
+    .unwrap();
+  // sc.d a3, x0, (a0)
+  cpu.execute(0x1805_36AF).unwrap();
+  assert_eq!(cpu.gpr[13], 1);
+  assert_eq!(
+    cpu
+      .bus
+      .load_u(DRAM_BASE + 0x100, SizeType::DoubleWord)
+      .unwrap(),
+    0xFF
+  );
+}
+
+#[test]
+fn test_amo_misaligned() {
+  let code = "
+    addi a0, x0, 0x102
+    amoadd.w a1, a1, (a0)
+  ";
+  let cpu = TestFramework::test_from_asm(code, "test_amo_misaligned", 16).unwrap();
+  assert_eq!(cpu.csr.load(MCAUSE), 6);
+  assert_eq!(cpu.csr.load(MTVAL), 0x102);
+}
+
+#[test]
+fn test_amo_access_fault() {
+  let code = "
+    li a0, 0x100000000000
+    amoswap.d a1, a1, (a0)
+  ";
+  let cpu = TestFramework::test_from_asm(code, "test_amo_access_fault", 16).unwrap();
+  assert_eq!(cpu.csr.load(MCAUSE), 7);
+  assert_eq!(cpu.csr.load(MTVAL), 0x1000_0000_0000);
+}
+
+#[test]
+fn test_atomic_access_fault_reports_vaddr() {
+  // lr.w a1, (a0) / sc.w a1, a2, (a0) / amoswap.w a1, a2, (a0)
+  let (lr, sc, amo) = (0x1005_25AF, 0x18C5_25AF, 0x08C5_25AF);
+  let mut cpu = Cpu::new(vec![]);
+  // A page backed by nothing
+  setup_page_tables(
+    &mut cpu,
+    SATP_MODE_SV39,
+    &[(0x4000_0000, 0x1000_0000_0000, MASK_PTE_R | MASK_PTE_W, 0)],
+  );
+  cpu.mode = Mode::Supervisor;
+  cpu.gpr[10] = 0x4000_0000;
+  assert_eq!(
+    cpu.execute(lr),
+    Err(Exception::LoadAccessFault(0x4000_0000))
+  );
+  cpu.bus.reserve(0, 0x1000_0000_0000);
+  assert_eq!(
+    cpu.execute(sc),
+    Err(Exception::StoreAMOAccessFault(0x4000_0000))
+  );
+  assert_eq!(
+    cpu.execute(amo),
+    Err(Exception::StoreAMOAccessFault(0x4000_0000))
+  );
+}
+
+#[test]
+fn test_fp_load_store() {
+  let code = "
+    auipc a0, 1
+    li a1, 0x400921FB54442D18
+    sd a1, 0(a0)
+    fld f1, 0(a0)
+    fsd f1, 8(a0)
+    ld a2, 8(a0)
+    flw f2, 0(a0)
+    fsw f2, 16(a0)
+    lwu a3, 16(a0)
+  ";
+  let cpu = TestFramework::test_from_asm(code, "test_fp_load_store", 32).unwrap();
+  assert_eq!(cpu.fpr[1], 0x4009_21FB_5444_2D18);
+  assert_eq!(cpu.observe_reg("a2"), 0x4009_21FB_5444_2D18);
+  // `FLW` NaN-boxes the single-precision value
+  assert_eq!(cpu.fpr[2], 0xFFFF_FFFF_5444_2D18);
+  assert_eq!(cpu.observe_reg("a3"), 0x5444_2D18);
+}
+
+#[test]
+fn test_fp_arithmetic() {
+  let code = "
+    addi a0, x0, 6
+    addi a1, x0, 4
+    fcvt.d.l f1, a0
+    fcvt.d.l f2, a1
+    fadd.d f3, f1, f2
+    fsub.d f4, f1, f2
+    fmul.d f5, f1, f2
+    fdiv.d f6, f1, f2
+    fsqrt.d f7, f2
+    fcvt.s.w f8, a0
+    fcvt.s.w f9, a1
+    fdiv.s f10, f8, f9
+    fmin.d f11, f1, f2
+    fmax.s f12, f8, f9
+  ";
+  let cpu = TestFramework::test_from_asm(code, "test_fp_arithmetic", 32).unwrap();
+  assert_eq!(cpu.fpr[3], 10.0_f64.to_bits());
+  assert_eq!(cpu.fpr[4], 2.0_f64.to_bits());
+  assert_eq!(cpu.fpr[5], 24.0_f64.to_bits());
+  assert_eq!(cpu.fpr[6], 1.5_f64.to_bits());
+  assert_eq!(cpu.fpr[7], 2.0_f64.to_bits());
+  assert_eq!(
+    cpu.fpr[10],
+    0xFFFF_FFFF_0000_0000 | 1.5_f32.to_bits() as u64
+  );
+  assert_eq!(cpu.fpr[11], 4.0_f64.to_bits());
+  assert_eq!(
+    cpu.fpr[12],
+    0xFFFF_FFFF_0000_0000 | 6.0_f32.to_bits() as u64
+  );
+  // All results above are exact
+  assert_eq!(cpu.csr.load(FFLAGS), 0);
+}
+
+#[test]
+fn test_fp_fma() {
+  let code = "
+    addi a0, x0, 2
+    addi a1, x0, 3
+    addi a2, x0, 10
+    fcvt.d.l f1, a0
+    fcvt.d.l f2, a1
+    fcvt.d.l f3, a2
+    fmadd.d f4, f1, f2, f3
+    fmsub.d f5, f1, f2, f3
+    fnmsub.d f6, f1, f2, f3
+    fnmadd.d f7, f1, f2, f3
+  ";
+  let cpu = TestFramework::test_from_asm(code, "test_fp_fma", 32).unwrap();
+  assert_eq!(cpu.fpr[4], 16.0_f64.to_bits());
+  assert_eq!(cpu.fpr[5], (-4.0_f64).to_bits());
+  assert_eq!(cpu.fpr[6], 4.0_f64.to_bits());
+  assert_eq!(cpu.fpr[7], (-16.0_f64).to_bits());
+}
+
+#[test]
+fn test_fp_fma_single_rounding() {
+  // (1 + 2^-52) * (1 - 2^-52) - 1 = -2^-104, which is lost if the product is rounded first
+  let code = "
+    li a0, 0x3FF0000000000001
+    li a1, 0x3FEFFFFFFFFFFFFE
+    li a2, 0x3FF0000000000000
+    fmv.d.x f1, a0
+    fmv.d.x f2, a1
+    fmv.d.x f3, a2
+    fmsub.d f4, f1, f2, f3
+    fmul.d f5, f1, f2
+    fsub.d f6, f5, f3
+  ";
+  let cpu = TestFramework::test_from_asm(code, "test_fp_fma_single_rounding", 32).unwrap();
+  assert_eq!(cpu.fpr[4], (-(2.0_f64.powi(-104))).to_bits());
+  assert_eq!(cpu.fpr[6], 0);
+}
+
+#[test]
+fn test_fp_rounding_mode() {
+  let code = "
+    li a0, 0x4004000000000000
+    fmv.d.x f1, a0
+    fcvt.l.d a1, f1, rne
+    fcvt.l.d a2, f1, rtz
+    fcvt.l.d a3, f1, rdn
+    fcvt.l.d a4, f1, rup
+    fcvt.l.d a5, f1, rmm
+    fneg.d f2, f1
+    fcvt.w.d a6, f2, rdn
+    fsrmi t0, 0b011
+    fcvt.l.d a7, f1
+  ";
+  let cpu = TestFramework::test_from_asm(code, "test_fp_rounding_mode", 32).unwrap();
+  // 2.5
+  assert_eq!(cpu.observe_reg("a1"), 2);
+  assert_eq!(cpu.observe_reg("a2"), 2);
+  assert_eq!(cpu.observe_reg("a3"), 2);
+  assert_eq!(cpu.observe_reg("a4"), 3);
+  assert_eq!(cpu.observe_reg("a5"), 3);
+  assert_eq!(cpu.observe_reg("a6"), -3_i64 as u64);
+  // Dynamic rounding mode is read from `frm`
+  assert_eq!(cpu.observe_reg("t0"), 0);
+  assert_eq!(cpu.csr.load(FRM), 0b011);
+  assert_eq!(cpu.observe_reg("a7"), 3);
+  assert_eq!(cpu.csr.load(FCSR), (0b011 << 5) | MASK_NX);
+}
+
+#[test]
+fn test_fcvt_int_tiny_values() {
+  let (rne, rtz, rdn, rup, rmm) = (0b000, 0b001, 0b010, 0b011, 0b100);
+  // (funct7, NaN-boxed value, sign bit), where some values are shifted out by more
+  // than 128 bits while being converted
+  let singles = [2.0_f32.powi(-20), 2.0_f32.powi(-120), f32::from_bits(1)].map(|x| {
+    (
+      0b1100000,
+      0xFFFF_FFFF_0000_0000 | x.to_bits() as u64,
+      1 << 31,
+    )
+  });
+  let doubles = [
+    2.0_f64.powi(-60),
+    2.0_f64.powi(-100),
+    1.2e-25,
+    f64::from_bits(1),
+  ]
+  .map(|x| (0b1100001, x.to_bits(), 1 << 63));
+  for (funct7, bits, sign) in singles.into_iter().chain(doubles) {
+    for negative in [false, true] {
+      // w, wu, l, lu
+      for (rs2, signed) in [(0, true), (1, false), (2, true), (3, false)] {
+        for rm in [rne, rtz, rdn, rup, rmm] {
+          let mut cpu = Cpu::new(vec![]);
+          cpu.fpr[1] = if negative { bits | sign } else { bits };
+          // fcvt.{w|wu|l|lu}.{s|d} a0, f1, rm
+          let inst = (funct7 << 25) | (rs2 << 20) | (1 << 15) | (rm << 12) | (10 << 7) | 0x53;
+          cpu.execute(inst).unwrap();
+          let (expected, fflags) = if rm == rup && !negative {
+            (1, MASK_NX)
+          } else if rm == rdn && negative && signed {
+            (u64::MAX, MASK_NX)
+          } else if rm == rdn && negative {
+            (0, MASK_NV)
+          } else {
+            (0, MASK_NX)
+          };
+          let case = format!("{inst:#010x} on {:#x}", cpu.fpr[1]);
+          assert_eq!(cpu.observe_reg("a0"), expected, "{case}");
+          assert_eq!(cpu.csr.load(FFLAGS), fflags, "{case}");
+        }
+      }
+    }
+  }
+}
+
+#[test]
+fn test_fp_invalid_rounding_mode() {
+  let mut cpu = Cpu::new(vec![]);
+  cpu.csr.store(FRM, 0b101);
+  // fadd.d f1, f2, f3 (dyn)
+  let inst = 0x0231_70D3;
+  assert!(matches!(
+    cpu.execute(inst),
+    Err(Exception::IllegalInstruction(0x0231_70D3))
+  ));
+}
+
+#[test]
+fn test_fp_exception_flags() {
+  let code = "
+    addi a0, x0, 1
+    addi a1, x0, 3
+    fcvt.d.l f1, a0
+    fcvt.d.l f2, a1
+    fcvt.d.l f0, x0
+    fdiv.d f3, f1, f2
+    frflags a2
+    fdiv.d f4, f1, f0
+    fsflags a3, x0
+    fneg.d f5, f1
+    fsqrt.d f6, f5
+    csrr a4, fflags
+    li a5, 0x7FEFFFFFFFFFFFFF
+    fmv.d.x f7, a5
+    fscsr x0
+    fmul.d f8, f7, f7
+    frflags a6
+    fcvt.wu.d a7, f5
+  ";
+  let cpu = TestFramework::test_from_asm(code, "test_fp_exception_flags", 64).unwrap();
+  assert_eq!(cpu.observe_reg("a2"), MASK_NX);
+  assert_eq!(cpu.fpr[4], f64::INFINITY.to_bits());
+  assert_eq!(cpu.observe_reg("a3"), MASK_NX | MASK_DZ);
+  assert_eq!(cpu.fpr[6], f64::NAN.to_bits());
+  assert_eq!(cpu.observe_reg("a4"), MASK_NV);
+  assert_eq!(cpu.fpr[8], f64::INFINITY.to_bits());
+  assert_eq!(cpu.observe_reg("a6"), MASK_OF | MASK_NX);
+  // Saturated
+  assert_eq!(cpu.observe_reg("a7"), 0);
+  assert_eq!(cpu.csr.load(FFLAGS), MASK_OF | MASK_NX | MASK_NV);
+}
+
+#[test]
+fn test_fp_conversion() {
+  let code = "
+    li a0, -7
+    fcvt.s.l f1, a0
+    fcvt.d.s f2, f1
+    fcvt.w.s a1, f1
+    fcvt.lu.d a2, f2
+    li a3, 0xFFFFFFFF
+    fcvt.d.wu f3, a3
+    fcvt.d.w f4, a3
+    li a4, 0x3FF0000010000000
+    fmv.d.x f5, a4
+    fcvt.s.d f6, f5
+    fcvt.s.d f7, f5, rup
+  ";
+  let cpu = TestFramework::test_from_asm(code, "test_fp_conversion", 32).unwrap();
+  assert_eq!(
+    cpu.fpr[1],
+    0xFFFF_FFFF_0000_0000 | (-7.0_f32).to_bits() as u64
+  );
+  assert_eq!(cpu.fpr[2], (-7.0_f64).to_bits());
+  assert_eq!(cpu.observe_reg("a1"), -7_i64 as u64);
+  // Out of range, saturated to 0 for unsigned
+  assert_eq!(cpu.observe_reg("a2"), 0);
+  assert_eq!(cpu.fpr[3], 4294967295.0_f64.to_bits());
+  assert_eq!(cpu.fpr[4], (-1.0_f64).to_bits());
+  // 1 + 2^-24 is a tie, rounded to even
+  assert_eq!(cpu.fpr[6], 0xFFFF_FFFF_0000_0000 | 1.0_f32.to_bits() as u64);
+  assert_eq!(
+    cpu.fpr[7],
+    0xFFFF_FFFF_0000_0000 | (1.0_f32 + f32::EPSILON).to_bits() as u64
+  );
+}
+
+#[test]
+fn test_fp_compare_classify() {
+  let code = "
+    addi a0, x0, 1
+    fcvt.d.l f1, a0
+    fcvt.d.l f2, x0
+    fdiv.d f3, f2, f2
+    fscsr x0
+    flt.d a1, f2, f1
+    fle.d a2, f1, f2
+    feq.d a3, f1, f1
+    feq.d a4, f3, f3
+    frflags a5
+    flt.d a6, f3, f1
+    frflags a7
+    fclass.d s2, f1
+    fclass.d s3, f3
+    fneg.d f4, f2
+    fclass.d s4, f4
+    fdiv.d f5, f1, f2
+    fclass.d s5, f5
+  ";
+  let cpu = TestFramework::test_from_asm(code, "test_fp_compare_classify", 32).unwrap();
+  assert_eq!(cpu.observe_reg("a1"), 1);
+  assert_eq!(cpu.observe_reg("a2"), 0);
+  assert_eq!(cpu.observe_reg("a3"), 1);
+  assert_eq!(cpu.observe_reg("a4"), 0);
+  // `FEQ` is quiet, `FLT` signals on quiet NaNs
+  assert_eq!(cpu.observe_reg("a5"), 0);
+  assert_eq!(cpu.observe_reg("a6"), 0);
+  assert_eq!(cpu.observe_reg("a7"), MASK_NV);
+  assert_eq!(cpu.observe_reg("s2"), 1 << 6);
+  assert_eq!(cpu.observe_reg("s3"), 1 << 9);
+  assert_eq!(cpu.observe_reg("s4"), 1 << 3);
+  assert_eq!(cpu.observe_reg("s5"), 1 << 7);
+}
+
+#[test]
+fn test_fp_move_and_nan_boxing() {
+  let code = "
+    li a0, 0xBF800000
+    fmv.w.x f1, a0
+    fmv.x.w a1, f1
+    fmv.d.x f2, a0
+    fadd.s f3, f2, f2
+    fsgnjn.s f4, f1, f1
+    fmv.x.d a2, f2
+    fsgnjx.d f5, f2, f2
+  ";
+  let cpu = TestFramework::test_from_asm(code, "test_fp_move_and_nan_boxing", 32).unwrap();
+  assert_eq!(cpu.fpr[1], 0xFFFF_FFFF_BF80_0000);
+  // `FMV.X.W` sign-extends
+  assert_eq!(cpu.observe_reg("a1"), 0xFFFF_FFFF_BF80_0000);
+  // Improperly NaN-boxed inputs are treated as canonical NaN
+  assert_eq!(cpu.fpr[3], 0xFFFF_FFFF_7FC0_0000);
+  assert_eq!(cpu.fpr[4], 0xFFFF_FFFF_3F80_0000);
+  assert_eq!(cpu.observe_reg("a2"), 0xBF80_0000);
+  assert_eq!(cpu.fpr[5], 0xBF80_0000);
+}
+
+#[test]
+fn test_fp_disabled_by_fs() {
+  let code = "
+    li t0, 0x6000
+    csrc mstatus, t0
+    fadd.d f1, f2, f3
+  ";
+  let cpu = TestFramework::test_from_asm(code, "test_fp_disabled_by_fs", 16).unwrap();
+  assert_eq!(cpu.csr.load(MCAUSE), 2);
+  assert_eq!(cpu.csr.load(MTVAL), 0x0231_70D3);
+}
+
+#[test]
+fn test_fp_dirty_state() {
+  let mut cpu = Cpu::new(vec![]);
+  assert_eq!(cpu.csr.load(MSTATUS) & (0b11 << 13), FS_INITIAL);
+  assert_eq!(cpu.csr.load(MSTATUS) >> 63, 0);
+  // fmv.d.x f1, x0
+  cpu.execute(0xF200_00D3).unwrap();
+  assert_eq!(cpu.csr.load(MSTATUS) & (0b11 << 13), FS_DIRTY);
+  assert_eq!(cpu.csr.load(MSTATUS) >> 63, 1);
+  assert_eq!(cpu.csr.load(SSTATUS) >> 63, 1);
+}
+
+#[test]
+fn test_rvc_arithmetic() {
+  let code = "
+    .option rvc
+    c.li a0, -3
+    c.addi a0, 10
+    c.mv a1, a0
+    c.add a1, a0
+    c.lui a2, 0x1
+    c.slli a2, 20
+    c.mv a3, a2
+    c.srli a3, 32
+    c.li a4, -16
+    c.srai a4, 2
+    c.andi a4, 0x1D
+    c.li a5, 0xF
+    c.sub a5, a0
+    c.xor a5, a0
+    c.or a5, a0
+    c.and a5, a0
+    c.li s0, 1
+    c.slli s0, 31
+    c.addiw s0, 0
+    c.li s1, -1
+    c.addw s1, s0
+    c.subw s1, s0
+  ";
+  let cpu = TestFramework::test_from_asm(code, "test_rvc_arithmetic", 64).unwrap();
+  assert_eq!(cpu.observe_reg("a0"), 7);
+  assert_eq!(cpu.observe_reg("a1"), 14);
+  assert_eq!(cpu.observe_reg("a2"), 0x1_0000_0000);
+  assert_eq!(cpu.observe_reg("a3"), 1);
+  assert_eq!(cpu.observe_reg("a4"), 0x1C);
+  assert_eq!(cpu.observe_reg("a5"), 7);
+  assert_eq!(cpu.observe_reg("s0"), 0xFFFF_FFFF_8000_0000);
+  assert_eq!(cpu.observe_reg("s1"), u64::MAX);
+  // 22 compressed instructions
+  assert_eq!(cpu.pc, DRAM_BASE + 44);
+}
+
+#[test]
+fn test_rvc_load_store() {
+  let code = "
+    .option rvc
+    li sp, 0x80001000
+    li a0, 0x1122334455667788
+    c.sdsp a0, 8(sp)
+    c.ldsp a1, 8(sp)
+    c.swsp a0, 16(sp)
+    c.lwsp a2, 16(sp)
+    c.addi4spn s0, sp, 32
+    c.sd a0, 8(s0)
+    c.ld a3, 8(s0)
+    c.sw a0, 4(s0)
+    c.lw a4, 4(s0)
+    c.fldsp fa0, 8(sp)
+    c.fsd fa0, 16(s0)
+    c.fld fa1, 16(s0)
+    c.fsdsp fa1, 24(sp)
+    c.ldsp a5, 24(sp)
+  ";
+  let cpu = TestFramework::test_from_asm(code, "test_rvc_load_store", 64).unwrap();
+  assert_eq!(cpu.observe_reg("a1"), 0x1122_3344_5566_7788);
+  assert_eq!(cpu.observe_reg("a2"), 0x5566_7788);
+  assert_eq!(cpu.observe_reg("s0"), DRAM_BASE + 0x1020);
+  assert_eq!(cpu.observe_reg("a3"), 0x1122_3344_5566_7788);
+  assert_eq!(cpu.observe_reg("a4"), 0x5566_7788);
+  assert_eq!(cpu.fpr[11], 0x1122_3344_5566_7788);
+  assert_eq!(cpu.observe_reg("a5"), 0x1122_3344_5566_7788);
+}
+
+#[test]
+fn test_rvc_control_flow() {
+  let code = "
+    .option rvc
+    c.li a0, 0
+    c.beqz a0, 1f
+    c.li a1, 1
+  1:
+    c.bnez a0, 2f
+    c.j 3f
+  2:
+    c.li a2, 1
+  3:
+    lla t0, 4f
+    c.jalr t0
+    c.j 5f
+  4:
+    c.mv a3, ra
+    c.jr ra
+  5:
+    c.li a4, 5
+  ";
+  let cpu = TestFramework::test_from_asm(code, "test_rvc_control_flow", 64).unwrap();
+  assert_eq!(cpu.observe_reg("a1"), 0);
+  assert_eq!(cpu.observe_reg("a2"), 0);
+  // `c.jalr` at offset 20 links `pc + 2`, right after the 8-byte `lla`
+  assert_eq!(cpu.observe_reg("a3"), DRAM_BASE + 22);
+  assert_eq!(cpu.observe_reg("a4"), 5);
+}
+
+#[test]
+fn test_rvc_ebreak() {
+  let code = "
+    .option rvc
+    c.nop
+    c.ebreak
+  ";
+  let cpu = TestFramework::test_from_asm(code, "test_rvc_ebreak", 2).unwrap();
+  assert_eq!(cpu.csr.load(MCAUSE), 3);
+  assert_eq!(cpu.csr.load(MEPC), DRAM_BASE + 2);
+}
+
+#[test]
+fn test_rvc_illegal_instruction() {
+  let mut cpu = Cpu::new(vec![]);
+  // The all-zero halfword is defined to be illegal
+  assert!(matches!(
+    cpu.execute(0),
+    Err(Exception::IllegalInstruction(0))
+  ));
+  // c.lwsp x0, 0(sp) is reserved
+  assert!(matches!(
+    cpu.execute(0x4002),
+    Err(Exception::IllegalInstruction(0x4002))
+  ));
+  // Upper bits of a 16-bit instruction are ignored
+  cpu.execute(0xFFFF_0505).unwrap();
+  assert_eq!(cpu.gpr[10], 1);
+}
+
+#[test]
+fn test_rvc_misaligned_fetch() {
+  let mut cpu = Cpu::new(vec![0x13, 0, 0, 0]);
+  cpu.pc = DRAM_BASE + 1;
+  assert!(matches!(
+    cpu.fetch(),
+    Err(Exception::InstructionAddrMisaligned(addr)) if addr == DRAM_BASE + 1
+  ));
+  cpu.pc = DRAM_BASE + 2;
+  assert!(cpu.fetch().is_ok());
+}
+
+/// Build page tables (with the root at `DRAM_BASE + 0x1_0000`) and enable paging of `satp_mode`.
+///
+/// Each `(va, pa, flags, level)` maps a page, which is a superpage when `level > 0`.
+fn setup_page_tables(cpu: &mut Cpu, satp_mode: u64, maps: &[(u64, u64, u64, u32)]) {
+  use rvemu_for_book::dram::SizeType;
+
+  let levels = if satp_mode == SATP_MODE_SV39 { 3 } else { 4 };
+  let root = DRAM_BASE + 0x1_0000;
+  let mut next_table = root + PAGE_SIZE;
+  for &(va, pa, flags, leaf_level) in maps {
+    let mut table = root;
+    for level in (leaf_level..levels).rev() {
+      let pte_addr = table + ((va >> (12 + 9 * level)) & 0x1FF) * 8;
+      if level == leaf_level {
+        let pte = ((pa >> 12) << 10) | flags | MASK_PTE_V;
+        cpu.bus.store(pte_addr, SizeType::DoubleWord, pte).unwrap();
+        break;
+      }
+      let pte = cpu.bus.load_u(pte_addr, SizeType::DoubleWord).unwrap();
+      table = if pte & MASK_PTE_V != 0 {
+        ((pte & MASK_PTE_PPN) >> 10) << 12
+      } else {
+        let pte = ((next_table >> 12) << 10) | MASK_PTE_V;
+        cpu.bus.store(pte_addr, SizeType::DoubleWord, pte).unwrap();
+        next_table += PAGE_SIZE;
+        next_table - PAGE_SIZE
+      };
+    }
+  }
+  cpu.csr.store(SATP, (satp_mode << 60) | (root >> 12));
+}
+
+/// Read the leaf PTE of `va` in the tables built by `setup_page_tables`
+fn leaf_pte(cpu: &mut Cpu, va: u64) -> u64 {
+  use rvemu_for_book::dram::SizeType;
+
+  let pte_addr = leaf_pte_addr(cpu, va);
+  cpu.bus.load_u(pte_addr, SizeType::DoubleWord).unwrap()
+}
+
+/// Address of the leaf PTE of `va` in the tables built by `setup_page_tables`
+fn leaf_pte_addr(cpu: &mut Cpu, va: u64) -> u64 {
+  use rvemu_for_book::dram::SizeType;
+
+  let mut table = (cpu.csr.load(SATP) & MASK_SATP_PPN) << 12;
+  let levels = rvemu_for_book::mmu::levels(cpu.csr.load(SATP)).unwrap();
+  for level in (0..levels).rev() {
+    let pte_addr = table + ((va >> (12 + 9 * level)) & 0x1FF) * 8;
+    let pte = cpu.bus.load_u(pte_addr, SizeType::DoubleWord).unwrap();
+    if pte & (MASK_PTE_R | MASK_PTE_X) != 0 {
+      return pte_addr;
+    }
+    table = ((pte & MASK_PTE_PPN) >> 10) << 12;
+  }
+  unreachable!()
+}
+
+#[test]
+fn test_sv39_translation_and_access_dirty_bits() {
+  use rvemu_for_book::dram::SizeType;
+
+  let mut cpu = Cpu::new(vec![]);
+  setup_page_tables(
+    &mut cpu,
+    SATP_MODE_SV39,
+    &[
+      (0x4000_0000, DRAM_BASE + 0x2000, MASK_PTE_R | MASK_PTE_W, 0),
+      (0xFFFF_FFC0_0000_1000, DRAM_BASE + 0x3000, MASK_PTE_R, 0),
+    ],
+  );
+  cpu.mode = Mode::Supervisor;
+
+  cpu
+    .store(0x4000_0008, SizeType::DoubleWord, 0xDEAD)
+    .unwrap();
+  assert_eq!(
+    cpu
+      .bus
+      .load_u(DRAM_BASE + 0x2008, SizeType::DoubleWord)
+      .unwrap(),
+    0xDEAD
+  );
+  assert_eq!(
+    cpu.load_u(0x4000_0008, SizeType::DoubleWord).unwrap(),
+    0xDEAD
+  );
+  let pte = leaf_pte(&mut cpu, 0x4000_0000);
+  assert_eq!(pte & (MASK_PTE_A | MASK_PTE_D), MASK_PTE_A | MASK_PTE_D);
+
+  // Upper half of the (sign-extended) address space
+  cpu
+    .bus
+    .store(DRAM_BASE + 0x3010, SizeType::Word, 0x1234)
+    .unwrap();
+  assert_eq!(
+    cpu.load(0xFFFF_FFC0_0000_1010, SizeType::Word).unwrap(),
+    0x1234
+  );
+  let pte = leaf_pte(&mut cpu, 0xFFFF_FFC0_0000_1000);
+  assert_eq!(pte & (MASK_PTE_A | MASK_PTE_D), MASK_PTE_A);
+  assert!(matches!(
+    cpu.store(0xFFFF_FFC0_0000_1010, SizeType::Word, 0),
+    Err(Exception::StoreAMOPageFault(0xFFFF_FFC0_0000_1010))
+  ));
+}
+
+#[test]
+fn test_sv48_translation_and_superpage() {
+  use rvemu_for_book::dram::SizeType;
+
+  let mut cpu = Cpu::new(vec![]);
+  setup_page_tables(
+    &mut cpu,
+    SATP_MODE_SV48,
+    &[
+      (
+        0x7F_0000_0000,
+        DRAM_BASE + 0x4000,
+        MASK_PTE_R | MASK_PTE_W,
+        0,
+      ),
+      // 2 MiB megapage
+      (0x20_0000, 0x20_0000, MASK_PTE_R | MASK_PTE_W, 1),
+      // Misaligned megapage
+      (0x40_0000, 0x1000, MASK_PTE_R | MASK_PTE_W, 1),
+    ],
+  );
+  cpu.mode = Mode::Supervisor;
+
+  cpu.store(0x7F_0000_0010, SizeType::Word, 42).unwrap();
+  assert_eq!(
+    cpu.bus.load(DRAM_BASE + 0x4010, SizeType::Word).unwrap(),
+    42
+  );
+  assert_eq!(
+    cpu.translate(0x3F_FFF8, AccessType::Load).unwrap(),
+    0x3F_FFF8
+  );
+  assert!(matches!(
+    cpu.load(0x40_0000, SizeType::Byte),
+    Err(Exception::LoadPageFault(0x40_0000))
+  ));
+  // Sv39 has no such address, while Sv48 has
+  assert!(matches!(
+    cpu.load(0x80_0000_0000, SizeType::Byte),
+    Err(Exception::LoadPageFault(0x80_0000_0000))
+  ));
+  // Non-canonical address
+  assert!(matches!(
+    cpu.load(0x1_0000_0000_0000, SizeType::Byte),
+    Err(Exception::LoadPageFault(0x1_0000_0000_0000))
+  ));
+}
+
+#[test]
+fn test_page_permission() {
+  use rvemu_for_book::dram::SizeType;
+
+  let mut cpu = Cpu::new(vec![]);
+  setup_page_tables(
+    &mut cpu,
+    SATP_MODE_SV39,
+    &[
+      (
+        0x1000,
+        DRAM_BASE + 0x5000,
+        MASK_PTE_R | MASK_PTE_W | MASK_PTE_U,
+        0,
+      ),
+      (0x2000, DRAM_BASE + 0x6000, MASK_PTE_R | MASK_PTE_W, 0),
+      (0x3000, DRAM_BASE + 0x7000, MASK_PTE_X, 0),
+    ],
+  );
+
+  cpu.mode = Mode::User;
+  assert!(cpu.load(0x1000, SizeType::Byte).is_ok());
+  assert!(matches!(
+    cpu.load(0x2000, SizeType::Byte),
+    Err(Exception::LoadPageFault(0x2000))
+  ));
+
+  // S-mode accesses user pages only with `SUM`, and never executes them
+  cpu.mode = Mode::Supervisor;
+  assert!(matches!(
+    cpu.store(0x1000, SizeType::Byte, 0),
+    Err(Exception::StoreAMOPageFault(0x1000))
+  ));
+  cpu.csr.store(SSTATUS, MASK_SUM);
+  assert!(cpu.store(0x1000, SizeType::Byte, 0).is_ok());
+  assert!(matches!(
+    cpu.translate(0x1000, AccessType::Instruction),
+    Err(Exception::InstructionPageFault(0x1000))
+  ));
+  assert!(cpu.load(0x2000, SizeType::Byte).is_ok());
+
+  // Execute-only page is readable with `MXR`
+  assert!(matches!(
+    cpu.load(0x3000, SizeType::Byte),
+    Err(Exception::LoadPageFault(0x3000))
+  ));
+  cpu.csr.store(SSTATUS, MASK_MXR);
+  assert!(cpu.load(0x3000, SizeType::Byte).is_ok());
+  assert_eq!(
+    cpu.translate(0x3004, AccessType::Instruction).unwrap(),
+    DRAM_BASE + 0x7004
+  );
+
+  // Unmapped page
+  assert!(matches!(
+    cpu.load(0x4000, SizeType::Byte),
+    Err(Exception::LoadPageFault(0x4000))
+  ));
+}
+
+#[test]
+fn test_mprv_translates_m_mode_data_access() {
+  use rvemu_for_book::dram::SizeType;
+
+  let mut cpu = Cpu::new(vec![]);
+  setup_page_tables(
+    &mut cpu,
+    SATP_MODE_SV39,
+    &[(0x1000, DRAM_BASE + 0x5000, MASK_PTE_R | MASK_PTE_W, 0)],
+  );
+  // M-mode is never translated
+  assert_eq!(cpu.translate(0x1000, AccessType::Load).unwrap(), 0x1000);
+  let mstatus = cpu.csr.load(MSTATUS);
+  cpu.csr.store(MSTATUS, mstatus | MASK_MPRV | (0b01 << 11));
+  cpu.store(0x1000, SizeType::Word, 7).unwrap();
+  assert_eq!(cpu.bus.load(DRAM_BASE + 0x5000, SizeType::Word).unwrap(), 7);
+  // Instruction fetch isn't affected by `MPRV`
+  assert_eq!(
+    cpu.translate(0x1000, AccessType::Instruction).unwrap(),
+    0x1000
+  );
+}
+
+#[test]
+fn test_translated_instruction_fetch() {
+  // addi a0, x0, 1
+  let mut cpu = Cpu::new(vec![0x13, 0x05, 0x10, 0x00]);
+  setup_page_tables(
+    &mut cpu,
+    SATP_MODE_SV39,
+    &[
+      (0x1000, DRAM_BASE, MASK_PTE_X, 0),
+      (0x2000, DRAM_BASE, MASK_PTE_R, 0),
+    ],
+  );
+  cpu.mode = Mode::Supervisor;
+  cpu.pc = 0x1000;
+  assert_eq!(cpu.fetch().unwrap(), 0x0010_0513);
+  cpu.pc = 0x2000;
+  assert!(matches!(
+    cpu.fetch(),
+    Err(Exception::InstructionPageFault(0x2000))
+  ));
+}
+
+#[test]
+fn test_sfence_vma_and_satp() {
+  // sfence.vma x0, x0
+  let sfence_vma = 0x1200_0073;
+  let mut cpu = Cpu::new(vec![]);
+  cpu.mode = Mode::Supervisor;
+  assert!(cpu.execute(sfence_vma).is_ok());
+  let mstatus = cpu.csr.load(MSTATUS);
+  cpu.csr.store(MSTATUS, mstatus | MASK_TVM);
+  assert!(matches!(
+    cpu.execute(sfence_vma),
+    Err(Exception::IllegalInstruction(0x1200_0073))
+  ));
+  cpu.mode = Mode::User;
+  assert!(matches!(
+    cpu.execute(sfence_vma),
+    Err(Exception::IllegalInstruction(0x1200_0073))
+  ));
+
+  // `satp` is WARL, and Sv57 is not supported
+  cpu.csr.store(SATP, (SATP_MODE_SV39 << 60) | 0x10);
+  cpu.csr.store(SATP, (10 << 60) | 0x20);
+  assert_eq!(cpu.csr.load(SATP), (SATP_MODE_SV39 << 60) | 0x10);
+}
+
+#[test]
+fn test_paging_with_mprv_in_asm() {
+  let code = "
+    lla t0, trap
+    csrw mtvec, t0
+    # root table at 0x80010000, with a gigapage mapping 0x40000000 to 0x80000000
+    li t0, 0x80010000
+    li t1, (0x80000 << 10) | 0xCF
+    sd t1, 8(t0)
+    li t1, (8 << 60) | 0x80010
+    csrw satp, t1
+    sfence.vma
+    # translate loads & stores as S-mode
+    li t1, (1 << 17) | (1 << 11)
+    csrs mstatus, t1
+    li a0, 0x40001000
+    li a1, 99
+    sd a1, 0(a0)
+    li t1, 1 << 17
+    csrc mstatus, t1
+    li a0, 0x80001000
+    ld a2, 0(a0)
+    csrs mstatus, t1
+    ld a3, 0(t0)
+    j end
+  trap:
+    csrr s2, mcause
+    csrr s3, mtval
+  end:
+  ";
+  let cpu = TestFramework::test_from_asm(code, "test_paging_with_mprv_in_asm", 64).unwrap();
+  assert_eq!(cpu.observe_reg("a2"), 99);
+  // 0x80010000 isn't mapped
+  assert_eq!(cpu.observe_reg("s2"), 13);
+  assert_eq!(cpu.observe_reg("s3"), 0x8001_0000);
+}
+
+#[test]
+fn test_tlb_hit_and_miss() {
+  use rvemu_for_book::dram::SizeType;
+
+  let mut cpu = Cpu::new(vec![]);
+  setup_page_tables(
+    &mut cpu,
+    SATP_MODE_SV39,
+    &[
+      (0x1000, DRAM_BASE + 0x5000, MASK_PTE_R | MASK_PTE_W, 0),
+      (0x2000, DRAM_BASE + 0x6000, MASK_PTE_R | MASK_PTE_W, 0),
+    ],
+  );
+  cpu.mode = Mode::Supervisor;
+  cpu.load(0x1000, SizeType::Byte).unwrap();
+  cpu.load(0x1008, SizeType::Byte).unwrap();
+  cpu.load(0x2000, SizeType::Byte).unwrap();
+  assert_eq!((cpu.dtlb.hits, cpu.dtlb.misses), (1, 2));
+  // The cached PTE is clean, so the first store walks again to set `D`
+  cpu.store(0x1000, SizeType::Byte, 0).unwrap();
+  cpu.store(0x1000, SizeType::Byte, 0).unwrap();
+  assert_eq!((cpu.dtlb.hits, cpu.dtlb.misses), (2, 3));
+  assert_eq!((cpu.itlb.hits, cpu.itlb.misses), (0, 0));
+}
+
+#[test]
+fn test_trap_handler_probed_without_side_effects() {
+  use rvemu_for_book::cpu::StopReason;
+
+  let mut cpu = Cpu::new(vec![]);
+  let x = MASK_PTE_X | MASK_PTE_A;
+  setup_page_tables(
+    &mut cpu,
+    SATP_MODE_SV39,
+    &[
+      (0x1000, DRAM_BASE + 0x5000, x, 0),
+      (0x2000, DRAM_BASE + 0x6000, MASK_PTE_X, 0),
+      (0x3000, DRAM_BASE + 0x7000, MASK_PTE_R, 0),
+    ],
+  );
+  // The all-zero instruction at 0x1000 traps to `stvec`
+  cpu.csr.store(MEDELEG, 1 << 2);
+  cpu.csr.store(STVEC, 0x2000);
+  cpu.mode = Mode::Supervisor;
+  cpu.pc = 0x1000;
+  assert_eq!(cpu.step(), None);
+  assert_eq!(cpu.pc, 0x2000);
+  // The handler is fetched only when it runs
+  assert_eq!(leaf_pte(&mut cpu, 0x2000) & MASK_PTE_A, 0);
+  assert_eq!((cpu.itlb.hits, cpu.itlb.misses), (0, 1));
+  assert_eq!(
+    cpu.step(),
+    Some(StopReason::Fatal(Exception::IllegalInstruction(0)))
+  );
+  assert_eq!(leaf_pte(&mut cpu, 0x2000) & MASK_PTE_A, MASK_PTE_A);
+
+  // A handler in a page which isn't executable
+  cpu.csr.store(STVEC, 0x3000);
+  cpu.pc = 0x1000;
+  assert_eq!(
+    cpu.step(),
+    Some(StopReason::Fatal(Exception::IllegalInstruction(0)))
+  );
+  assert_eq!(cpu.pc, 0x3000);
+}
+
+#[test]
+fn test_tlb_flushed_by_sfence_vma() {
+  use rvemu_for_book::dram::SizeType;
+
+  // sfence.vma a0, x0
+  let sfence_vma_addr = 0x1205_0073;
+  // sfence.vma x0, a1
+  let sfence_vma_asid = 0x12B0_0073;
+  let mut cpu = Cpu::new(vec![]);
+  setup_page_tables(
+    &mut cpu,
+    SATP_MODE_SV39,
+    &[
+      (0x1000, DRAM_BASE + 0x5000, MASK_PTE_R, 0),
+      (0x2000, DRAM_BASE + 0x6000, MASK_PTE_R | MASK_PTE_G, 0),
+    ],
+  );
+  cpu.mode = Mode::Supervisor;
+  assert_eq!(
+    cpu.translate(0x1000, AccessType::Load).unwrap(),
+    DRAM_BASE + 0x5000
+  );
+  assert_eq!(
+    cpu.translate(0x2000, AccessType::Load).unwrap(),
+    DRAM_BASE + 0x6000
+  );
+
+  // Remap both pages, while stale translations are still cached
+  let remap = |cpu: &mut Cpu, va: u64, pa: u64| {
+    let pte_addr = leaf_pte_addr(cpu, va);
+    let pte = cpu.bus.load_u(pte_addr, SizeType::DoubleWord).unwrap();
+    let pte = (pte & !MASK_PTE_PPN) | ((pa >> 12) << 10);
+    cpu.bus.store(pte_addr, SizeType::DoubleWord, pte).unwrap();
+  };
+  remap(&mut cpu, 0x1000, DRAM_BASE + 0x7000);
+  remap(&mut cpu, 0x2000, DRAM_BASE + 0x8000);
+  assert_eq!(
+    cpu.translate(0x1000, AccessType::Load).unwrap(),
+    DRAM_BASE + 0x5000
+  );
+
+  // Per-ASID flush keeps global translations
+  cpu.gpr[11] = 0;
+  cpu.execute(sfence_vma_asid).unwrap();
+  assert_eq!(
+    cpu.translate(0x1000, AccessType::Load).unwrap(),
+    DRAM_BASE + 0x7000
+  );
+  assert_eq!(
+    cpu.translate(0x2000, AccessType::Load).unwrap(),
+    DRAM_BASE + 0x6000
+  );
+
+  // Per-address flush removes global translations too
+  cpu.gpr[10] = 0x2FFF;
+  cpu.execute(sfence_vma_addr).unwrap();
+  assert_eq!(
+    cpu.translate(0x2000, AccessType::Load).unwrap(),
+    DRAM_BASE + 0x8000
+  );
+}
+
+#[test]
+fn test_tlb_flushed_by_satp_write() {
+  let mut cpu = Cpu::new(vec![]);
+  setup_page_tables(
+    &mut cpu,
+    SATP_MODE_SV39,
+    &[(0x1000, DRAM_BASE + 0x5000, MASK_PTE_R, 0)],
+  );
+  cpu.mode = Mode::Supervisor;
+  cpu.translate(0x1000, AccessType::Load).unwrap();
+  let satp = cpu.csr.load(SATP);
+  cpu.gpr[10] = satp | (1 << 44);
+  // csrw satp, a0
+  cpu.execute(0x1805_1073).unwrap();
+  cpu.translate(0x1000, AccessType::Load).unwrap();
+  assert_eq!((cpu.dtlb.hits, cpu.dtlb.misses), (0, 2));
+}
+
+#[test]
+fn test_tlb_superpage_and_replacement() {
+  use rvemu_for_book::mmu::{Tlb, Translation};
+
+  let megapage = Translation {
+    page_base: 0x20_0000,
+    page_size: 0x20_0000,
+    pte: MASK_PTE_V | MASK_PTE_R,
+  };
+  let mut tlb = Tlb::new(1, 2);
+  tlb.insert(0, 0x20_0000, megapage);
+  tlb.insert(0, 0x3F_F000, megapage);
+  assert!(tlb.lookup(0, 0x3F_F008, |_| true).is_some());
+  // Flushing any address of a superpage flushes all of its cached pages
+  tlb.flush(Some(0x30_0000), None);
+  assert!(tlb.lookup(0, 0x20_0000, |_| true).is_none());
+  assert!(tlb.lookup(0, 0x3F_F000, |_| true).is_none());
+
+  // A full set replaces the oldest entry
+  let page = |page_base| Translation {
+    page_base,
+    page_size: PAGE_SIZE,
+    pte: MASK_PTE_V | MASK_PTE_R,
+  };
+  tlb.insert(0, 0x1000, page(0x5000));
+  tlb.insert(0, 0x2000, page(0x6000));
+  tlb.insert(1, 0x3000, page(0x7000));
+  assert!(tlb.lookup(0, 0x1000, |_| true).is_none());
+  assert!(tlb.lookup(0, 0x2000, |_| true).is_some());
+  assert!(tlb.lookup(0, 0x3000, |_| true).is_none());
+  assert!(tlb.lookup(1, 0x3000, |_| true).is_some());
+}
+
+#[test]
+fn test_misaligned_trap() {
+  use rvemu_for_book::{bus::MisalignedPolicy, dram::SizeType};
+
+  // The handler records `(mcause, mtval)` of each trap at `s0`, then skips the
+  // faulting instruction
+  let code = "
+    lla t0, trap
+    csrw mtvec, t0
+    auipc a0, 1
+    andi a0, a0, -16
+    addi s0, a0, 0x100
+    addi a1, x0, 7
+    lw a1, 2(a0)
+    sw a1, 6(a0)
+    addi a3, a0, 1
+    amoadd.w a2, a1, (a3)
+    j end
+  trap:
+    csrr t1, mcause
+    csrr t2, mtval
+    slli t3, s1, 4
+    add t3, t3, s0
+    sd t1, 0(t3)
+    sd t2, 8(t3)
+    addi s1, s1, 1
+    csrr t0, mepc
+    addi t0, t0, 4
+    csrw mepc, t0
+    mret
+  end:
+  ";
+  let mut cpu = TestFramework::test_from_asm_with(code, "test_misaligned_trap", 64, |cpu| {
+    cpu.bus.set_misaligned_policy(MisalignedPolicy::Trap)
+  })
+  .unwrap();
+  // `rd` is left intact, and memory isn't written
+  assert_eq!(cpu.observe_reg("a1"), 7);
+  assert_eq!(cpu.observe_reg("a2"), 0);
+  let a0 = cpu.observe_reg("a0");
+  assert_eq!(cpu.bus.load_u(a0, SizeType::DoubleWord).unwrap(), 0);
+  let records: Vec<(u64, u64)> = (0..cpu.observe_reg("s1"))
+    .map(|i| {
+      let record = cpu.observe_reg("s0") + 16 * i;
+      (
+        cpu.bus.load_u(record, SizeType::DoubleWord).unwrap(),
+        cpu.bus.load_u(record + 8, SizeType::DoubleWord).unwrap(),
+      )
+    })
+    .collect();
+  assert_eq!(records, [(4, a0 + 2), (6, a0 + 6), (6, a0 + 1)]);
+
+  let mut cpu = Cpu::new(vec![]);
+  cpu.bus.set_misaligned_policy(MisalignedPolicy::Trap);
+  assert!(matches!(
+    cpu.store(DRAM_BASE + 1, SizeType::Half, 0),
+    Err(Exception::StoreAMOAddrMisaligned(0x8000_0001))
+  ));
+  // Accesses of bytes are always aligned
+  cpu.store(DRAM_BASE + 1, SizeType::Byte, 0).unwrap();
+}
+
+#[test]
+fn test_misaligned_emulation() {
+  use rvemu_for_book::{bus::MisalignedPolicy, dram::SizeType};
+
+  let mut cpu = Cpu::new(vec![]);
+  cpu
+    .bus
+    .set_misaligned_policy(MisalignedPolicy::EmulateWithinPage);
+  cpu
+    .store(DRAM_BASE + 0x1002, SizeType::Word, 0x1122_3344)
+    .unwrap();
+  assert_eq!(
+    cpu.load(DRAM_BASE + 0x1001, SizeType::Word).unwrap(),
+    0x2233_4400
+  );
+  assert!(matches!(
+    cpu.load(DRAM_BASE + 0x1FFE, SizeType::Word),
+    Err(Exception::LoadAccessMisaligned(0x8000_1FFE))
+  ));
+
+  // An access across a page boundary is split between both pages
+  cpu.bus.set_misaligned_policy(MisalignedPolicy::Emulate);
+  setup_page_tables(
+    &mut cpu,
+    SATP_MODE_SV39,
+    &[
+      (0x1000, DRAM_BASE + 0x5000, MASK_PTE_R | MASK_PTE_W, 0),
+      (0x2000, DRAM_BASE + 0x7000, MASK_PTE_R | MASK_PTE_W, 0),
+    ],
+  );
+  cpu.mode = Mode::Supervisor;
+  cpu
+    .store(0x1FFC, SizeType::DoubleWord, 0x8877_6655_4433_2211)
+    .unwrap();
+  assert_eq!(
+    cpu.bus.load_u(DRAM_BASE + 0x5FFC, SizeType::Word).unwrap(),
+    0x4433_2211
+  );
+  assert_eq!(
+    cpu.bus.load_u(DRAM_BASE + 0x7000, SizeType::Word).unwrap(),
+    0x8877_6655
+  );
+  assert_eq!(cpu.load(0x1FFE, SizeType::Word).unwrap(), 0x6655_4433);
+  // Nothing is written if the second page faults
+  assert!(matches!(
+    cpu.store(0x2FFC, SizeType::DoubleWord, u64::MAX),
+    Err(Exception::StoreAMOPageFault(0x3000))
+  ));
+  assert_eq!(
+    cpu.bus.load_u(DRAM_BASE + 0x7FFC, SizeType::Word).unwrap(),
+    0
+  );
+}